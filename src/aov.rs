@@ -0,0 +1,175 @@
+//! Aov module renders arbitrary output variables: layers describing what each pixel's
+//! camera rays hit first, for compositing and denoising
+use rayon::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::exr::*;
+use crate::film::*;
+use crate::render::*;
+use crate::sampler::*;
+use crate::scene::*;
+use crate::settings::*;
+use crate::vec3::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AovType {
+  /// Distance from the camera along its view direction
+  Depth,
+  /// World space surface normal
+  Normal,
+  /// Surface color without lighting
+  Albedo,
+  /// World space position
+  Position,
+  /// Surface coordinates
+  Uv,
+  /// Index of the object in the world, plus one. 0 is the background.
+  ObjectId,
+  /// MaterialType::id of the material, plus one. 0 is the background.
+  MaterialId,
+}
+
+impl AovType {
+  pub const ALL: [AovType; 7] = [
+    AovType::Depth,
+    AovType::Normal,
+    AovType::Albedo,
+    AovType::Position,
+    AovType::Uv,
+    AovType::ObjectId,
+    AovType::MaterialId,
+  ];
+
+  /// Names of this AOV's channels within its EXR layer
+  pub fn channels(self) -> &'static [&'static str] {
+    match self {
+      AovType::Depth => &["Z"],
+      AovType::Normal | AovType::Position => &["X", "Y", "Z"],
+      AovType::Albedo => &["R", "G", "B"],
+      AovType::Uv => &["U", "V"],
+      AovType::ObjectId | AovType::MaterialId => &["id"],
+    }
+  }
+}
+
+impl FromStr for AovType {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "depth" => Ok(AovType::Depth),
+      "normal" => Ok(AovType::Normal),
+      "albedo" => Ok(AovType::Albedo),
+      "position" => Ok(AovType::Position),
+      "uv" => Ok(AovType::Uv),
+      "object_id" => Ok(AovType::ObjectId),
+      "material_id" => Ok(AovType::MaterialId),
+      _ => Err(format!("unknown AOV '{}'", s)),
+    }
+  }
+}
+
+impl fmt::Display for AovType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      AovType::Depth => "depth",
+      AovType::Normal => "normal",
+      AovType::Albedo => "albedo",
+      AovType::Position => "position",
+      AovType::Uv => "uv",
+      AovType::ObjectId => "object_id",
+      AovType::MaterialId => "material_id",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// The AOVs of one pixel. The continuous ones are averaged over the samples that hit
+/// something, and are 0 where nothing was. The IDs are those under the pixel's first sample.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct AovPixel {
+  pub depth: f32,
+  pub normal: Vec3,
+  pub albedo: Vec3,
+  pub position: Vec3,
+  pub uv: (f32, f32),
+  pub object_id: u32,
+  pub material_id: u32,
+}
+
+/// Full size AOV image, top row first. Pixels outside the crop window are left at 0.
+pub struct AovImage {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<AovPixel>,
+}
+
+impl AovImage {
+  /// One plane of values per channel of `aov`, cropped to `bounds`
+  pub fn planes(&self, aov: AovType, bounds: &Bounds) -> Vec<ExrData> {
+    let pixels = crop_image(&self.pixels, self.width, bounds);
+    let plane = |f: &dyn Fn(&AovPixel) -> f32| ExrData::Float(pixels.iter().map(f).collect());
+    match aov {
+      AovType::Depth => vec![plane(&|p| p.depth)],
+      AovType::Normal => (0..3).map(|i| plane(&|p| p.normal.e[i])).collect(),
+      AovType::Albedo => (0..3).map(|i| plane(&|p| p.albedo.e[i])).collect(),
+      AovType::Position => (0..3).map(|i| plane(&|p| p.position.e[i])).collect(),
+      AovType::Uv => vec![plane(&|p| p.uv.0), plane(&|p| p.uv.1)],
+      AovType::ObjectId => vec![ExrData::Uint(pixels.iter().map(|p| p.object_id).collect())],
+      AovType::MaterialId => vec![ExrData::Uint(pixels.iter().map(|p| p.material_id).collect())],
+    }
+  }
+}
+
+/// Trace the first hit of every camera sample the beauty render takes (`settings.spp` per
+/// pixel) and record the AOVs. Samples are reseeded per pixel & index, so these are
+/// exactly the beauty image's camera rays, without the cost of the rest of their paths.
+pub fn render_aovs(scene: &Scene, settings: &RenderSettings) -> AovImage {
+  let (width, height) = (settings.width, settings.height);
+  let crop = settings.crop_bounds();
+  let forward = scene.camera.forward();
+  let init = || SamplerContext::with_sampler(settings.seed, settings.sampler, settings.spp);
+
+  let mut pixels = vec![AovPixel::default(); (width * height) as usize];
+  pixels.par_chunks_mut(width as usize).enumerate()
+    .filter(|(y, _)| *y as u32 >= crop.y0 && (*y as u32) < crop.y1)
+    .for_each_init(init, |ctx, (y, row)| {
+      let y = y as u32;
+      for x in crop.x0..crop.x1 {
+        let mut sum = AovPixel::default();
+        let mut hits = 0;
+        for s in 0..settings.spp {
+          let (_, _, r) = camera_ray(scene, width, height, x, y, s, ctx);
+          let rec = match scene.world.hit(&r, 0.001, f32::MAX, ctx) {
+            Some(rec) => rec,
+            None => continue,
+          };
+          if s == 0 {
+            sum.object_id = rec.object_id + 1;
+            sum.material_id = rec.material.id() + 1;
+          }
+          sum.depth += (rec.p - scene.camera.origin).dot(forward);
+          sum.normal += rec.normal;
+          sum.albedo += rec.material.albedo(rec.u, rec.v);
+          sum.position += rec.p;
+          sum.uv = (sum.uv.0 + rec.u, sum.uv.1 + rec.v);
+          hits += 1;
+        }
+        if hits > 0 {
+          let n = hits as f32;
+          sum.depth /= n;
+          sum.normal /= n;
+          sum.albedo /= n;
+          sum.position /= n;
+          sum.uv = (sum.uv.0 / n, sum.uv.1 / n);
+        }
+        row[x as usize] = sum;
+      }
+    });
+
+  AovImage {
+    width,
+    height,
+    pixels,
+  }
+}
@@ -0,0 +1,35 @@
+//! Bench module times the different render schemes against each other
+use std::time::Instant;
+
+use crate::progress::*;
+use crate::render::*;
+use crate::scene::*;
+use crate::settings::*;
+use crate::tile::*;
+
+/// Render the scene with every scheme and print how long each took, relative to the
+/// original per pixel scheme
+pub fn benchmark(scene: &Scene, settings: &RenderSettings) {
+  println!("{}x{} @ {} spp on {} threads", settings.width, settings.height, settings.spp,
+           rayon::current_num_threads());
+  println!("{:<24} {:>10} {:>10}", "scheme", "seconds", "speedup");
+
+  let start = Instant::now();
+  render_per_pixel(scene, settings);
+  let baseline = start.elapsed().as_secs_f64();
+  println!("{:<24} {:>10.3} {:>9.2}x", "per pixel", baseline, 1.0);
+
+  let start = Instant::now();
+  render_serial(scene, settings);
+  let seconds = start.elapsed().as_secs_f64();
+  println!("{:<24} {:>10.3} {:>9.2}x", "serial", seconds, baseline / seconds);
+
+  for &order in [TileOrder::Scanline, TileOrder::Hilbert, TileOrder::Spiral].iter() {
+    let tiled = RenderSettings { tile_order: order, ..settings.clone() };
+    let start = Instant::now();
+    render_parallel(scene, &tiled, settings.sampler, settings.spp, &Progress::default());
+    let seconds = start.elapsed().as_secs_f64();
+    let name = format!("{}x{} tiles, {}", settings.tile_size, settings.tile_size, order);
+    println!("{:<24} {:>10.3} {:>9.2}x", name, seconds, baseline / seconds);
+  }
+}
@@ -1,19 +1,23 @@
 use crate::ray::*;
+use crate::sampler::*;
 use crate::vec3::*;
 use std::f32::consts::PI;
 
 // TODO: Add DOF
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Camera {
   pub origin: Vec3,
   pub lower_left_corner: Vec3,
   pub horizontal: Vec3,
   pub vertical: Vec3,
+  // Camera basis
+  pub u: Vec3,
+  pub v: Vec3,
 }
 
-// Common camera ratios: 16:9, 4:3
+// Common camera ratio: 16:9
 const SIXTEEN_NINE: f32 = 1440.0 / 2560.0;
-const FOUR_THREE: f32 = 768.0 / 1024.0;
 impl Default for Camera {
   fn default() -> Self {
     Camera::new(Vec3::from([0.0; 3]),
@@ -37,13 +41,20 @@ impl Camera {
       lower_left_corner: origin - half_width * u - half_height * v - w,
       horizontal: 2.0 * half_width * u,
       vertical: 2.0 * half_height * v,
+      u,
+      v,
     }
   }
 
-  pub fn get_ray(&self, u: f32, v: f32) -> Ray {
+  /// Unit vector the camera looks along
+  pub fn forward(&self) -> Vec3 {
+    self.v.cross(self.u)
+  }
+
+  pub fn get_ray(&self, u: f32, v: f32, _ctx: &mut SamplerContext) -> Ray {
     Ray {
       origin: self.origin,
       direction: (self.lower_left_corner + u * self.horizontal + v * self.vertical - self.origin)
     }
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,259 @@
+//! Checkpoint module saves and restores the state of a render in progress
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::film::*;
+use crate::sampler::*;
+use crate::scene::*;
+use crate::settings::*;
+use crate::vec3::*;
+
+const MAGIC: &[u8; 8] = b"RTWCKPT2";
+
+/// Everything needed to pick a render back up. There's no separate RNG state to store:
+/// every sample's stream is derived from the seed and its pixel & sample index, so knowing
+/// how many samples each pixel already took is enough to continue the exact same sequence.
+pub struct Checkpoint {
+  pub scene_hash: u64,
+  pub settings_hash: u64,
+  pub seed: u64,
+  /// Samples per pixel rendered so far
+  pub spp: u32,
+  pub film: Film,
+}
+
+impl Checkpoint {
+  /// Refuse to continue a render whose scene or settings don't match this one
+  pub fn validate(&self, scene: &Scene, settings: &RenderSettings) -> Result<(), String> {
+    if self.scene_hash != scene_hash(scene) {
+      return Err("the scene has changed since the checkpoint was written".to_string());
+    }
+    if self.settings_hash != settings_hash(settings) || self.seed != settings.seed {
+      return Err("the render settings have changed since the checkpoint was written".to_string());
+    }
+    Ok(())
+  }
+}
+
+/// Write a checkpoint to `path`, via a temporary file so a crash mid-write can't
+/// destroy the previous checkpoint
+pub fn write_checkpoint(path: &str, scene: &Scene, settings: &RenderSettings,
+                        spp: u32, film: &Film) -> io::Result<()> {
+  let temp = format!("{}.tmp", path);
+  {
+    let mut out = BufWriter::new(File::create(&temp)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&scene_hash(scene).to_le_bytes())?;
+    out.write_all(&settings_hash(settings).to_le_bytes())?;
+    out.write_all(&settings.seed.to_le_bytes())?;
+    for v in [spp, film.width, film.height].iter() {
+      out.write_all(&v.to_le_bytes())?;
+    }
+    for p in film.pixels.iter() {
+      for v in [p.sum.r(), p.sum.g(), p.sum.b(), p.weight, p.luminance_sum, p.luminance_sq_sum].iter() {
+        out.write_all(&v.to_le_bytes())?;
+      }
+      out.write_all(&p.samples.to_le_bytes())?;
+    }
+    out.flush()?;
+  }
+  fs::rename(&temp, path)
+}
+
+pub fn read_checkpoint(path: &str, settings: &RenderSettings) -> io::Result<Checkpoint> {
+  let mut input = BufReader::new(File::open(path)?);
+  let mut magic = [0u8; 8];
+  input.read_exact(&mut magic)?;
+  if &magic != MAGIC {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "not a checkpoint file"));
+  }
+  let scene_hash = read_u64(&mut input)?;
+  let settings_hash = read_u64(&mut input)?;
+  let seed = read_u64(&mut input)?;
+  let spp = read_u32(&mut input)?;
+  let width = read_u32(&mut input)?;
+  let height = read_u32(&mut input)?;
+  // Check the size before allocating for it, a corrupt header could ask for anything
+  if (width, height) != (settings.width, settings.height) {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+      "checkpoint is {}x{}, the render is {}x{}", width, height, settings.width, settings.height)));
+  }
+  // The film counts its pixels in a u32
+  if width.checked_mul(height).is_none() {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "checkpoint has too many pixels"));
+  }
+
+  let mut film = Film::new(width, height, settings.filter()).with_crop(settings.crop_bounds());
+  for p in film.pixels.iter_mut() {
+    p.sum = Vec3::from((read_f32(&mut input)?, read_f32(&mut input)?, read_f32(&mut input)?));
+    p.weight = read_f32(&mut input)?;
+    p.luminance_sum = read_f32(&mut input)?;
+    p.luminance_sq_sum = read_f32(&mut input)?;
+    p.samples = read_u32(&mut input)?;
+  }
+
+  Ok(Checkpoint {
+    scene_hash,
+    settings_hash,
+    seed,
+    spp,
+    film,
+  })
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+  let mut bytes = [0u8; 4];
+  input.read_exact(&mut bytes)?;
+  Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+  let mut bytes = [0u8; 8];
+  input.read_exact(&mut bytes)?;
+  Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(input: &mut R) -> io::Result<f32> {
+  Ok(f32::from_bits(read_u32(input)?))
+}
+
+/// 64 bit FNV-1a
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+    (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+  })
+}
+
+/// Fingerprint of everything in the scene. Float Debug output round-trips exactly, so
+/// any change to an object, material or the camera changes the hash.
+pub fn scene_hash(scene: &Scene) -> u64 {
+  let description = format!("{:?}{:?}{:?}{:?}{:?}", scene.world, scene.camera, scene.lights, scene.sky,
+                            scene.background);
+  let mut bytes = description.into_bytes();
+  if let Some(environment) = &scene.environment {
+    bytes.extend(format!("{} {} {}x{}", environment.intensity, environment.rotation,
+                         environment.image.width, environment.image.height).bytes());
+    for p in environment.image.pixels.iter() {
+      for v in [p.r(), p.g(), p.b()].iter() {
+        bytes.extend(&v.to_le_bytes());
+      }
+    }
+  }
+  fnv1a(&bytes)
+}
+
+/// Fingerprint of the settings that decide which samples land where. Anything that only
+/// decides when to stop (time budget, target spp) is left out, so a finished render can be
+/// resumed with a higher target. The stratified sampler is the exception, since its
+/// strata depend on the spp it was told to expect.
+pub fn settings_hash(settings: &RenderSettings) -> u64 {
+  let strata = match settings.sampler {
+    SamplerType::Stratified => settings.progressive_sampler_spp(),
+    _ => 0,
+  };
+  let description = format!("{}x{} {:?} {} {:?} {} {} {}", settings.width, settings.height,
+                            settings.crop_bounds(), settings.sampler, settings.filter(), strata,
+                            settings.light_sampler, settings.spectral);
+  fnv1a(description.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::manylights::*;
+
+  fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(format!("rtw-{}-{}.ckpt", name, std::process::id()))
+      .to_string_lossy().into_owned()
+  }
+
+  fn small_settings() -> RenderSettings {
+    RenderSettings { width: 6, height: 4, ..RenderSettings::default() }
+  }
+
+  fn small_scene() -> Scene {
+    Scene::random_spheres(3, 1.5, &SceneOptions::default())
+  }
+
+  #[test]
+  fn checkpoint_round_trips() {
+    let settings = small_settings();
+    let scene = small_scene();
+    let mut film = settings.film();
+    for (i, p) in film.pixels.iter_mut().enumerate() {
+      let i = i as f32;
+      p.sum = Vec3::from((i, 0.5 * i, -1.25));
+      p.weight = 1.0 + i;
+      p.samples = 3 * i as u32;
+      p.luminance_sum = 0.1 * i;
+      p.luminance_sq_sum = f32::MAX / (1.0 + i);
+    }
+    let path = temp_path("round-trip");
+    write_checkpoint(&path, &scene, &settings, 7, &film).unwrap();
+    let checkpoint = read_checkpoint(&path, &settings);
+    fs::remove_file(&path).unwrap();
+    let checkpoint = checkpoint.unwrap();
+
+    assert_eq!(checkpoint.spp, 7);
+    assert_eq!(checkpoint.seed, settings.seed);
+    assert_eq!((checkpoint.film.width, checkpoint.film.height), (film.width, film.height));
+    assert_eq!(checkpoint.film.crop, film.crop);
+    assert_eq!(checkpoint.film.pixels, film.pixels);
+    assert!(checkpoint.validate(&scene, &settings).is_ok());
+  }
+
+  #[test]
+  fn checkpoint_from_another_render_is_rejected() {
+    let settings = small_settings();
+    let scene = small_scene();
+    let path = temp_path("mismatch");
+    write_checkpoint(&path, &scene, &settings, 1, &settings.film()).unwrap();
+    let resized = read_checkpoint(&path, &RenderSettings { width: 8, ..small_settings() });
+    let checkpoint = read_checkpoint(&path, &settings);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(resized.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    let checkpoint = checkpoint.unwrap();
+    let reseeded = RenderSettings { seed: 5, ..small_settings() };
+    assert!(checkpoint.validate(&scene, &reseeded).is_err());
+    assert!(checkpoint.validate(&Scene::random_spheres(4, 1.5, &SceneOptions::default()), &settings).is_err());
+  }
+
+  #[test]
+  fn hashes_cover_the_scene_and_what_decides_the_film() {
+    let scene = small_scene();
+    let mut lit = small_scene();
+    lit.background = Some(Vec3::from([0.2; 3]));
+    assert_ne!(scene_hash(&scene), scene_hash(&lit));
+    assert_eq!(scene_hash(&scene), scene_hash(&small_scene()));
+
+    let settings = small_settings();
+    for changed in [RenderSettings { spectral: true, ..small_settings() },
+                    RenderSettings { light_sampler: LightSamplerType::Uniform, ..small_settings() },
+                    RenderSettings { sampler: SamplerType::Halton, ..small_settings() },
+                    RenderSettings { filter_radius: Some(3.0), ..small_settings() },
+                    RenderSettings { crop: Some(CropWindow::Pixels(1, 1, 5, 3)), ..small_settings() }].iter() {
+      assert_ne!(settings_hash(&settings), settings_hash(changed), "{:?}", changed);
+    }
+    // How long to render for isn't part of it
+    assert_eq!(settings_hash(&settings), settings_hash(&RenderSettings { time_budget: Some(5.0), ..small_settings() }));
+  }
+
+  #[test]
+  fn huge_or_foreign_headers_are_rejected() {
+    let path = temp_path("header");
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(&[0u8; 24]);
+    for v in [1u32, u32::MAX, u32::MAX].iter() {
+      bytes.extend(&v.to_le_bytes());
+    }
+    fs::write(&path, &bytes).unwrap();
+    let huge = read_checkpoint(&path, &RenderSettings { width: u32::MAX, height: u32::MAX, ..small_settings() });
+    fs::write(&path, b"P3\n6 4\n255\n").unwrap();
+    let foreign = read_checkpoint(&path, &small_settings());
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(huge.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    assert!(foreign.is_err());
+  }
+}
@@ -0,0 +1,354 @@
+//! Cryptomatte module renders ranked ID/coverage mattes for objects and materials, in the
+//! layout compositors read from multi-layer EXRs
+use rayon::prelude::*;
+
+use crate::exr::*;
+use crate::film::*;
+use crate::render::*;
+use crate::sampler::*;
+use crate::scene::*;
+use crate::settings::*;
+
+/// MurmurHash3 x86 32 bit, which Cryptomatte hashes names with (seed 0)
+pub fn murmur3_32(bytes: &[u8], seed: u32) -> u32 {
+  const C1: u32 = 0xcc9e_2d51;
+  const C2: u32 = 0x1b87_3593;
+  let mut h = seed;
+  let chunks = bytes.chunks_exact(4);
+  let tail = chunks.remainder();
+  for chunk in chunks {
+    let k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+  }
+  if !tail.is_empty() {
+    let k = tail.iter().rev().fold(0u32, |k, &b| (k << 8) | u32::from(b));
+    h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+  }
+  h ^= bytes.len() as u32;
+  h ^= h >> 16;
+  h = h.wrapping_mul(0x85eb_ca6b);
+  h ^= h >> 13;
+  h = h.wrapping_mul(0xc2b2_ae35);
+  h ^ (h >> 16)
+}
+
+/// A name's Cryptomatte hash. The bits are stored as a float, so hashes that would be
+/// denormal, infinite or NaN get a bit of their exponent flipped.
+pub fn name_hash(name: &str) -> u32 {
+  let hash = murmur3_32(name.as_bytes(), 0);
+  let exponent = (hash >> 23) & 0xff;
+  if exponent == 0 || exponent == 0xff { hash ^ (1 << 23) } else { hash }
+}
+
+/// Which name of the hit object a matte is made from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MatteType {
+  Object,
+  Material,
+}
+
+impl MatteType {
+  pub const ALL: [MatteType; 2] = [MatteType::Object, MatteType::Material];
+
+  /// Name of the Cryptomatte layer
+  pub fn layer(self) -> &'static str {
+    match self {
+      MatteType::Object => "CryptoObject",
+      MatteType::Material => "CryptoMaterial",
+    }
+  }
+
+  fn name(self, info: &ObjectInfo) -> &str {
+    match self {
+      MatteType::Object => &info.name,
+      MatteType::Material => &info.material,
+    }
+  }
+}
+
+/// One matte: for every pixel, each ID's share of the filter weight, highest first
+pub struct Cryptomatte {
+  pub matte_type: MatteType,
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<Vec<(u32, f32)>>,
+  /// Every name that appears, with its hash
+  pub manifest: Vec<(String, u32)>,
+}
+
+impl Cryptomatte {
+  /// The `{layer}00`, `{layer}01`... channels cropped to `bounds`, holding `depth` ranks
+  /// as (id, coverage) pairs, two per RGBA layer
+  pub fn channels(&self, depth: u32, bounds: &Bounds) -> Vec<ExrChannel> {
+    let pixels = crop_image(&self.pixels, self.width, bounds);
+    let mut channels = Vec::new();
+    for rank in 0..depth as usize {
+      let layer = format!("{}{:02}", self.matte_type.layer(), rank / 2);
+      let (id_channel, coverage_channel) = if rank % 2 == 0 { ("R", "G") } else { ("B", "A") };
+      let rank_of = |p: &Vec<(u32, f32)>| p.get(rank).copied().unwrap_or((0, 0.0));
+      let ids: Vec<f32> = pixels.iter().map(|p| f32::from_bits(rank_of(p).0)).collect();
+      let coverage: Vec<f32> = pixels.iter().map(|p| rank_of(p).1).collect();
+      channels.push(ExrChannel::from((format!("{}.{}", layer, id_channel).as_str(), ids)));
+      channels.push(ExrChannel::from((format!("{}.{}", layer, coverage_channel).as_str(), coverage)));
+    }
+    channels
+  }
+
+  /// The EXR header attributes that describe this matte
+  pub fn metadata(&self) -> Vec<(String, String)> {
+    let layer = self.matte_type.layer();
+    let key = format!("cryptomatte/{}", &format!("{:08x}", murmur3_32(layer.as_bytes(), 0))[..7]);
+    let entries: Vec<String> = self.manifest.iter()
+      .map(|(name, hash)| format!("{}:\"{:08x}\"", json_string(name), hash))
+      .collect();
+    vec![
+      (format!("{}/name", key), layer.to_string()),
+      (format!("{}/hash", key), "MurmurHash3_32".to_string()),
+      (format!("{}/conversion", key), "uint32_to_float32".to_string()),
+      (format!("{}/manifest", key), format!("{{{}}}", entries.join(","))),
+    ]
+  }
+}
+
+// Filter weight of the samples each object was the first hit of, at one pixel, and of
+// every sample
+#[derive(Debug, Clone, Default)]
+struct Coverage {
+  total: f32,
+  objects: Vec<(u32, f32)>,
+}
+
+impl Coverage {
+  fn add(&mut self, object_id: u32, weight: f32) {
+    match self.objects.iter_mut().find(|(id, _)| *id == object_id) {
+      Some(entry) => entry.1 += weight,
+      None => self.objects.push((object_id, weight)),
+    }
+  }
+
+  fn merge(&mut self, other: &Coverage) {
+    self.total += other.total;
+    for &(id, weight) in other.objects.iter() {
+      self.add(id, weight);
+    }
+  }
+
+  // Each hash's share of the weight, highest first, objects with the same hash together
+  fn ranked(&self, hashes: &[u32]) -> Vec<(u32, f32)> {
+    let mut by_hash = Coverage::default();
+    for &(id, weight) in self.objects.iter() {
+      by_hash.add(hashes[id as usize], weight);
+    }
+    let mut ranked: Vec<(u32, f32)> = by_hash.objects.into_iter()
+      .filter(|&(_, weight)| weight > 0.0)
+      .map(|(hash, weight)| (hash, weight / self.total))
+      .collect();
+    // Ties broken by ID so the ranking doesn't depend on the order objects were hit in
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+    ranked
+  }
+}
+
+/// Render the object & material mattes of every camera sample's first hit
+/// (`settings.spp` per pixel, the beauty image's own camera rays). Samples are weighted
+/// into pixels with the film's filter, so edges get fractional coverage. Negative filter
+/// lobes are clamped to 0. Both mattes come from one set of rays, splatted as they're
+/// traced a band of rows at a time, so memory grows with the image but not the samples.
+pub fn render_cryptomattes(scene: &Scene, settings: &RenderSettings) -> Vec<Cryptomatte> {
+  let (width, height) = (settings.width, settings.height);
+  let crop = settings.crop_bounds();
+  let filter = settings.filter();
+  let margin = (filter.radius - 0.5).max(0.0).ceil() as u32;
+  let init = || SamplerContext::with_sampler(settings.seed, settings.sampler, settings.spp);
+
+  let row_length = crop.width() as usize;
+  let mut coverage = vec![Coverage::default(); row_length * (crop.y1 - crop.y0) as usize];
+  let band = 4 * rayon::current_num_threads() as u32;
+  for band_y0 in (crop.y0..crop.y1).step_by(band as usize) {
+    // Each row's samples splatted into the rows its filter reaches, which start at the
+    // returned row
+    let rows: Vec<(u32, Vec<Coverage>)> = (band_y0..(band_y0 + band).min(crop.y1)).into_par_iter()
+      .map_init(init, |ctx, y| {
+        let reach = Bounds::new(crop.x0, y, crop.x1, y + 1).expand(margin, &crop);
+        let mut splats = vec![Coverage::default(); row_length * (reach.y1 - reach.y0) as usize];
+        for x in crop.x0..crop.x1 {
+          for s in 0..settings.spp {
+            let (fx, fy, r) = camera_ray(scene, width, height, x, y, s, ctx);
+            let hit = scene.world.hit(&r, 0.001, f32::MAX, ctx).map(|rec| rec.object_id);
+            splat(&filter, &reach, fx, fy, |index, weight| {
+              let weight = weight.max(0.0);
+              splats[index].total += weight;
+              if let Some(id) = hit {
+                splats[index].add(id, weight);
+              }
+            });
+          }
+        }
+        (reach.y0, splats)
+      })
+      .collect();
+    // In row order, so the sums don't depend on the thread count
+    for (first_row, splats) in rows {
+      let offset = (first_row - crop.y0) as usize * row_length;
+      for (pixel, splat) in coverage[offset..offset + splats.len()].iter_mut().zip(splats.iter()) {
+        pixel.merge(splat);
+      }
+    }
+  }
+
+  MatteType::ALL.iter().map(|&matte_type| {
+    let hashes: Vec<u32> = scene.objects.iter().map(|info| name_hash(matte_type.name(info))).collect();
+    let pixels: Vec<Vec<(u32, f32)>> = (0..width * height).map(|index| {
+      let (x, y) = (index % width, index / width);
+      if !crop.contains(x, y) {
+        return Vec::new();
+      }
+      coverage[(y - crop.y0) as usize * row_length + (x - crop.x0) as usize].ranked(&hashes)
+    }).collect();
+
+    let mut manifest: Vec<(String, u32)> = scene.objects.iter()
+      .map(|info| matte_type.name(info).to_string())
+      .map(|name| { let hash = name_hash(&name); (name, hash) })
+      .collect();
+    manifest.sort();
+    manifest.dedup();
+
+    Cryptomatte {
+      matte_type,
+      width,
+      height,
+      pixels,
+      manifest,
+    }
+  }).collect()
+}
+
+// A string as a JSON string literal
+fn json_string(s: &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filter::*;
+  use std::collections::HashMap;
+
+  #[test]
+  fn murmur3_matches_known_vectors() {
+    // The reference implementation's published results
+    let vectors: [(&[u8], u32, u32); 9] = [
+      (b"", 0, 0),
+      (b"", 1, 0x514e_28b7),
+      (b"", 0xffff_ffff, 0x81f1_6f39),
+      (b"\0\0\0\0", 0, 0x2362_f9de),
+      (b"aaaa", 0x9747_b28c, 0x5a97_808a),
+      (b"a", 0x9747_b28c, 0x7fa0_9ea6),
+      (b"Hello, world!", 0x9747_b28c, 0x2488_4cba),
+      (b"The quick brown fox jumps over the lazy dog", 0x9747_b28c, 0x2fa8_26cd),
+      (b"hello", 0, 0x248b_fa47),
+    ];
+    for &(bytes, seed, hash) in vectors.iter() {
+      assert_eq!(murmur3_32(bytes, seed), hash, "{:?} with seed {:#x}", String::from_utf8_lossy(bytes), seed);
+    }
+  }
+
+  #[test]
+  fn name_hashes_are_ordinary_floats() {
+    assert_eq!(name_hash("hello"), 0x248b_fa47);
+    // Find names whose raw hash would be a denormal or a NaN
+    let names: Vec<String> = (0..20000).map(|i| format!("object_{}", i)).collect();
+    let odd: Vec<&String> = names.iter()
+      .filter(|name| [0, 0xff].contains(&((murmur3_32(name.as_bytes(), 0) >> 23) & 0xff)))
+      .collect();
+    assert!(!odd.is_empty());
+    for name in odd {
+      let hash = name_hash(name);
+      assert_eq!(hash ^ murmur3_32(name.as_bytes(), 0), 1 << 23);
+      assert!(f32::from_bits(hash).is_normal(), "{} hashes to {:e}", name, f32::from_bits(hash));
+    }
+  }
+
+  #[test]
+  fn metadata_is_keyed_by_the_layer_hash() {
+    let matte = Cryptomatte {
+      matte_type: MatteType::Object,
+      width: 1,
+      height: 1,
+      pixels: vec![Vec::new()],
+      manifest: vec![("say \"hi\"".to_string(), 0x248b_fa47)],
+    };
+    let metadata = matte.metadata();
+    // MurmurHash3 of "CryptoObject" is 3ae39a58
+    assert_eq!(metadata[0], ("cryptomatte/3ae39a5/name".to_string(), "CryptoObject".to_string()));
+    assert_eq!(metadata[3].1, "{\"say \\\"hi\\\"\":\"248bfa47\"}");
+  }
+
+  #[test]
+  fn splatting_matches_gathering_every_sample() {
+    let settings = RenderSettings {
+      width: 24,
+      height: 12,
+      spp: 4,
+      crop: Some(CropWindow::Pixels(4, 2, 20, 10)),
+      // Wide enough for samples to land rows away, with negative lobes to clamp
+      filter: FilterType::Mitchell,
+      filter_radius: Some(2.5),
+      ..RenderSettings::default()
+    };
+    let scene = Scene::random_spheres(settings.seed, 2.0, &settings.scene_options());
+    let mattes = render_cryptomattes(&scene, &settings);
+    assert_eq!(mattes.iter().map(|m| m.matte_type).collect::<Vec<_>>(), MatteType::ALL.to_vec());
+
+    // Every sample in the crop, then each pixel's weighted share of them
+    let crop = settings.crop_bounds();
+    let filter = settings.filter();
+    let mut ctx = SamplerContext::with_sampler(settings.seed, settings.sampler, settings.spp);
+    let mut samples = Vec::new();
+    for y in crop.y0..crop.y1 {
+      for x in crop.x0..crop.x1 {
+        for s in 0..settings.spp {
+          let (fx, fy, r) = camera_ray(&scene, settings.width, settings.height, x, y, s, &mut ctx);
+          samples.push((fx, fy, scene.world.hit(&r, 0.001, f32::MAX, &mut ctx).map(|rec| rec.object_id)));
+        }
+      }
+    }
+    for matte in mattes.iter() {
+      for y in 0..settings.height {
+        for x in 0..settings.width {
+          let pixel = &matte.pixels[(y * settings.width + x) as usize];
+          if !crop.contains(x, y) {
+            assert!(pixel.is_empty());
+            continue;
+          }
+          let mut expected: HashMap<u32, f32> = HashMap::new();
+          let mut total = 0.0;
+          for &(fx, fy, hit) in samples.iter() {
+            let weight = filter.evaluate(fx - (x as f32 + 0.5), fy - (y as f32 + 0.5)).max(0.0);
+            total += weight;
+            if let Some(id) = hit {
+              *expected.entry(name_hash(matte.matte_type.name(scene.object(id)))).or_insert(0.0) += weight;
+            }
+          }
+          expected.retain(|_, weight| *weight > 0.0);
+          assert_eq!(pixel.len(), expected.len(), "{:?} at {}, {}", matte.matte_type, x, y);
+          for (hash, coverage) in pixel.iter() {
+            assert!((coverage - expected[hash] / total).abs() < 1e-4, "{:?} at {}, {}", matte.matte_type, x, y);
+          }
+          assert!(pixel.windows(2).all(|w| w[0].1 >= w[1].1));
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,54 @@
+//! Debug module traces a single pixel and prints what every one of its paths does
+use std::io::{self, Write};
+
+use crate::render::*;
+use crate::sampler::*;
+use crate::scene::*;
+use crate::settings::*;
+use crate::vec3::*;
+
+/// Trace every sample of pixel (x, y) with `color`, printing each bounce: the object &
+/// material hit, the light it gathered, where the path scattered, and its throughput so far.
+pub fn debug_pixel<W: Write>(out: &mut W, scene: &Scene, settings: &RenderSettings,
+                             x: u32, y: u32) -> io::Result<()> {
+  let mut ctx = SamplerContext::with_sampler(settings.seed, settings.sampler, settings.spp);
+  let mut sum = Vec3::new();
+  writeln!(out, "pixel ({}, {}): {} samples, {} sampler, seed {}",
+           x, y, settings.spp, settings.sampler, settings.seed)?;
+
+  for s in 0..settings.spp {
+    let (fx, fy, r) = camera_ray(scene, settings.width, settings.height, x, y, s, &mut ctx);
+    writeln!(out, "sample {} at ({:.4}, {:.4}): ray from {:.4} towards {:.4}",
+             s, fx, fy, r.origin, r.direction)?;
+    // The observer can't return errors, so keep the first one for after the path
+    let mut result = Ok(());
+    let radiance = color(&r, scene, &mut ctx, None, Some(&mut |event| {
+      if result.is_ok() {
+        result = print_event(out, scene, event);
+      }
+    }));
+    result?;
+    writeln!(out, "  radiance {:.4}", radiance)?;
+    sum += radiance;
+  }
+
+  writeln!(out, "mean radiance {:.4}", sum / settings.spp as f32)
+}
+
+fn print_event<W: Write>(out: &mut W, scene: &Scene, event: PathEvent) -> io::Result<()> {
+  match event {
+    PathEvent::Hit { depth, rec } => {
+      let object = scene.object(rec.object_id);
+      writeln!(out, "  bounce {}: {} at t {:.4}, p {:.4}, normal {:.4}",
+               depth, object.name, rec.t, rec.p, rec.normal)?;
+      writeln!(out, "    material {} {:?}", object.material, rec.material)
+    },
+    PathEvent::Escaped { depth } => writeln!(out, "  bounce {}: escaped", depth),
+    PathEvent::Light { source, radiance } => writeln!(out, "    {}, adds {:.4}", source, radiance),
+    PathEvent::Scattered { direction, attenuation, throughput } =>
+      writeln!(out, "    scattered towards {:.4}, attenuation {:.4}, throughput {:.4}",
+               direction, attenuation, throughput),
+    PathEvent::TooDeep => writeln!(out, "    terminated, too many bounces"),
+    PathEvent::Absorbed => writeln!(out, "    absorbed"),
+  }
+}
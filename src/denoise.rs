@@ -0,0 +1,210 @@
+//! Denoise module contains an edge-avoiding à-trous wavelet filter, guided by the AOVs
+use rayon::prelude::*;
+
+use crate::aov::*;
+use crate::film::*;
+use crate::vec3::*;
+
+/// How hard each feature stops the filter. Smaller sigmas keep more detail.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DenoiseSettings {
+  /// Number of à-trous passes. Pass i samples 5x5 pixels spaced 2^i apart, so 5 passes
+  /// reach 62 pixels across.
+  pub iterations: u32,
+  /// Allowed luminance difference, in standard deviations of the pixel's noise
+  pub sigma_color: f32,
+  /// Exponent on the cosine between normals
+  pub sigma_normal: f32,
+  /// Allowed depth difference, relative to how fast depth changes across the pixel
+  pub sigma_depth: f32,
+  /// Allowed albedo difference
+  pub sigma_albedo: f32,
+}
+
+impl Default for DenoiseSettings {
+  fn default() -> Self {
+    DenoiseSettings {
+      iterations: 5,
+      sigma_color: 4.0,
+      sigma_normal: 128.0,
+      sigma_depth: 1.0,
+      sigma_albedo: 0.1,
+    }
+  }
+}
+
+// B3 spline, the à-trous kernel
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Denoise the film's image inside its crop window, returning the full size image.
+/// Neighbours are weighted by how alike their albedo, normal, depth & luminance are, and
+/// pixels on different objects (by the object ID AOV) never contribute to each other, so
+/// geometric edges stay sharp. Each pass's variance estimate is filtered along with the
+/// color, so the luminance term tightens as the noise goes down.
+pub fn denoise(film: &Film, aovs: &AovImage, settings: &DenoiseSettings) -> Vec<Vec3> {
+  let width = film.width as usize;
+  let crop = film.crop;
+  let features = &aovs.pixels;
+  let mut color = film.resolve();
+  let mut variance: Vec<f32> = film.pixels.iter().map(FilmPixel::luminance_variance).collect();
+
+  // How much depth changes per pixel, to tell a slanted surface from a depth discontinuity
+  let depth_gradient: Vec<f32> = (0..features.len()).map(|index| {
+    let (x, y) = ((index % width) as u32, (index / width) as u32);
+    let p = &features[index];
+    [(1, 0), (-1, 0), (0, 1), (0, -1)].iter()
+      .map(|&(dx, dy)| (x as i32 + dx, y as i32 + dy))
+      .filter(|&(nx, ny)| nx >= 0 && ny >= 0 && crop.contains(nx as u32, ny as u32))
+      .map(|(nx, ny)| &features[ny as usize * width + nx as usize])
+      .filter(|q| q.object_id == p.object_id)
+      .map(|q| (q.depth - p.depth).abs())
+      .fold(0.0, f32::max)
+  }).collect();
+
+  for iteration in 0..settings.iterations {
+    let step = 1i32 << iteration;
+    let mut next_color = color.clone();
+    let mut next_variance = variance.clone();
+    next_color.par_chunks_mut(width).zip(next_variance.par_chunks_mut(width)).enumerate()
+      .filter(|(y, _)| crop.contains(crop.x0, *y as u32))
+      .for_each(|(y, (color_row, variance_row))| {
+        for x in crop.x0 as usize..crop.x1 as usize {
+          let p = y * width + x;
+          let (fp, lp) = (&features[p], luminance(color[p]));
+          let noise = settings.sigma_color * variance[p].sqrt() + 1e-4;
+          let (mut sum, mut weight_sum, mut variance_sum) = (Vec3::new(), 0.0, 0.0);
+          for (j, kj) in KERNEL.iter().enumerate() {
+            for (i, ki) in KERNEL.iter().enumerate() {
+              let (ox, oy) = ((i as i32 - 2) * step, (j as i32 - 2) * step);
+              let (qx, qy) = (x as i32 + ox, y as i32 + oy);
+              if qx < 0 || qy < 0 || !crop.contains(qx as u32, qy as u32) {
+                continue;
+              }
+              let q = qy as usize * width + qx as usize;
+              let fq = &features[q];
+              if fq.object_id != fp.object_id {
+                continue;
+              }
+              let mut w = ki * kj * (-(luminance(color[q]) - lp).abs() / noise).exp();
+              // The background has no geometry to compare
+              if fp.object_id != 0 {
+                let distance = ((ox * ox + oy * oy) as f32).sqrt();
+                // Normals averaged over a pixel's samples can be shorter than 1
+                let cosine = fp.normal.unit_vector().dot(fq.normal.unit_vector());
+                let w_normal = cosine.max(0.0).powf(settings.sigma_normal);
+                let w_depth = (-(fp.depth - fq.depth).abs()
+                  / (settings.sigma_depth * depth_gradient[p] * distance + 1e-4)).exp();
+                let w_albedo = (-(fp.albedo - fq.albedo).squared_length()
+                  / (settings.sigma_albedo * settings.sigma_albedo)).exp();
+                w *= w_normal * w_depth * w_albedo;
+              }
+              if w <= 0.0 {
+                // Skipped outright, as the variance of a pixel with one sample is infinite
+                continue;
+              }
+              sum += w * color[q];
+              weight_sum += w;
+              variance_sum += w * w * variance[q];
+            }
+          }
+          // The center pixel always has weight, bar rounding
+          if weight_sum > 0.0 {
+            color_row[x] = sum / weight_sum;
+            variance_row[x] = variance_sum / (weight_sum * weight_sum);
+          }
+        }
+      });
+    color = next_color;
+    variance = next_variance;
+  }
+  color
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filter::*;
+  use rand::Rng;
+  use rand_pcg::Pcg32;
+
+  const SIZE: u32 = 48;
+  const HALF: u32 = SIZE / 2;
+
+  // Two objects side by side, the left one flat and lit evenly, the right one stepping back
+  // at its middle row, its near half bright and its far half dark
+  fn truth(x: u32, y: u32) -> (f32, AovPixel) {
+    let (object_id, depth, brightness) = if x < HALF {
+      (1, 2.0, 0.5)
+    } else if y < HALF {
+      (2, 2.0, 0.8)
+    } else {
+      (2, 8.0, 0.3)
+    };
+    (brightness, AovPixel {
+      depth,
+      normal: Vec3::from((0.0, 0.0, 1.0)),
+      albedo: Vec3::from([0.5; 3]),
+      object_id,
+      ..AovPixel::default()
+    })
+  }
+
+  // A film of `samples` noisy samples per pixel around the truth, and its AOVs
+  fn noisy_film(samples: u32) -> (Film, AovImage) {
+    let mut rng = Pcg32::new(7, 0);
+    let mut film = Film::new(SIZE, SIZE, Filter::from(FilterType::Box));
+    let mut features = Vec::new();
+    for y in 0..SIZE {
+      for x in 0..SIZE {
+        let (brightness, aov) = truth(x, y);
+        features.push(aov);
+        let pixel = &mut film.pixels[(y * SIZE + x) as usize];
+        for _ in 0..samples {
+          let value = (brightness + 0.6 * (rng.gen::<f32>() - 0.5)).max(0.0);
+          pixel.sum += Vec3::from([value; 3]);
+          pixel.weight += 1.0;
+          pixel.samples += 1;
+          pixel.luminance_sum += value;
+          pixel.luminance_sq_sum += value * value;
+        }
+      }
+    }
+    (film, AovImage { width: SIZE, height: SIZE, pixels: features })
+  }
+
+  // Mean and variance of the red of `image` over `pixels`
+  fn statistics(image: &[Vec3], pixels: &[(u32, u32)]) -> (f32, f32) {
+    let values: Vec<f32> = pixels.iter().map(|&(x, y)| image[(y * SIZE + x) as usize].r()).collect();
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / values.len() as f32;
+    (mean, variance)
+  }
+
+  #[test]
+  fn noise_goes_down_but_edges_stay_sharp() {
+    let (film, aovs) = noisy_film(4);
+    let noisy = film.resolve();
+    let denoised = denoise(&film, &aovs, &DenoiseSettings::default());
+
+    let flat: Vec<(u32, u32)> = (4..SIZE - 4).flat_map(|y| (4..HALF - 4).map(move |x| (x, y))).collect();
+    let (_, before) = statistics(&noisy, &flat);
+    let (mean, after) = statistics(&denoised, &flat);
+    assert!(after < before / 10.0, "variance only went from {} to {}", before, after);
+    assert!((mean - 0.5).abs() < 0.02, "flat region's mean moved to {}", mean);
+
+    // Either side of the object edge, and of the depth step, keeps its own brightness
+    let column = |x: u32| -> Vec<(u32, u32)> { (4..SIZE - 4).filter(|&y| y + 1 != HALF && y != HALF).map(|y| (x, y)).collect() };
+    let row = |y: u32| -> Vec<(u32, u32)> { (HALF + 1..SIZE).map(|x| (x, y)).collect() };
+    for (name, pixels, expected) in [("left of the object edge", column(HALF - 1), 0.5),
+                                     ("near side of the depth step", row(HALF - 1), 0.8),
+                                     ("far side of the depth step", row(HALF), 0.3)].iter() {
+      let (mean, _) = statistics(&denoised, pixels);
+      assert!((mean - expected).abs() < 0.03, "{} is {}, not {}", name, mean, expected);
+    }
+    let right_of_edge: Vec<(u32, u32)> = (0..SIZE).map(|y| (HALF, y)).collect();
+    for &(x, y) in right_of_edge.iter() {
+      let expected = truth(x, y).0;
+      assert!((denoised[(y * SIZE + x) as usize].r() - expected).abs() < 0.1, "{}, {} right of the object edge", x, y);
+    }
+  }
+}
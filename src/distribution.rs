@@ -0,0 +1,165 @@
+//! Distribution module contains piecewise-constant distributions and the other pieces of
+//! importance sampling
+
+/// A piecewise-constant 1D distribution over [0, 1), proportional to `func`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution1D {
+  pub func: Vec<f32>,
+  /// `cdf[i]` is the probability of landing before piece i, with a final 1
+  cdf: Vec<f32>,
+  /// Integral of `func` over [0, 1)
+  pub integral: f32,
+}
+
+impl Distribution1D {
+  /// All values must be non-negative. If they're all 0 the distribution is uniform.
+  pub fn new(func: Vec<f32>) -> Self {
+    let n = func.len() as f32;
+    let mut cdf = Vec::with_capacity(func.len() + 1);
+    cdf.push(0.0);
+    for (i, f) in func.iter().enumerate() {
+      cdf.push(cdf[i] + f / n);
+    }
+    let integral = cdf[func.len()];
+    for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+      *c = if integral > 0.0 { *c / integral } else { i as f32 / n };
+    }
+    Distribution1D {
+      func,
+      cdf,
+      integral,
+    }
+  }
+
+  pub fn count(&self) -> usize {
+    self.func.len()
+  }
+
+  /// Map uniform `u` to a point in [0, 1), returning it, its pdf and the piece it's in
+  pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+    // Last piece whose cdf is at most u
+    let offset = self.cdf.partition_point(|&c| c <= u).clamp(1, self.count()) - 1;
+    let width = self.cdf[offset + 1] - self.cdf[offset];
+    let du = if width > 0.0 { (u - self.cdf[offset]) / width } else { 0.0 };
+    let x = ((offset as f32 + du) / self.count() as f32).min(1.0 - f32::EPSILON);
+    (x, self.pdf_piece(offset), offset)
+  }
+
+  /// Density at a point in piece `offset`
+  fn pdf_piece(&self, offset: usize) -> f32 {
+    if self.integral > 0.0 { self.func[offset] / self.integral } else { 1.0 }
+  }
+
+  /// The piece `x` in [0, 1) is in
+  fn piece(&self, x: f32) -> usize {
+    ((x * self.count() as f32) as usize).min(self.count() - 1)
+  }
+}
+
+/// A piecewise-constant 2D distribution over [0, 1)², proportional to a `width` x `height`
+/// grid of values given row by row. Picks a row from the rows' totals, then a column within
+/// that row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution2D {
+  conditional: Vec<Distribution1D>,
+  marginal: Distribution1D,
+}
+
+impl Distribution2D {
+  pub fn new(func: &[f32], width: usize, height: usize) -> Self {
+    let conditional: Vec<Distribution1D> = func.chunks(width).take(height)
+      .map(|row| Distribution1D::new(row.to_vec()))
+      .collect();
+    let marginal = Distribution1D::new(conditional.iter().map(|row| row.integral).collect());
+    Distribution2D {
+      conditional,
+      marginal,
+    }
+  }
+
+  /// Map uniform (u1, u2) to a point (x, y), returning it and its pdf
+  pub fn sample_continuous(&self, u: (f32, f32)) -> ((f32, f32), f32) {
+    let (y, pdf_y, row) = self.marginal.sample_continuous(u.1);
+    let (x, pdf_x, _) = self.conditional[row].sample_continuous(u.0);
+    ((x, y), pdf_x * pdf_y)
+  }
+
+  /// Density at (x, y)
+  pub fn pdf(&self, x: f32, y: f32) -> f32 {
+    let row = &self.conditional[self.marginal.piece(y)];
+    if self.marginal.integral > 0.0 { row.func[row.piece(x)] / self.marginal.integral } else { 1.0 }
+  }
+}
+
+/// The power heuristic's weight for a sample drawn with pdf `f` when another strategy
+/// could have drawn it with pdf `g`
+pub fn power_heuristic(f: f32, g: f32) -> f32 {
+  if f <= 0.0 { 0.0 } else { f * f / (f * f + g * g) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Midpoint rule integral over [0, 1) of `f`, with `n` steps. Exact for a piecewise
+  // constant `f` when the piece count divides `n`.
+  fn integrate(n: usize, f: impl Fn(f32) -> f32) -> f32 {
+    (0..n).map(|i| f((i as f32 + 0.5) / n as f32)).sum::<f32>() / n as f32
+  }
+
+  #[test]
+  fn pdf_1d_integrates_to_one() {
+    for func in [vec![1.0, 3.0, 0.0, 4.0], vec![0.0; 5], vec![2.5], vec![0.0, 0.0, 7.0]].iter() {
+      let d = Distribution1D::new(func.clone());
+      let total = integrate(840, |x| d.pdf_piece(d.piece(x)));
+      assert!((total - 1.0).abs() < 1e-4, "{:?} integrates to {}", func, total);
+    }
+  }
+
+  #[test]
+  fn samples_1d_land_in_proportion_to_the_function() {
+    let func = vec![1.0, 3.0, 0.0, 4.0];
+    let d = Distribution1D::new(func.clone());
+    assert!((d.integral - 2.0).abs() < 1e-6);
+    let n = 8000;
+    let mut counts = [0; 4];
+    for i in 0..n {
+      let (x, pdf, piece) = d.sample_continuous((i as f32 + 0.5) / n as f32);
+      assert!((0.0..1.0).contains(&x));
+      assert_eq!(piece, d.piece(x));
+      assert_eq!(pdf, d.pdf_piece(piece));
+      counts[piece] += 1;
+    }
+    for (count, f) in counts.iter().zip(func.iter()) {
+      assert!((*count as f32 / n as f32 - f / 8.0).abs() < 1e-3, "{:?}", counts);
+    }
+  }
+
+  #[test]
+  fn pdf_2d_integrates_to_one_and_matches_its_samples() {
+    let func = [0.0, 1.0, 2.0, 0.0, 0.0, 0.0, 5.0, 1.0, 0.5, 3.0, 0.0, 0.0];
+    for &(width, height) in [(4, 3), (3, 4), (12, 1)].iter() {
+      let d = Distribution2D::new(&func, width, height);
+      let total = integrate(120, |y| integrate(120, |x| d.pdf(x, y)));
+      assert!((total - 1.0).abs() < 1e-4, "{}x{} integrates to {}", width, height, total);
+      for j in 0..32 {
+        for i in 0..32 {
+          let ((x, y), pdf) = d.sample_continuous(((i as f32 + 0.5) / 32.0, (j as f32 + 0.5) / 32.0));
+          assert!(pdf > 0.0, "sampled a zero at ({}, {})", x, y);
+          assert!((pdf - d.pdf(x, y)).abs() < 1e-4 * pdf, "{} vs {} at ({}, {})", pdf, d.pdf(x, y), x, y);
+        }
+      }
+    }
+    let black = Distribution2D::new(&[0.0; 6], 3, 2);
+    assert!((integrate(100, |y| integrate(100, |x| black.pdf(x, y))) - 1.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn power_heuristic_weights_add_to_one() {
+    for &(f, g) in [(1.0, 1.0), (0.3, 2.0), (5.0, 0.0)].iter() {
+      assert!((power_heuristic(f, g) + power_heuristic(g, f) - 1.0).abs() < 1e-6);
+    }
+    assert_eq!(power_heuristic(0.0, 1.0), 0.0);
+    assert_eq!(power_heuristic(1.0, 0.0), 1.0);
+  }
+}
@@ -0,0 +1,103 @@
+//! Envmap module contains the image based environment light and how to importance sample it
+use std::f32::consts::PI;
+
+use crate::distribution::*;
+use crate::film::*;
+use crate::hdr::*;
+use crate::vec3::*;
+
+/// A latitude-longitude HDR image surrounding the scene. Its top row is straight up (+y),
+/// its bottom row straight down, and its middle row the horizon, with the left edge at +x
+/// and a quarter of the way across at +z.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvironmentMap {
+  pub image: HdrImage,
+  /// Multiplier on the image's values
+  pub intensity: f32,
+  /// Degrees the map is turned around the y axis, from +x towards +z
+  pub rotation: f32,
+  /// Pixels' chance of being sampled: luminance times the solid angle they cover
+  distribution: Distribution2D,
+}
+
+/// A direction towards the environment, chosen by its distribution
+pub struct EnvironmentSample {
+  pub wi: Vec3,
+  /// Density over solid angle
+  pub pdf: f32,
+  pub radiance: Vec3,
+}
+
+impl EnvironmentMap {
+  pub fn new(image: HdrImage, intensity: f32, rotation: f32) -> Self {
+    let (width, height) = (image.width as usize, image.height as usize);
+    let func: Vec<f32> = image.pixels.iter().enumerate().map(|(index, &p)| {
+      // Rows near the poles are squashed into less solid angle
+      let theta = PI * ((index / width) as f32 + 0.5) / height as f32;
+      luminance(p).max(0.0) * theta.sin()
+    }).collect();
+    let distribution = Distribution2D::new(&func, width, height);
+    EnvironmentMap {
+      image,
+      intensity,
+      rotation,
+      distribution,
+    }
+  }
+
+  /// Read the map from a .hdr or .pfm file
+  pub fn load(path: &str, intensity: f32, rotation: f32) -> Result<Self, String> {
+    Ok(Self::new(HdrImage::load(path)?, intensity, rotation))
+  }
+
+  /// Radiance arriving from `direction`
+  pub fn radiance(&self, direction: Vec3) -> Vec3 {
+    let (u, v) = self.map_coordinates(direction);
+    let x = ((u * self.image.width as f32) as u32).min(self.image.width - 1);
+    let y = ((v * self.image.height as f32) as u32).min(self.image.height - 1);
+    self.intensity * self.image.pixels[(y * self.image.width + x) as usize]
+  }
+
+  /// Pick a direction in proportion to how much light comes from it, from uniform `u`
+  pub fn sample(&self, u: (f32, f32)) -> Option<EnvironmentSample> {
+    let ((x, y), map_pdf) = self.distribution.sample_continuous(u);
+    let (theta, phi) = (PI * y, 2.0 * PI * x);
+    let sin_theta = theta.sin();
+    if map_pdf <= 0.0 || sin_theta <= 0.0 {
+      return None;
+    }
+    let local = Vec3::from((sin_theta * phi.cos(), theta.cos(), sin_theta * phi.sin()));
+    let wi = rotate_y(local, self.rotation);
+    Some(EnvironmentSample {
+      wi,
+      // The map's [0, 1)² stretched over the sphere
+      pdf: map_pdf / (2.0 * PI * PI * sin_theta),
+      radiance: self.radiance(wi),
+    })
+  }
+
+  /// Density over solid angle of `sample` choosing `direction`
+  pub fn pdf(&self, direction: Vec3) -> f32 {
+    let (u, v) = self.map_coordinates(direction);
+    let sin_theta = (PI * v).sin();
+    if sin_theta <= 0.0 {
+      return 0.0;
+    }
+    self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+  }
+
+  // Where in the map, each in [0, 1), a world direction looks
+  fn map_coordinates(&self, direction: Vec3) -> (f32, f32) {
+    let d = rotate_y(direction.unit_vector(), -self.rotation);
+    let theta = d.y().clamp(-1.0, 1.0).acos();
+    let phi = d.z().atan2(d.x());
+    let u = phi / (2.0 * PI);
+    (if u < 0.0 { u + 1.0 } else { u }.min(1.0 - f32::EPSILON), theta / PI)
+  }
+}
+
+// Turn `v` by `degrees` around the y axis, from +x towards +z
+fn rotate_y(v: Vec3, degrees: f32) -> Vec3 {
+  let (sin, cos) = degrees.to_radians().sin_cos();
+  Vec3::from((cos * v.x() - sin * v.z(), v.y(), sin * v.x() + cos * v.z()))
+}
@@ -0,0 +1,214 @@
+//! Exr module writes uncompressed multi-layer OpenEXR images
+use std::io::{self, Write};
+
+/// Pixel values of one channel, top row first
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExrData {
+  Uint(Vec<u32>),
+  Float(Vec<f32>),
+}
+
+impl ExrData {
+  /// OpenEXR's pixel type code
+  fn pixel_type(&self) -> i32 {
+    match self {
+      ExrData::Uint(_) => 0,
+      ExrData::Float(_) => 2,
+    }
+  }
+}
+
+/// A named channel. Layers are expressed in the name, e.g. `normal.X`, with the beauty
+/// image as the unprefixed `R`, `G` and `B`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExrChannel {
+  pub name: String,
+  pub data: ExrData,
+}
+
+impl From<(&str, Vec<f32>)> for ExrChannel {
+  fn from(tuple: (&str, Vec<f32>)) -> Self {
+    ExrChannel {
+      name: tuple.0.to_string(),
+      data: ExrData::Float(tuple.1),
+    }
+  }
+}
+
+impl From<(&str, Vec<u32>)> for ExrChannel {
+  fn from(tuple: (&str, Vec<u32>)) -> Self {
+    ExrChannel {
+      name: tuple.0.to_string(),
+      data: ExrData::Uint(tuple.1),
+    }
+  }
+}
+
+/// Write a `width` x `height` scanline EXR with no compression. Every channel must have
+/// `width * height` values. `metadata` is added to the header as string attributes.
+pub fn write_exr<W: Write>(out: &mut W, width: u32, height: u32, mut channels: Vec<ExrChannel>,
+                           metadata: &[(String, String)]) -> io::Result<()> {
+  // The format requires channels in name order, in the header and in every scanline
+  channels.sort_by(|a, b| a.name.cmp(&b.name));
+  let long_names = channels.iter().map(|c| &c.name).chain(metadata.iter().map(|(name, _)| name))
+    .any(|name| name.len() > 31);
+
+  let mut header = Vec::new();
+  header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+  // Version 2, single part scanline, with the long names flag if needed
+  let version: u32 = 2 | if long_names { 0x400 } else { 0 };
+  header.extend_from_slice(&version.to_le_bytes());
+
+  let mut chlist = Vec::new();
+  for channel in channels.iter() {
+    chlist.extend_from_slice(channel.name.as_bytes());
+    chlist.push(0);
+    chlist.extend_from_slice(&channel.data.pixel_type().to_le_bytes());
+    // pLinear and 3 reserved bytes, then x & y sampling
+    chlist.extend_from_slice(&[0; 4]);
+    chlist.extend_from_slice(&1i32.to_le_bytes());
+    chlist.extend_from_slice(&1i32.to_le_bytes());
+  }
+  chlist.push(0);
+  attribute(&mut header, "channels", "chlist", &chlist);
+  attribute(&mut header, "compression", "compression", &[0]);
+  let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1].iter()
+    .flat_map(|v| v.to_le_bytes().to_vec())
+    .collect();
+  attribute(&mut header, "dataWindow", "box2i", &window);
+  attribute(&mut header, "displayWindow", "box2i", &window);
+  attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+  attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+  attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+  attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+  for (name, value) in metadata.iter() {
+    attribute(&mut header, name, "string", value.as_bytes());
+  }
+  header.push(0);
+
+  // One scanline per chunk: its y, its size, then each channel's values for the row
+  let line_size: usize = width as usize * 4 * channels.len();
+  let chunk_size = 8 + line_size as u64;
+  let table_end = (header.len() + 8 * height as usize) as u64;
+  out.write_all(&header)?;
+  for y in 0..u64::from(height) {
+    out.write_all(&(table_end + y * chunk_size).to_le_bytes())?;
+  }
+  for y in 0..height as usize {
+    out.write_all(&(y as i32).to_le_bytes())?;
+    out.write_all(&(line_size as i32).to_le_bytes())?;
+    let row = y * width as usize..(y + 1) * width as usize;
+    for channel in channels.iter() {
+      match &channel.data {
+        ExrData::Uint(data) => for v in data[row.clone()].iter() {
+          out.write_all(&v.to_le_bytes())?;
+        },
+        ExrData::Float(data) => for v in data[row.clone()].iter() {
+          out.write_all(&v.to_le_bytes())?;
+        },
+      }
+    }
+  }
+  Ok(())
+}
+
+// Append a header attribute: name, type name, size, value
+fn attribute(header: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+  header.extend_from_slice(name.as_bytes());
+  header.push(0);
+  header.extend_from_slice(type_name.as_bytes());
+  header.push(0);
+  header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+  header.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::convert::TryInto;
+
+  // Null terminated string at `bytes[*at..]`, moving `at` past it
+  fn read_str(bytes: &[u8], at: &mut usize) -> String {
+    let end = *at + bytes[*at..].iter().position(|&b| b == 0).unwrap();
+    let s = String::from_utf8(bytes[*at..end].to_vec()).unwrap();
+    *at = end + 1;
+    s
+  }
+
+  fn read_i32(bytes: &[u8], at: usize) -> i32 {
+    i32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+  }
+
+  // The header's attributes as (name, type, value), and where the header ends
+  fn attributes(bytes: &[u8]) -> (Vec<(String, String, Vec<u8>)>, usize) {
+    let mut at = 8;
+    let mut attributes = Vec::new();
+    while bytes[at] != 0 {
+      let name = read_str(bytes, &mut at);
+      let type_name = read_str(bytes, &mut at);
+      let size = read_i32(bytes, at) as usize;
+      attributes.push((name, type_name, bytes[at + 4..at + 4 + size].to_vec()));
+      at += 4 + size;
+    }
+    (attributes, at + 1)
+  }
+
+  #[test]
+  fn header_lists_sorted_channels_and_the_image_size() {
+    let channels = vec![
+      ExrChannel::from(("R", vec![1.0f32; 6])),
+      ExrChannel::from(("G", vec![2.0f32; 6])),
+      ExrChannel::from(("crypto.id", vec![7u32; 6])),
+      ExrChannel::from(("B", vec![3.0f32; 6])),
+    ];
+    let metadata = vec![("note".to_string(), "hello".to_string())];
+    let mut bytes = Vec::new();
+    write_exr(&mut bytes, 3, 2, channels, &metadata).unwrap();
+
+    assert_eq!(&bytes[0..4], &[0x76, 0x2f, 0x31, 0x01]);
+    assert_eq!(read_i32(&bytes, 4), 2);
+    let (attributes, header_end) = attributes(&bytes);
+    let names: Vec<&str> = attributes.iter().map(|(name, _, _)| name.as_str()).collect();
+    for required in ["channels", "compression", "dataWindow", "displayWindow", "lineOrder",
+                     "pixelAspectRatio", "screenWindowCenter", "screenWindowWidth"].iter() {
+      assert!(names.contains(required), "no {} attribute", required);
+    }
+    let value = |name: &str| attributes.iter().find(|a| a.0 == name).unwrap().clone();
+
+    let (_, type_name, chlist) = value("channels");
+    assert_eq!(type_name, "chlist");
+    let mut at = 0;
+    let mut listed = Vec::new();
+    while chlist[at] != 0 {
+      let name = read_str(&chlist, &mut at);
+      listed.push((name, read_i32(&chlist, at)));
+      at += 16;
+    }
+    assert_eq!(listed, vec![("B".to_string(), 2), ("G".to_string(), 2), ("R".to_string(), 2),
+                            ("crypto.id".to_string(), 0)]);
+    let (_, _, window) = value("dataWindow");
+    assert_eq!((0..4).map(|i| read_i32(&window, 4 * i)).collect::<Vec<_>>(), vec![0, 0, 2, 1]);
+    assert_eq!(value("compression").2, vec![0]);
+    assert_eq!(value("note"), ("note".to_string(), "string".to_string(), b"hello".to_vec()));
+
+    // The offset table points at each scanline, which holds its y and every channel's row
+    let line_size = 3 * 4 * 4;
+    for y in 0..2 {
+      let offset = u64::from_le_bytes(bytes[header_end + 8 * y..header_end + 8 * y + 8].try_into().unwrap()) as usize;
+      assert_eq!(read_i32(&bytes, offset), y as i32);
+      assert_eq!(read_i32(&bytes, offset + 4), line_size);
+      // B comes first
+      assert_eq!(f32::from_bits(read_i32(&bytes, offset + 8) as u32), 3.0);
+    }
+    assert_eq!(bytes.len(), header_end + 2 * 8 + 2 * (8 + line_size as usize));
+  }
+
+  #[test]
+  fn long_names_set_the_long_names_flag() {
+    let name = "a_layer_with_a_rather_long_name_indeed.R";
+    assert!(name.len() > 31);
+    let mut bytes = Vec::new();
+    write_exr(&mut bytes, 1, 1, vec![ExrChannel::from((name, vec![0.0f32]))], &[]).unwrap();
+    assert_eq!(read_i32(&bytes, 4), 0x402);
+  }
+}
@@ -0,0 +1,314 @@
+//! Film module contains the float framebuffer samples are reconstructed into
+use crate::filter::*;
+use crate::vec3::*;
+
+/// Filter weighted sum of every sample that overlaps a pixel, plus unfiltered statistics
+/// of the samples taken inside it
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct FilmPixel {
+  pub sum: Vec3,
+  pub weight: f32,
+  /// Number of samples taken inside this pixel
+  pub samples: u32,
+  /// Sum and sum of squares of those samples' luminance
+  pub luminance_sum: f32,
+  pub luminance_sq_sum: f32,
+}
+
+impl FilmPixel {
+  pub fn color(&self) -> Vec3 {
+    if self.weight > 0.0 {
+      self.sum / self.weight
+    } else {
+      Vec3::new()
+    }
+  }
+
+  /// Standard error of the mean luminance, relative to that mean. Means below `min_mean`
+  /// are clamped so near-black pixels don't soak up samples on noise nobody can see.
+  pub fn relative_error(&self, min_mean: f32) -> f32 {
+    if self.samples < 2 {
+      return f32::INFINITY;
+    }
+    let mean = self.luminance_sum / self.samples as f32;
+    self.luminance_variance().sqrt() / mean.max(min_mean)
+  }
+
+  /// Variance of the mean luminance, infinite with fewer than two samples
+  pub fn luminance_variance(&self) -> f32 {
+    if self.samples < 2 {
+      return f32::INFINITY;
+    }
+    let n = self.samples as f32;
+    let mean = self.luminance_sum / n;
+    let variance = ((self.luminance_sq_sum - n * mean * mean) / (n - 1.0)).max(0.0);
+    variance / n
+  }
+}
+
+/// Rec. 709 luminance of a linear color
+pub fn luminance(c: Vec3) -> f32 {
+  0.2126 * c.r() + 0.7152 * c.g() + 0.0722 * c.b()
+}
+
+/// Pixel bounds [x0, x1) x [y0, y1) in raster space (y down, row 0 is the top of the image)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bounds {
+  pub x0: u32,
+  pub y0: u32,
+  pub x1: u32,
+  pub y1: u32,
+}
+
+impl Bounds {
+  pub fn new(x0: u32, y0: u32, x1: u32, y1: u32) -> Self {
+    Bounds { x0, y0, x1, y1 }
+  }
+
+  pub fn width(&self) -> u32 {
+    self.x1 - self.x0
+  }
+
+  pub fn height(&self) -> u32 {
+    self.y1 - self.y0
+  }
+
+  pub fn area(&self) -> u32 {
+    self.width() * self.height()
+  }
+
+  pub fn contains(&self, x: u32, y: u32) -> bool {
+    x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+  }
+
+  /// Grow by `margin` pixels on every side, clamped to `limit`
+  pub fn expand(&self, margin: u32, limit: &Bounds) -> Self {
+    Bounds {
+      x0: self.x0.saturating_sub(margin).max(limit.x0),
+      y0: self.y0.saturating_sub(margin).max(limit.y0),
+      x1: (self.x1 + margin).min(limit.x1),
+      y1: (self.y1 + margin).min(limit.y1),
+    }
+  }
+}
+
+/// Call `add` with the index in `bounds` and filter weight of every pixel a sample at
+/// (x, y) lands in
+pub fn splat<F: FnMut(usize, f32)>(filter: &Filter, bounds: &Bounds, x: f32, y: f32, mut add: F) {
+  let r = filter.radius;
+  let b = bounds;
+  let x0 = ((x - 0.5 - r).ceil().max(b.x0 as f32)) as u32;
+  let y0 = ((y - 0.5 - r).ceil().max(b.y0 as f32)) as u32;
+  let x1 = ((x - 0.5 + r).floor() + 1.0).min(b.x1 as f32).max(0.0) as u32;
+  let y1 = ((y - 0.5 + r).floor() + 1.0).min(b.y1 as f32).max(0.0) as u32;
+  for j in y0..y1 {
+    for i in x0..x1 {
+      let weight = filter.evaluate(x - (i as f32 + 0.5), y - (j as f32 + 0.5));
+      if weight != 0.0 {
+        add(((j - b.y0) * b.width() + (i - b.x0)) as usize, weight);
+      }
+    }
+  }
+}
+
+/// The image being rendered. Samples are splatted into a FilmTile, and tiles are merged
+/// back into the Film, so threads never write to the same pixels.
+/// Only pixels inside `crop` are rendered; the rest of the image stays black.
+pub struct Film {
+  pub width: u32,
+  pub height: u32,
+  pub filter: Filter,
+  pub crop: Bounds,
+  pub pixels: Vec<FilmPixel>,
+  /// Filter weighted sums of the samples routed to each light path expression buffer.
+  /// They share the beauty pixels' weights, so the buffers add up to the beauty image.
+  pub lpe: Vec<Vec<Vec3>>,
+}
+
+impl Film {
+  pub fn new(width: u32, height: u32, filter: Filter) -> Self {
+    Film {
+      width,
+      height,
+      filter,
+      crop: Bounds::new(0, 0, width, height),
+      pixels: vec![FilmPixel::default(); (width * height) as usize],
+      lpe: Vec::new(),
+    }
+  }
+
+  /// Also accumulate `buffers` light path expression buffers
+  pub fn with_lpe(mut self, buffers: usize) -> Self {
+    self.lpe = vec![vec![Vec3::new(); (self.width * self.height) as usize]; buffers];
+    self
+  }
+
+  /// Only render the pixels in `crop`. The framing stays that of the whole image.
+  pub fn with_crop(mut self, crop: Bounds) -> Self {
+    self.crop = crop;
+    self
+  }
+
+  pub fn bounds(&self) -> Bounds {
+    Bounds::new(0, 0, self.width, self.height)
+  }
+
+  /// A tile that can take samples generated inside `sample_bounds`. It's grown by the filter
+  /// radius, since those samples also land in the neighbouring pixels (inside the crop).
+  pub fn tile(&self, sample_bounds: &Bounds) -> FilmTile {
+    let margin = (self.filter.radius - 0.5).max(0.0).ceil() as u32;
+    FilmTile::new(sample_bounds.expand(margin, &self.crop), self.filter, self.lpe.len())
+  }
+
+  /// Add a tile's weighted samples into the image
+  pub fn merge_tile(&mut self, tile: &FilmTile) {
+    let b = tile.bounds;
+    for y in b.y0..b.y1 {
+      for x in b.x0..b.x1 {
+        let src = tile.pixels[((y - b.y0) * b.width() + (x - b.x0)) as usize];
+        let dst = &mut self.pixels[(y * self.width + x) as usize];
+        dst.sum += src.sum;
+        dst.weight += src.weight;
+        dst.samples += src.samples;
+        dst.luminance_sum += src.luminance_sum;
+        dst.luminance_sq_sum += src.luminance_sq_sum;
+        for (dst, src) in self.lpe.iter_mut().zip(tile.lpe.iter()) {
+          dst[(y * self.width + x) as usize] += src[((y - b.y0) * b.width() + (x - b.x0)) as usize];
+        }
+      }
+    }
+  }
+
+  /// Reconstructed linear color of light path expression buffer `buffer`
+  pub fn resolve_lpe(&self, buffer: usize) -> Vec<Vec3> {
+    self.lpe[buffer].iter().zip(self.pixels.iter())
+      .map(|(&sum, p)| if p.weight > 0.0 { sum / p.weight } else { Vec3::new() })
+      .collect()
+  }
+
+  /// Fewest samples taken by any pixel inside the crop
+  pub fn min_samples(&self) -> u32 {
+    let c = self.crop;
+    (c.y0..c.y1).flat_map(|y| (c.x0..c.x1).map(move |x| (x, y)))
+      .map(|(x, y)| self.pixels[(y * self.width + x) as usize].samples)
+      .min()
+      .unwrap_or(0)
+  }
+
+  /// Reconstructed linear color of every pixel, top row first
+  pub fn resolve(&self) -> Vec<Vec3> {
+    self.pixels.iter().map(FilmPixel::color).collect()
+  }
+}
+
+/// Copy the pixels inside `bounds` out of a `width` wide image
+pub fn crop_image<T: Clone>(pixels: &[T], width: u32, bounds: &Bounds) -> Vec<T> {
+  (bounds.y0..bounds.y1)
+    .flat_map(|y| (bounds.x0..bounds.x1).map(move |x| pixels[(y * width + x) as usize].clone()))
+    .collect()
+}
+
+pub struct FilmTile {
+  pub bounds: Bounds,
+  pub filter: Filter,
+  pub pixels: Vec<FilmPixel>,
+  pub lpe: Vec<Vec<Vec3>>,
+}
+
+impl FilmTile {
+  pub fn new(bounds: Bounds, filter: Filter, lpe_buffers: usize) -> Self {
+    FilmTile {
+      bounds,
+      filter,
+      pixels: vec![FilmPixel::default(); bounds.area() as usize],
+      lpe: vec![vec![Vec3::new(); bounds.area() as usize]; lpe_buffers],
+    }
+  }
+
+  /// Splat a sample taken in `pixel` at continuous raster position (x, y) into every pixel
+  /// the filter overlaps. Pixel (i, j) has its center at (i + 0.5, j + 0.5).
+  pub fn add_sample(&mut self, pixel: (u32, u32), x: f32, y: f32, color: Vec3) {
+    let b = self.bounds;
+    // Statistics only go to the pixel the sample was taken in. It's passed in rather than
+    // recovered from (x, y), which can round up into the next pixel.
+    let (px, py) = pixel;
+    if b.contains(px, py) {
+      let y_lum = luminance(color);
+      let pixel = &mut self.pixels[((py - b.y0) * b.width() + (px - b.x0)) as usize];
+      pixel.samples += 1;
+      pixel.luminance_sum += y_lum;
+      pixel.luminance_sq_sum += y_lum * y_lum;
+    }
+
+    let pixels = &mut self.pixels;
+    splat(&self.filter, &b, x, y, |index, weight| {
+      pixels[index].sum += weight * color;
+      pixels[index].weight += weight;
+    });
+  }
+
+  /// Splat a sample into light path expression buffer `buffer`. It must also be added
+  /// with add_sample, which accounts for its weight.
+  pub fn add_lpe_sample(&mut self, buffer: usize, x: f32, y: f32, color: Vec3) {
+    let sums = &mut self.lpe[buffer];
+    splat(&self.filter, &self.bounds, x, y, |index, weight| sums[index] += weight * color);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn constant_image_resolves_to_the_constant_with_every_filter() {
+    let color = Vec3::from((0.25, 0.5, 1.0));
+    for &filter_type in [FilterType::Box, FilterType::Tent, FilterType::Gaussian, FilterType::Mitchell,
+                         FilterType::Lanczos].iter() {
+      let mut film = Film::new(6, 4, Filter::from(filter_type));
+      let mut tile = film.tile(&film.bounds());
+      for y in 0..film.height {
+        for x in 0..film.width {
+          // A 4x4 jittered grid of samples in every pixel
+          for s in 0..16 {
+            let fx = x as f32 + ((s % 4) as f32 + 0.3) / 4.0;
+            let fy = y as f32 + ((s / 4) as f32 + 0.6) / 4.0;
+            tile.add_sample((x, y), fx, fy, color);
+          }
+        }
+      }
+      film.merge_tile(&tile);
+      assert_eq!(film.min_samples(), 16);
+      for pixel in film.resolve() {
+        assert!((pixel - color).length() < 1e-4, "{} resolved {:?}", filter_type, pixel);
+      }
+    }
+  }
+
+  #[test]
+  fn tiles_merge_into_the_same_image_as_one_tile() {
+    let filter = Filter::from(FilterType::Gaussian);
+    let sample = |x: u32, y: u32| Vec3::from((x as f32, y as f32, 1.0));
+    let mut whole = Film::new(8, 8, filter);
+    let mut split = Film::new(8, 8, filter);
+    let mut tile = whole.tile(&whole.bounds());
+    for y in 0..8 {
+      for x in 0..8 {
+        tile.add_sample((x, y), x as f32 + 0.5, y as f32 + 0.5, sample(x, y));
+      }
+    }
+    whole.merge_tile(&tile);
+    for &(x0, y0) in [(0, 0), (4, 0), (0, 4), (4, 4)].iter() {
+      let bounds = Bounds::new(x0, y0, x0 + 4, y0 + 4);
+      let mut tile = split.tile(&bounds);
+      for y in y0..y0 + 4 {
+        for x in x0..x0 + 4 {
+          tile.add_sample((x, y), x as f32 + 0.5, y as f32 + 0.5, sample(x, y));
+        }
+      }
+      split.merge_tile(&tile);
+    }
+    for (a, b) in whole.resolve().iter().zip(split.resolve().iter()) {
+      assert!((*a - *b).length() < 1e-4, "{:?} vs {:?}", a, b);
+    }
+  }
+}
@@ -0,0 +1,193 @@
+//! Filter module contains the pixel reconstruction filters used by the Film
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FilterType {
+  Box,
+  Tent,
+  Gaussian,
+  Mitchell,
+  Lanczos,
+}
+
+impl FilterType {
+  /// Radius (in pixels) each filter is usually run at
+  pub fn default_radius(self) -> f32 {
+    match self {
+      FilterType::Box => 0.5,
+      FilterType::Tent => 1.0,
+      FilterType::Gaussian => 1.5,
+      FilterType::Mitchell => 2.0,
+      FilterType::Lanczos => 3.0,
+    }
+  }
+}
+
+impl FromStr for FilterType {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "box" => Ok(FilterType::Box),
+      "tent" => Ok(FilterType::Tent),
+      "gaussian" => Ok(FilterType::Gaussian),
+      "mitchell" => Ok(FilterType::Mitchell),
+      "lanczos" => Ok(FilterType::Lanczos),
+      _ => Err(format!("unknown filter '{}'", s)),
+    }
+  }
+}
+
+impl fmt::Display for FilterType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      FilterType::Box => "box",
+      FilterType::Tent => "tent",
+      FilterType::Gaussian => "gaussian",
+      FilterType::Mitchell => "mitchell",
+      FilterType::Lanczos => "lanczos",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// A separable filter: the weight of a sample at offset (dx, dy) from a pixel center
+/// is f(dx) * f(dy), and zero once either offset reaches `radius`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Filter {
+  pub filter_type: FilterType,
+  pub radius: f32,
+}
+
+impl Default for Filter {
+  /// A half pixel box, i.e. a plain average of the samples inside each pixel
+  fn default() -> Self {
+    Filter::from(FilterType::Box)
+  }
+}
+
+impl From<FilterType> for Filter {
+  fn from(filter_type: FilterType) -> Self {
+    Filter {
+      filter_type,
+      radius: filter_type.default_radius(),
+    }
+  }
+}
+
+impl From<(FilterType, f32)> for Filter {
+  fn from(tuple: (FilterType, f32)) -> Self {
+    Filter {
+      filter_type: tuple.0,
+      radius: tuple.1,
+    }
+  }
+}
+
+impl Filter {
+  pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+    self.evaluate_1d(dx) * self.evaluate_1d(dy)
+  }
+
+  fn evaluate_1d(&self, x: f32) -> f32 {
+    let r = self.radius;
+    match self.filter_type {
+      // Half open so a sample on a pixel edge only lands in one pixel
+      FilterType::Box => if x >= -r && x < r { 1.0 } else { 0.0 },
+      FilterType::Tent => (r - x.abs()).max(0.0),
+      FilterType::Gaussian => {
+        // sigma = r / 3, shifted down so the filter reaches 0 at the radius
+        let alpha = 4.5 / (r * r);
+        ((-alpha * x * x).exp() - (-alpha * r * r).exp()).max(0.0)
+      },
+      FilterType::Mitchell => mitchell_1d(2.0 * x / r, 1.0 / 3.0, 1.0 / 3.0),
+      FilterType::Lanczos => {
+        if x.abs() >= r {
+          0.0
+        } else {
+          sinc(x) * sinc(x / r)
+        }
+      },
+    }
+  }
+}
+
+// Mitchell-Netravali cubic over [-2, 2]
+fn mitchell_1d(x: f32, b: f32, c: f32) -> f32 {
+  let x = x.abs();
+  if x >= 2.0 {
+    0.0
+  } else if x >= 1.0 {
+    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x
+      + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+  } else {
+    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+      + (6.0 - 2.0 * b)) / 6.0
+  }
+}
+
+// Normalized sinc, sin(pi x) / (pi x)
+fn sinc(x: f32) -> f32 {
+  if x.abs() < 1e-5 {
+    1.0
+  } else {
+    (PI * x).sin() / (PI * x)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FILTERS: [FilterType; 5] = [
+    FilterType::Box, FilterType::Tent, FilterType::Gaussian, FilterType::Mitchell, FilterType::Lanczos,
+  ];
+
+  // Midpoint rule integral of the filter over its support
+  fn integral(filter: &Filter) -> f32 {
+    let n = 200;
+    let step = 2.0 * filter.radius / n as f32;
+    let mut sum = 0.0;
+    for j in 0..n {
+      for i in 0..n {
+        let x = -filter.radius + (i as f32 + 0.5) * step;
+        let y = -filter.radius + (j as f32 + 0.5) * step;
+        sum += filter.evaluate(x, y) * step * step;
+      }
+    }
+    sum
+  }
+
+  #[test]
+  fn filters_peak_in_the_middle_and_vanish_at_their_radius() {
+    for &filter_type in FILTERS.iter() {
+      let filter = Filter::from(filter_type);
+      let r = filter.radius;
+      let center = filter.evaluate(0.0, 0.0);
+      assert!(center > 0.0, "{} is {} in the middle", filter_type, center);
+      for &(dx, dy) in [(r, 0.0), (0.0, r), (r + 0.5, 0.2), (-r - 0.1, 0.0)].iter() {
+        assert_eq!(filter.evaluate(dx, dy), 0.0, "{} at ({}, {})", filter_type, dx, dy);
+      }
+      for &x in [0.1, 0.4, 0.9].iter() {
+        let x = x * r;
+        assert!((filter.evaluate(x, 0.3) - filter.evaluate(-x, -0.3)).abs() < 1e-6, "{} isn't symmetric", filter_type);
+        assert!(filter.evaluate(x, 0.0) <= center, "{} is higher off center", filter_type);
+      }
+    }
+  }
+
+  #[test]
+  fn filters_have_a_positive_integral() {
+    // The film divides by the summed weights, so a filter only has to integrate to
+    // something positive. Box and tent at their default radius are already unit.
+    assert!((integral(&Filter::from(FilterType::Box)) - 1.0).abs() < 1e-3);
+    assert!((integral(&Filter::from(FilterType::Tent)) - 1.0).abs() < 1e-3);
+    for &filter_type in FILTERS.iter() {
+      for &radius in [0.5, 1.0, 2.5].iter() {
+        let filter = Filter::from((filter_type, radius));
+        assert!(integral(&filter) > 0.0, "{} at radius {}", filter_type, radius);
+      }
+    }
+  }
+}
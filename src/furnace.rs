@@ -0,0 +1,234 @@
+//! Furnace module checks that the glass and layered materials conserve energy. In a white
+//! furnace, uniform white light all around, a surface that neither absorbs nor emits light
+//! should look exactly as bright as the furnace.
+use crate::hitable::*;
+use crate::material::*;
+use crate::ray::*;
+use crate::sampler::*;
+use crate::settings::*;
+use crate::texture::*;
+use crate::vec3::*;
+
+/// Rays shot at the surface per material, side & angle
+const SAMPLES: u32 = 100_000;
+/// Angles between the incoming ray and the normal, in degrees
+const ANGLES: [f32; 5] = [0.0, 30.0, 60.0, 80.0, 89.0];
+
+/// Shoot rays at a flat piece of each material from both sides, and print the fraction of
+/// light that scatters onwards, the directional albedo. Clear glass keeps all of it. Frosted
+/// glass should stay under 1, losing a little more as it gets rougher and the angle more
+/// grazing, since light that would scatter between microfacets more than once is lost.
+/// Varnished white should keep nearly all of it, rough varnish losing some at grazing
+/// angles like frosted glass, and a mix of white and clear glass should keep everything.
+pub fn white_furnace(settings: &RenderSettings) {
+  let white = MaterialType::Lambertian(Lambertian::from((1.0, 1.0, 1.0)));
+  let clear = MaterialType::Dielectric(Dielectric::from(1.5));
+  let materials: Vec<(String, MaterialType)> = vec![
+    ("clear glass".to_string(), clear.clone()),
+  ].into_iter().chain([0.0, 0.1, 0.3, 0.6, 1.0].iter().map(|&roughness| {
+    (format!("frosted glass {}", roughness), MaterialType::RoughDielectric(RoughDielectric::from((1.5, roughness))))
+  })).chain([0.0, 0.3].iter().map(|&roughness| {
+    (format!("varnished white {}", roughness), MaterialType::Layered(Layered::from((white.clone(), 1.5, roughness))))
+  })).chain(std::iter::once(
+    ("white & glass mix".to_string(), MaterialType::Mix(Mix::from((white.clone(), clear.clone(), Texture::from(0.5)))))
+  )).collect();
+
+  println!("directional albedo, {} samples per angle, {} sampler", SAMPLES, settings.sampler);
+  print!("{:<20} {:<8}", "material", "side");
+  for angle in ANGLES.iter() {
+    print!(" {:>7}", format!("{}°", angle));
+  }
+  println!();
+
+  let mut ctx = SamplerContext::with_sampler(settings.seed, settings.sampler, SAMPLES);
+  let mut gains = 0;
+  for (row, (name, material)) in materials.iter().enumerate() {
+    for &inside in [false, true].iter() {
+      print!("{:<20} {:<8}", name, if inside { "inside" } else { "outside" });
+      for (column, angle) in ANGLES.iter().enumerate() {
+        let (albedo, error) = directional_albedo(material, angle.to_radians(), inside, &mut ctx,
+                                                 (2 * row + inside as usize) as u32, column as u32, SAMPLES);
+        // Past 1 by more than the noise means the material makes light
+        if albedo > 1.0 + 4.0 * error + 1e-4 {
+          gains += 1;
+        }
+        print!(" {:>7.4}", albedo);
+      }
+      println!();
+    }
+  }
+  if gains == 0 {
+    println!("ok, nothing gains energy");
+  } else {
+    println!("{} gain energy", gains);
+  }
+}
+
+// Mean & standard error of the throughput of `samples` rays hitting `material` at `theta`
+// to the normal, from outside or inside. Each (row, column) gets its own sample stream.
+fn directional_albedo(material: &MaterialType, theta: f32, inside: bool, ctx: &mut SamplerContext,
+                      row: u32, column: u32, samples: u32) -> (f32, f32) {
+  let direction = Vec3::from((theta.sin(), if inside { theta.cos() } else { -theta.cos() }, 0.0));
+  let r_in = Ray::from((Vec3::new() - direction, direction));
+  let rec = furnace_hit(material);
+  let (mut sum, mut sum_squares) = (0.0f64, 0.0f64);
+  for s in 0..samples {
+    ctx.start_pixel_sample(column, row, s);
+    let mut attenuation = Vec3::new();
+    // Everything here is gray, so any channel will do
+    let kept = match material.scatter(&r_in, &rec, &mut attenuation, ctx) {
+      Some(_) => f64::from(attenuation.r()),
+      None => 0.0,
+    };
+    sum += kept;
+    sum_squares += kept * kept;
+  }
+  let n = f64::from(samples);
+  let mean = sum / n;
+  let variance = (sum_squares / n - mean * mean).max(0.0);
+  (mean as f32, (variance / n).sqrt() as f32)
+}
+
+// A hit on `material` at the origin, facing up the y axis
+fn furnace_hit(material: &MaterialType) -> HitRecord {
+  let normal = Vec3::from((0.0, 1.0, 0.0));
+  HitRecord {
+    t: 1.0,
+    p: Vec3::new(),
+    normal,
+    geometric_normal: normal,
+    tangent: Vec3::from((1.0, 0.0, 0.0)),
+    bitangent: Vec3::from((0.0, 0.0, -1.0)),
+    material: material.clone(),
+    u: 0.0,
+    v: 0.0,
+    object_id: 0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::microfacet::*;
+  use std::f32::consts::PI;
+
+  const TEST_SAMPLES: u32 = 20_000;
+
+  // The directional albedo of each material from both sides at every angle, failing if
+  // any is above 1 by more than the noise, or below `at_least`
+  fn furnace(materials: &[(&str, MaterialType)], at_least: f32) {
+    let mut ctx = SamplerContext::with_sampler(1, SamplerType::Sobol, TEST_SAMPLES);
+    for (row, (name, material)) in materials.iter().enumerate() {
+      for &inside in [false, true].iter() {
+        for (column, angle) in ANGLES.iter().enumerate() {
+          let (albedo, error) = directional_albedo(material, angle.to_radians(), inside, &mut ctx,
+                                                   (2 * row + inside as usize) as u32, column as u32,
+                                                   TEST_SAMPLES);
+          let side = if inside { "inside" } else { "outside" };
+          assert!(albedo <= 1.0 + 4.0 * error + 1e-4, "{} from {} at {}° keeps {}", name, side, angle, albedo);
+          assert!(albedo >= at_least, "{} from {} at {}° only keeps {}", name, side, angle, albedo);
+        }
+      }
+    }
+  }
+
+  fn white() -> MaterialType {
+    MaterialType::Lambertian(Lambertian::from((1.0, 1.0, 1.0)))
+  }
+
+  #[test]
+  fn lambertian_keeps_all_the_light() {
+    furnace(&[("white", white())], 0.999);
+    furnace(&[("gray", MaterialType::Lambertian(Lambertian::from((0.5, 0.5, 0.5))))], 0.0);
+  }
+
+  #[test]
+  fn conductor_never_gains_energy() {
+    // An index with a huge imaginary part reflects everything
+    let perfect = |roughness: f32, anisotropy: f32| MaterialType::Conductor(Conductor {
+      eta: Vec3::from([1.0; 3]),
+      k: Vec3::from([1000.0; 3]),
+      distribution: Ggx::from_roughness(roughness, anisotropy),
+    });
+    furnace(&[("perfect mirror", perfect(0.0, 0.0)), ("perfect rough", perfect(0.3, 0.0)),
+              ("perfect very rough", perfect(1.0, 0.0)), ("perfect anisotropic", perfect(0.5, 0.8))], 0.0);
+    let gold = ConductorSettings { preset: ConductorPreset::Gold, roughness: 0.4, anisotropy: 0.0 };
+    furnace(&[("rough gold", MaterialType::Conductor(Conductor::from(gold)))], 0.0);
+  }
+
+  #[test]
+  fn rough_dielectric_never_gains_energy() {
+    let glass = |roughness| MaterialType::RoughDielectric(RoughDielectric::from((1.5, roughness)));
+    furnace(&[("smooth glass", glass(0.0)), ("frosted glass", glass(0.3)), ("very frosted glass", glass(1.0))], 0.0);
+  }
+
+  #[test]
+  fn principled_never_gains_energy() {
+    let principled = |metallic: f32, roughness: f32, transmission: f32| MaterialType::Principled(Principled {
+      base_color: Texture::from(1.0),
+      metallic: Texture::from(metallic),
+      roughness: Texture::from(roughness),
+      transmission: Texture::from(transmission),
+      ..Principled::default()
+    });
+    furnace(&[("plastic", principled(0.0, 0.5, 0.0)), ("rough plastic", principled(0.0, 1.0, 0.0)),
+              ("shiny plastic", principled(0.0, 0.05, 0.0)), ("metal", principled(1.0, 0.3, 0.0)),
+              ("half metal", principled(0.5, 0.5, 0.0)), ("glass", principled(0.0, 0.2, 1.0))], 0.0);
+  }
+
+  #[test]
+  fn layered_never_gains_energy() {
+    furnace(&[("varnished white", MaterialType::Layered(Layered::from((white(), 1.5, 0.0)))),
+              ("rough varnished white", MaterialType::Layered(Layered::from((white(), 1.5, 0.3))))], 0.0);
+  }
+
+  // What `bsdf` says `material` keeps of light from `theta` outside, and the total of its
+  // pdf, integrated in steps of even cosine & azimuth over the hemisphere
+  fn bsdf_albedo(material: &MaterialType, theta: f32) -> (f32, f32) {
+    let rec = furnace_hit(material);
+    let wo = Vec3::from((-theta.sin(), theta.cos(), 0.0));
+    let (steps_cos, steps_phi) = (256, 512);
+    let (mut albedo, mut pdf) = (0.0f64, 0.0f64);
+    for i in 0..steps_cos {
+      let cos = (i as f32 + 0.5) / steps_cos as f32;
+      let sin = (1.0 - cos * cos).sqrt();
+      for j in 0..steps_phi {
+        let phi = 2.0 * PI * (j as f32 + 0.5) / steps_phi as f32;
+        let wi = Vec3::from((sin * phi.cos(), cos, sin * phi.sin()));
+        let (f, p) = material.bsdf(&rec, wo, wi).unwrap();
+        albedo += f64::from(f.r());
+        pdf += f64::from(p);
+      }
+    }
+    let step = 2.0 * f64::from(PI) / f64::from(steps_cos * steps_phi);
+    ((albedo * step) as f32, (pdf * step) as f32)
+  }
+
+  // Sampling has to send as much light each way as the BSDF says, and the pdf can't
+  // account for more than every sample
+  fn scatter_matches_bsdf(name: &str, material: MaterialType) {
+    let mut ctx = SamplerContext::with_sampler(1, SamplerType::Sobol, TEST_SAMPLES);
+    for (column, angle) in [0.0f32, 45.0, 75.0].iter().enumerate() {
+      let theta = angle.to_radians();
+      let (sampled, error) = directional_albedo(&material, theta, false, &mut ctx, 0, column as u32, TEST_SAMPLES);
+      let (integral, pdf) = bsdf_albedo(&material, theta);
+      assert!((sampled - integral).abs() <= 4.0 * error + 0.01,
+              "{} at {}° scatter keeps {} but the BSDF {}", name, angle, sampled, integral);
+      assert!(pdf <= 1.01, "{} at {}° has a pdf totalling {}", name, angle, pdf);
+    }
+  }
+
+  #[test]
+  fn layered_scatter_matches_its_bsdf() {
+    scatter_matches_bsdf("rough varnished white", MaterialType::Layered(Layered::from((white(), 1.5, 0.5))));
+  }
+
+  #[test]
+  fn rough_conductor_scatter_matches_its_bsdf() {
+    for &(roughness, anisotropy) in [(0.3, 0.0), (0.6, 0.0), (0.5, 0.8)].iter() {
+      let gold = ConductorSettings { preset: ConductorPreset::Gold, roughness, anisotropy };
+      let name = format!("gold of roughness {} & anisotropy {}", roughness, anisotropy);
+      scatter_matches_bsdf(&name, MaterialType::Conductor(Conductor::from(gold)));
+    }
+  }
+}
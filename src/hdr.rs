@@ -0,0 +1,234 @@
+//! Hdr module reads high dynamic range images: Radiance RGBE (.hdr) and PFM
+use std::fs;
+
+use crate::vec3::*;
+
+/// Linear RGB pixels, top row first
+#[derive(Debug, Clone, PartialEq)]
+pub struct HdrImage {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<Vec3>,
+}
+
+impl HdrImage {
+  /// Read a Radiance RGBE or an RGB PFM image, told apart by their magic numbers
+  pub fn load(path: &str) -> Result<Self, String> {
+    let bytes = fs::read(path).map_err(|e| format!("can't read '{}': {}", path, e))?;
+    let image = if bytes.starts_with(b"#?") {
+      read_rgbe(&bytes)
+    } else if bytes.starts_with(b"PF") {
+      read_pfm(&bytes)
+    } else {
+      Err("not a Radiance .hdr or RGB .pfm image".to_string())
+    };
+    image.map_err(|e| format!("'{}': {}", path, e))
+  }
+}
+
+// Split off the next newline-terminated line
+fn next_line<'a>(bytes: &mut &'a [u8]) -> Result<&'a str, String> {
+  let end = bytes.iter().position(|&b| b == b'\n').ok_or("truncated header")?;
+  let line = std::str::from_utf8(&bytes[..end]).map_err(|_| "bad header")?;
+  *bytes = &bytes[end + 1..];
+  Ok(line.trim())
+}
+
+// Number of pixels in a width by height image, which mustn't be empty or too big to count
+fn pixel_count(width: u32, height: u32) -> Result<usize, String> {
+  if width == 0 || height == 0 {
+    return Err(format!("empty {}x{} image", width, height));
+  }
+  (width as usize).checked_mul(height as usize).ok_or_else(|| format!("{}x{} image is too big", width, height))
+}
+
+fn read_rgbe(mut bytes: &[u8]) -> Result<HdrImage, String> {
+  // Header lines up to a blank line, then the resolution
+  loop {
+    let line = next_line(&mut bytes)?;
+    if line.is_empty() {
+      break;
+    }
+    if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+      return Err(format!("unsupported {}", line));
+    }
+  }
+  let resolution: Vec<&str> = next_line(&mut bytes)?.split_whitespace().collect();
+  let (height, width) = match resolution.as_slice() {
+    ["-Y", h, "+X", w] => (h.parse::<u32>().map_err(|_| "bad height")?, w.parse::<u32>().map_err(|_| "bad width")?),
+    _ => return Err("only -Y H +X W orientation is supported".to_string()),
+  };
+
+  let count = pixel_count(width, height)?;
+  // Even run length encoded, a scanline takes a couple of bytes per run of up to 127 pixels
+  // in each component, so a header promising more than the data could hold is truncated
+  let run_length = (8..0x8000).contains(&width);
+  let smallest_scanline = if run_length { 4 + 8 * (width as usize).div_ceil(127) } else { 4 * width as usize };
+  if smallest_scanline.checked_mul(height as usize).is_none_or(|size| size > bytes.len()) {
+    return Err("truncated pixel data".to_string());
+  }
+  let mut pixels = Vec::with_capacity(count);
+  let mut scanline = vec![[0u8; 4]; width as usize];
+  let mut data = bytes.iter().copied();
+  let mut next = || data.next().ok_or_else(|| "truncated pixel data".to_string());
+  for _ in 0..height {
+    let start = [next()?, next()?, next()?, next()?];
+    if run_length && start[0] == 2 && start[1] == 2 && start[2] & 0x80 == 0 {
+      if (u32::from(start[2]) << 8 | u32::from(start[3])) != width {
+        return Err("scanline width doesn't match the image".to_string());
+      }
+      // Each component is run length encoded separately
+      for c in 0..4 {
+        let mut x = 0;
+        while x < width as usize {
+          let count = next()? as usize;
+          if count > 128 {
+            let value = next()?;
+            for pixel in scanline.iter_mut().skip(x).take(count - 128) {
+              pixel[c] = value;
+            }
+            x += count - 128;
+          } else {
+            for pixel in scanline.iter_mut().skip(x).take(count) {
+              pixel[c] = next()?;
+            }
+            x += count;
+          }
+          if count == 0 || x > width as usize {
+            return Err("bad run length".to_string());
+          }
+        }
+      }
+    } else {
+      // Flat scanline, which started with its first pixel
+      scanline[0] = start;
+      for pixel in scanline.iter_mut().skip(1) {
+        *pixel = [next()?, next()?, next()?, next()?];
+      }
+    }
+    pixels.extend(scanline.iter().map(|&[r, g, b, e]| {
+      if e == 0 {
+        Vec3::new()
+      } else {
+        let scale = 2f32.powi(i32::from(e) - 136);
+        Vec3::from((f32::from(r) * scale, f32::from(g) * scale, f32::from(b) * scale))
+      }
+    }));
+  }
+
+  Ok(HdrImage {
+    width,
+    height,
+    pixels,
+  })
+}
+
+fn read_pfm(mut bytes: &[u8]) -> Result<HdrImage, String> {
+  if next_line(&mut bytes)? != "PF" {
+    return Err("only RGB PFMs are supported".to_string());
+  }
+  let size: Vec<u32> = next_line(&mut bytes)?.split_whitespace()
+    .map(|v| v.parse().map_err(|_| "bad size".to_string()))
+    .collect::<Result<_, _>>()?;
+  let (width, height) = match size.as_slice() {
+    [w, h] => (*w, *h),
+    _ => return Err("bad size".to_string()),
+  };
+  let scale: f32 = next_line(&mut bytes)?.parse().map_err(|_| "bad scale")?;
+  let count = pixel_count(width, height)?.checked_mul(3).ok_or_else(|| format!("{}x{} image is too big", width, height))?;
+  if count.checked_mul(4).is_none_or(|size| bytes.len() < size) {
+    return Err("truncated pixel data".to_string());
+  }
+  let values: Vec<f32> = bytes.chunks_exact(4).take(count).map(|b| {
+    let b = [b[0], b[1], b[2], b[3]];
+    // A negative scale means little endian
+    if scale < 0.0 { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }
+  }).collect();
+  // PFM rows run bottom to top
+  let pixels = (0..height).rev()
+    .flat_map(|y| (0..width as usize).map(move |x| (y as usize * width as usize + x) * 3))
+    .map(|i| Vec3::from((values[i], values[i + 1], values[i + 2])))
+    .collect();
+
+  Ok(HdrImage {
+    width,
+    height,
+    pixels,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rgbe(resolution: &str, data: &[u8]) -> Vec<u8> {
+    let mut bytes = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution).into_bytes();
+    bytes.extend_from_slice(data);
+    bytes
+  }
+
+  fn pfm(size: &str, scale: &str, values: &[f32], little_endian: bool) -> Vec<u8> {
+    let mut bytes = format!("PF\n{}\n{}\n", size, scale).into_bytes();
+    for v in values {
+      bytes.extend_from_slice(&if little_endian { v.to_le_bytes() } else { v.to_be_bytes() });
+    }
+    bytes
+  }
+
+  #[test]
+  fn flat_rgbe_is_read_top_row_first() {
+    // 128 * 2^(129 - 136) = 1, and an exponent of 0 is black
+    let data = [128, 0, 0, 129, 0, 128, 0, 129, 0, 0, 128, 130, 255, 255, 255, 0];
+    let image = read_rgbe(&rgbe("-Y 2 +X 2", &data)).unwrap();
+    assert_eq!((image.width, image.height), (2, 2));
+    assert_eq!(image.pixels, vec![Vec3::from((1.0, 0.0, 0.0)), Vec3::from((0.0, 1.0, 0.0)),
+                                  Vec3::from((0.0, 0.0, 2.0)), Vec3::new()]);
+  }
+
+  #[test]
+  fn run_length_encoded_rgbe_is_read() {
+    // One 10 pixel scanline: red a run of 1s, green 3 literal values then a run of 7,
+    // blue a run of 0s and the exponent a run
+    let mut data = vec![2, 2, 0, 10];
+    data.extend_from_slice(&[128 + 10, 128]);
+    data.extend_from_slice(&[3, 64, 128, 255, 128 + 7, 32]);
+    data.extend_from_slice(&[128 + 10, 0]);
+    data.extend_from_slice(&[128 + 10, 129]);
+    let image = read_rgbe(&rgbe("-Y 1 +X 10", &data)).unwrap();
+    let green = [0.5, 1.0, 255.0 / 128.0, 0.25, 0.25, 0.25, 0.25, 0.25, 0.25, 0.25];
+    let expected: Vec<Vec3> = green.iter().map(|&g| Vec3::from((1.0, g, 0.0))).collect();
+    assert_eq!(image.pixels, expected);
+  }
+
+  #[test]
+  fn pfm_honours_byte_order_and_flips_rows() {
+    // Bottom row first in the file
+    let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0];
+    let expected = vec![Vec3::from((7.0, 8.0, 9.0)), Vec3::from((10.0, 11.0, 12.0)),
+                        Vec3::from((1.0, 2.0, 3.0)), Vec3::from((4.0, 5.0, 6.0))];
+    for &(scale, little_endian) in [("-1.0", true), ("1.0", false)].iter() {
+      let image = read_pfm(&pfm("2 2", scale, &values, little_endian)).unwrap();
+      assert_eq!((image.width, image.height), (2, 2));
+      assert_eq!(image.pixels, expected, "scale {}", scale);
+    }
+  }
+
+  #[test]
+  fn truncated_images_are_rejected() {
+    let data = [128, 0, 0, 129, 0, 128, 0, 129, 0, 0, 128, 130, 255, 255, 255, 0];
+    assert!(read_rgbe(&rgbe("-Y 2 +X 2", &data[..12])).is_err());
+    assert!(read_rgbe(&rgbe("-Y 1 +X 10", &[2, 2, 0, 10, 128 + 10, 128])).is_err());
+    assert!(read_rgbe(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n").is_err());
+    assert!(read_pfm(&pfm("2 2", "-1.0", &[1.0; 11], true)).is_err());
+    assert!(read_pfm(b"PF\n2 2\n").is_err());
+  }
+
+  #[test]
+  fn empty_and_huge_sizes_are_rejected() {
+    assert!(read_pfm(&pfm("0 0", "-1.0", &[], true)).is_err());
+    assert!(read_pfm(&pfm("65536 65536", "-1.0", &[1.0; 3], true)).is_err());
+    assert!(read_pfm(&pfm("4294967295 4294967295", "-1.0", &[1.0; 3], true)).is_err());
+    assert!(read_rgbe(&rgbe("-Y 0 +X 4", &[])).is_err());
+    assert!(read_rgbe(&rgbe("-Y 65536 +X 65536", &[0; 16])).is_err());
+  }
+}
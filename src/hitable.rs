@@ -1,19 +1,40 @@
 //! Hitable module contains the Hitable trait, HitRecord, and HitableList
 use crate::ray::*;
 use crate::vec3::*;
+use std::fmt;
 use std::sync::Arc;
 use crate::material::*;
+use crate::sampler::*;
 
-pub trait Hitable {
-  fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+/// Hitables are Debug so a scene can be fingerprinted from its full description
+pub trait Hitable: fmt::Debug {
+  /// Distance along `r` to the closest hit between t_min & t_max, without the rest of the
+  /// record, so only the closest of many candidates has to be worked out in full
+  fn hit_distance(&self, r: &Ray, t_min: f32, t_max: f32, ctx: &mut SamplerContext) -> Option<f32>;
+  /// The full record of a hit `hit_distance` found at `t`
+  fn record(&self, r: &Ray, t: f32) -> HitRecord;
+
+  fn hit(&self, r: &Ray, t_min: f32, t_max: f32, ctx: &mut SamplerContext) -> Option<HitRecord> {
+    self.hit_distance(r, t_min, t_max, ctx).map(|t| self.record(r, t))
+  }
 }
 
 //#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct HitRecord {
   pub t: f32,
   pub p: Vec3,
+  /// The shading normal, which a normal map may have bent away from `geometric_normal`
   pub normal: Vec3,
+  pub geometric_normal: Vec3,
+  /// How p moves as u & v increase
+  pub tangent: Vec3,
+  pub bitangent: Vec3,
   pub material: MaterialType,
+  /// Surface coordinates of the hit, each in [0, 1]
+  pub u: f32,
+  pub v: f32,
+  /// Index of the object hit in the world's HitableList, filled in by the list
+  pub object_id: u32,
 }
 
 // impl HitRecord {
@@ -27,30 +48,35 @@ pub struct HitRecord {
 //   }
 // }
 
+#[derive(Debug)]
 pub struct HitableList {
-  pub list: Vec<Arc<Hitable + Send + Sync>>,
+  pub list: Vec<Arc<dyn Hitable + Send + Sync>>,
 }
 
 impl HitableList {
-  pub fn new(list: Vec<Arc<Hitable + Send + Sync>>) -> Self {
+  pub fn new(list: Vec<Arc<dyn Hitable + Send + Sync>>) -> Self {
     HitableList {
       list
     }
   }
 }
 
-impl Hitable for HitableList {
-  fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-    let mut rec = None;
+impl HitableList {
+  /// The closest hit between t_min & t_max, with the index of the object hit
+  pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32, ctx: &mut SamplerContext) -> Option<HitRecord> {
+    let mut closest = None;
     let mut closest_so_far = t_max;
-    for h in &self.list {
-      let temp = h.hit(&r, t_min, closest_so_far);
-      if let Some(hit_anything) = temp {
-        closest_so_far = hit_anything.t;
-        rec = Some(hit_anything);
+    for (index, h) in self.list.iter().enumerate() {
+      if let Some(t) = h.hit_distance(r, t_min, closest_so_far, ctx) {
+        closest_so_far = t;
+        closest = Some(index);
       }
     }
 
-    rec
+    closest.map(|index| {
+      let mut rec = self.list[index].record(r, closest_so_far);
+      rec.object_id = index as u32;
+      rec
+    })
   }
 }
\ No newline at end of file
@@ -0,0 +1,200 @@
+//! Light module contains the lights that are sampled directly through shadow rays
+use std::f32::consts::PI;
+
+use crate::sampler::*;
+use crate::vec3::*;
+
+/// A light outside the world's geometry. Surfaces gather its light by sampling it directly
+/// and tracing a shadow ray. All but the sun are delta lights, with no area for a scattered
+/// ray to hit.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Light {
+  /// Shines `intensity` (W/sr) equally in every direction from `position`
+  Point {
+    position: Vec3,
+    intensity: Vec3,
+  },
+  /// A point light restricted to a cone around `direction`. Full intensity inside
+  /// `cos_falloff_start`, smoothly fading to nothing at `cos_total_width`.
+  Spot {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cos_total_width: f32,
+    cos_falloff_start: f32,
+  },
+  /// Parallel light from infinitely far away, like the sun. `direction` points towards
+  /// the light and `irradiance` is what a surface facing it receives.
+  Distant {
+    direction: Vec3,
+    irradiance: Vec3,
+  },
+  /// A distant disk, the sun: every direction whose cosine to `direction` is within
+  /// `one_minus_cos_max` of 1 has `radiance`. It is sampled uniformly over the cone it
+  /// fills. One minus the cosine is kept, as a cosine this close to 1 would lose it.
+  Sun {
+    direction: Vec3,
+    radiance: Vec3,
+    one_minus_cos_max: f32,
+  },
+}
+
+/// Light arriving at a point from one light
+pub struct LightSample {
+  /// Unit vector from the point towards the light
+  pub wi: Vec3,
+  /// How far a shadow ray has to go unblocked
+  pub distance: f32,
+  /// Incident radiance times the solid angle it arrives from, i.e. the irradiance on a
+  /// surface facing the light
+  pub li: Vec3,
+}
+
+impl Light {
+  pub fn point(position: Vec3, intensity: Vec3) -> Self {
+    Light::Point { position, intensity }
+  }
+
+  /// Spot light at `position` shining towards `target`, with a cone `angle` degrees from its
+  /// axis to its edge, starting to fade `falloff_start` degrees from its axis
+  pub fn spot(position: Vec3, target: Vec3, intensity: Vec3, angle: f32, falloff_start: f32) -> Self {
+    Light::Spot {
+      position,
+      direction: (target - position).unit_vector(),
+      intensity,
+      cos_total_width: (angle * PI / 180.0).cos(),
+      cos_falloff_start: (falloff_start.min(angle) * PI / 180.0).cos(),
+    }
+  }
+
+  pub fn distant(direction: Vec3, irradiance: Vec3) -> Self {
+    Light::Distant { direction: direction.unit_vector(), irradiance }
+  }
+
+  /// A disk `angular_radius` radians across seen from `direction`
+  pub fn sun(direction: Vec3, radiance: Vec3, angular_radius: f32) -> Self {
+    let half = (0.5 * angular_radius).sin();
+    Light::Sun { direction: direction.unit_vector(), radiance, one_minus_cos_max: 2.0 * half * half }
+  }
+
+  /// The light arriving at `p`, ignoring anything in the way. Only lights with an area
+  /// draw from `ctx`.
+  pub fn sample_li(&self, p: Vec3, ctx: &mut SamplerContext) -> Option<LightSample> {
+    match *self {
+      Light::Point { position, intensity } => {
+        let to_light = position - p;
+        let distance = to_light.length();
+        // A point at the light itself has no direction to it
+        if distance <= 0.0 {
+          return None;
+        }
+        Some(LightSample { wi: to_light / distance, distance, li: intensity / (distance * distance) })
+      },
+      Light::Spot { position, direction, intensity, cos_total_width, cos_falloff_start } => {
+        let to_light = position - p;
+        let distance = to_light.length();
+        if distance <= 0.0 {
+          return None;
+        }
+        let wi = to_light / distance;
+        let cos_theta = -wi.dot(direction);
+        if cos_theta <= cos_total_width {
+          return None;
+        }
+        let falloff = if cos_theta >= cos_falloff_start {
+          1.0
+        } else {
+          let t = (cos_theta - cos_total_width) / (cos_falloff_start - cos_total_width);
+          t * t * (3.0 - 2.0 * t)
+        };
+        Some(LightSample { wi, distance, li: falloff * intensity / (distance * distance) })
+      },
+      Light::Distant { direction, irradiance } => {
+        Some(LightSample { wi: direction, distance: f32::MAX, li: irradiance })
+      },
+      Light::Sun { direction, radiance, one_minus_cos_max } => {
+        let (u1, u2) = ctx.light_2d();
+        let one_minus_cos = u1 * one_minus_cos_max;
+        let cos_theta = 1.0 - one_minus_cos;
+        let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        let (tangent, bitangent) = basis(direction);
+        let wi = cos_theta * direction + sin_theta * (phi.cos() * tangent + phi.sin() * bitangent);
+        // Radiance over the pdf, which is one over the cone's solid angle
+        let solid_angle = 2.0 * PI * one_minus_cos_max;
+        Some(LightSample { wi, distance: f32::MAX, li: solid_angle * radiance })
+      },
+    }
+  }
+
+  /// Radiance seen looking in `direction`, for lights a ray can hit by chance
+  pub fn emitted(&self, direction: Vec3) -> Vec3 {
+    match *self {
+      Light::Sun { direction: sun, radiance, one_minus_cos_max }
+        if 1.0 - direction.unit_vector().dot(sun) <= one_minus_cos_max => radiance,
+      _ => Vec3::new(),
+    }
+  }
+}
+
+/// Two unit vectors perpendicular to unit vector `n` and each other
+pub fn basis(n: Vec3) -> (Vec3, Vec3) {
+  let helper = if n.x().abs() > 0.9 { Vec3::from((0.0, 1.0, 0.0)) } else { Vec3::from((1.0, 0.0, 0.0)) };
+  let tangent = n.cross(helper).unit_vector();
+  (tangent, n.cross(tangent))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn point_light_falls_off_with_the_square_of_distance() {
+    let mut ctx = SamplerContext::new(1);
+    let light = Light::point(Vec3::from((0.0, 4.0, 0.0)), Vec3::from([8.0; 3]));
+    let sample = light.sample_li(Vec3::from((0.0, 2.0, 0.0)), &mut ctx).unwrap();
+    assert_eq!((sample.wi, sample.distance, sample.li), (Vec3::from((0.0, 1.0, 0.0)), 2.0, Vec3::from([2.0; 3])));
+    assert!(light.sample_li(Vec3::from((0.0, 4.0, 0.0)), &mut ctx).is_none());
+  }
+
+  #[test]
+  fn spot_light_fades_between_its_falloff_start_and_edge() {
+    let mut ctx = SamplerContext::new(1);
+    let spot = Light::spot(Vec3::new(), Vec3::from((0.0, -1.0, 0.0)), Vec3::from([4.0; 3]), 30.0, 20.0);
+    // Lit at `angle` degrees off the axis, 2 away
+    let mut falloff = |angle: f32| {
+      let angle = angle.to_radians();
+      let p = 2.0 * Vec3::from((angle.sin(), -angle.cos(), 0.0));
+      spot.sample_li(p, &mut ctx).map_or(0.0, |sample| sample.li.r())
+    };
+    assert!((falloff(0.0) - 1.0).abs() < 1e-5);
+    assert!((falloff(19.9) - 1.0).abs() < 1e-5);
+    assert_eq!(falloff(30.1), 0.0);
+    assert!(falloff(29.9) < 1e-3);
+    // Halfway between the cosines, smoothstep is at a half
+    let halfway = ((20f32.to_radians().cos() + 30f32.to_radians().cos()) / 2.0).acos().to_degrees();
+    assert!((falloff(halfway) - 0.5).abs() < 1e-3, "{}", falloff(halfway));
+    assert!(spot.sample_li(Vec3::new(), &mut ctx).is_none());
+  }
+
+  #[test]
+  fn sun_gives_its_radiance_times_the_cone_it_fills() {
+    let mut ctx = SamplerContext::new(1);
+    let direction = Vec3::from((0.3, 0.8, -0.5)).unit_vector();
+    // The real sun's size, and a bigger one
+    for &radius in [0.004_654f32, 0.05].iter() {
+      let sun = Light::sun(direction, Vec3::from((2.0, 1.0, 0.5)), radius);
+      let solid_angle = 2.0 * std::f64::consts::PI * (1.0 - f64::from(radius).cos());
+      for s in 0..64 {
+        ctx.start_pixel_sample(0, 0, s);
+        let sample = sun.sample_li(Vec3::new(), &mut ctx).unwrap();
+        let expected = solid_angle * 2.0;
+        assert!((f64::from(sample.li.r()) - expected).abs() < 1e-4 * expected, "{} for {}", sample.li.r(), expected);
+        let angle = f64::from(sample.wi.dot(direction)).min(1.0).acos();
+        assert!(angle <= f64::from(radius) * 1.001, "{} is outside the {} cone", angle, radius);
+        assert_eq!(sun.emitted(sample.wi), Vec3::from((2.0, 1.0, 0.5)));
+      }
+      assert_eq!(sun.emitted(-direction), Vec3::new());
+    }
+  }
+}
@@ -0,0 +1,226 @@
+//! Low discrepancy module contains the sequences & scrambling used by the Sampler implementations
+use std::sync::OnceLock;
+
+/// Largest f32 below 1.0, so scrambled samples never round up to 1
+pub const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// First primes, one Halton base per dimension
+pub const PRIMES: [u32; 32] = [
+  2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+  59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+/// 32 bit integer hash (lowbias32)
+pub fn hash_u32(mut x: u32) -> u32 {
+  x ^= x >> 16;
+  x = x.wrapping_mul(0x7feb_352d);
+  x ^= x >> 15;
+  x = x.wrapping_mul(0x846c_a68b);
+  x ^ (x >> 16)
+}
+
+/// Hash a pixel & dimension into a scramble seed
+pub fn hash_pixel(seed: u64, x: u32, y: u32, dimension: u32) -> u32 {
+  let s = hash_u32(seed as u32 ^ hash_u32((seed >> 32) as u32));
+  hash_u32(s ^ hash_u32(x ^ hash_u32(y ^ hash_u32(dimension))))
+}
+
+/// Map 32 random bits to an f32 in [0, 1)
+pub fn u32_to_unit(bits: u32) -> f32 {
+  ((bits >> 8) as f32 * (1.0 / (1u32 << 24) as f32)).min(ONE_MINUS_EPSILON)
+}
+
+/// Element `i` of a pseudo-random permutation of 0..len chosen by `p` (Kensler, "Correlated
+/// Multi-Jittered Sampling"). Lets us shuffle strata & digits without storing a table.
+pub fn permutation_element(mut i: u32, len: u32, p: u32) -> u32 {
+  let mut w = len - 1;
+  w |= w >> 1;
+  w |= w >> 2;
+  w |= w >> 4;
+  w |= w >> 8;
+  w |= w >> 16;
+  loop {
+    i ^= p;
+    i = i.wrapping_mul(0xe170_893d);
+    i ^= p >> 16;
+    i ^= (i & w) >> 4;
+    i ^= p >> 8;
+    i = i.wrapping_mul(0x0929_eb3f);
+    i ^= p >> 23;
+    i ^= (i & w) >> 1;
+    i = i.wrapping_mul(1 | p >> 27);
+    i = i.wrapping_mul(0x6935_fa69);
+    i ^= (i & w) >> 11;
+    i = i.wrapping_mul(0x74dc_b303);
+    i ^= (i & w) >> 2;
+    i = i.wrapping_mul(0x9e50_1cc3);
+    i ^= (i & w) >> 2;
+    i = i.wrapping_mul(0xc860_a3df);
+    i &= w;
+    i ^= i >> 5;
+    if i < len {
+      break;
+    }
+  }
+  i.wrapping_add(p) % len
+}
+
+/// Radical inverse of `a` in `base` with every digit Owen scrambled. Each digit's
+/// permutation depends on the digits before it, which is what makes it a nested scramble.
+pub fn owen_scrambled_radical_inverse(base: u32, mut a: u64, hash: u32) -> f32 {
+  let base64 = u64::from(base);
+  let inv_base = 1.0 / base as f32;
+  let mut inv_base_m = 1.0_f32;
+  let mut reversed: u64 = 0;
+  let mut position = 0;
+  // Keep going past the last non-zero digit of `a`, the scramble still has to fill those in
+  while 1.0 - (base - 1) as f32 * inv_base_m < 1.0 {
+    let next = a / base64;
+    let digit = (a - next * base64) as u32;
+    // The digits before and how many there are, since a prefix of zeros is otherwise the
+    // empty prefix again and every digit after it would be scrambled the same way
+    let digit_hash = hash_u32(hash ^ hash_u32(position) ^ reversed as u32 ^ (reversed >> 32) as u32);
+    position += 1;
+    let digit = permutation_element(digit, base, digit_hash);
+    reversed = reversed * base64 + u64::from(digit);
+    inv_base_m *= inv_base;
+    a = next;
+  }
+  ((f64::from(inv_base_m) * reversed as f64) as f32).min(ONE_MINUS_EPSILON)
+}
+
+/// First dimension of the (0,2) Sobol sequence (van der Corput in base 2), as 32 bit fixed point
+pub fn sobol_02_x(i: u32) -> u32 {
+  i.reverse_bits()
+}
+
+/// Second dimension of the (0,2) Sobol sequence, as 32 bit fixed point
+pub fn sobol_02_y(mut i: u32) -> u32 {
+  let mut r = 0;
+  let mut v = 1u32 << 31;
+  while i != 0 {
+    if i & 1 != 0 {
+      r ^= v;
+    }
+    i >>= 1;
+    v ^= v >> 1;
+  }
+  r
+}
+
+// Laine-Karras style hash that only lets each bit be influenced by the bits below it
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+  x = x.wrapping_add(seed);
+  x ^= x.wrapping_mul(0x6c50_b47c);
+  x ^= x.wrapping_mul(0xb82f_1e52);
+  x ^= x.wrapping_mul(0xc7af_e638);
+  x ^= x.wrapping_mul(0x8d22_f6e6);
+  x
+}
+
+/// Hash based nested uniform (Owen) scramble of a base 2 fixed point value (Burley 2020)
+pub fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+  laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Side length of the tiling blue noise mask
+pub const BLUE_NOISE_SIZE: usize = 64;
+
+/// A tiling 64x64 blue noise dither mask with values in [0, 1), generated once on first use
+pub fn blue_noise_mask() -> &'static [f32] {
+  static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+  MASK.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE, 1.5))
+}
+
+// Ulichney's void-and-cluster method. Ranks every cell of a size x size torus so that
+// thresholding the ranks at any level gives an evenly spread (blue noise) point set.
+fn void_and_cluster(size: usize, sigma: f32) -> Vec<f32> {
+  let n = size * size;
+  // Gaussian splat for every toroidal offset
+  let mut kernel = vec![0.0f32; n];
+  for dy in 0..size {
+    for dx in 0..size {
+      let x = dx.min(size - dx) as f32;
+      let y = dy.min(size - dy) as f32;
+      kernel[dy * size + dx] = (-(x * x + y * y) / (2.0 * sigma * sigma)).exp();
+    }
+  }
+  let splat = |energy: &mut Vec<f32>, p: usize, sign: f32| {
+    let (px, py) = (p % size, p / size);
+    for y in 0..size {
+      for x in 0..size {
+        let k = ((y + size - py) % size) * size + (x + size - px) % size;
+        energy[y * size + x] += sign * kernel[k];
+      }
+    }
+  };
+  // Tightest cluster: the set cell with the most energy. Largest void: the empty one with least.
+  let tightest = |pattern: &[bool], energy: &[f32], value: bool| {
+    (0..n).filter(|&i| pattern[i] == value)
+      .max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap()).unwrap()
+  };
+  let largest_void = |pattern: &[bool], energy: &[f32]| {
+    (0..n).filter(|&i| !pattern[i])
+      .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap()).unwrap()
+  };
+
+  // Initial binary pattern: ~10% of cells set by a fixed hash, then relaxed until stable
+  let mut pattern = vec![false; n];
+  let mut energy = vec![0.0f32; n];
+  let initial = n / 10;
+  let mut set = 0;
+  let mut h = 0;
+  while set < initial {
+    let p = (hash_u32(h) as usize) % n;
+    h += 1;
+    if !pattern[p] {
+      pattern[p] = true;
+      splat(&mut energy, p, 1.0);
+      set += 1;
+    }
+  }
+  loop {
+    let cluster = tightest(&pattern, &energy, true);
+    pattern[cluster] = false;
+    splat(&mut energy, cluster, -1.0);
+    let void = largest_void(&pattern, &energy);
+    pattern[void] = true;
+    splat(&mut energy, void, 1.0);
+    if void == cluster {
+      break;
+    }
+  }
+
+  let mut rank = vec![0usize; n];
+  // Phase 1: rank the initial points by removing tightest clusters
+  {
+    let mut pattern = pattern.clone();
+    let mut energy = energy.clone();
+    for r in (0..initial).rev() {
+      let cluster = tightest(&pattern, &energy, true);
+      pattern[cluster] = false;
+      splat(&mut energy, cluster, -1.0);
+      rank[cluster] = r;
+    }
+  }
+  // Phase 2: fill the largest voids up to half the cells
+  for r in initial..n / 2 {
+    let void = largest_void(&pattern, &energy);
+    pattern[void] = true;
+    splat(&mut energy, void, 1.0);
+    rank[void] = r;
+  }
+  // Phase 3: the empty cells are now the minority, so fill their tightest clusters
+  let mut energy = vec![0.0f32; n];
+  for p in (0..n).filter(|&p| !pattern[p]) {
+    splat(&mut energy, p, 1.0);
+  }
+  for r in n / 2..n {
+    let cluster = tightest(&pattern, &energy, false);
+    pattern[cluster] = true;
+    splat(&mut energy, cluster, -1.0);
+    rank[cluster] = r;
+  }
+
+  rank.iter().map(|&r| (r as f32 + 0.5) / n as f32).collect()
+}
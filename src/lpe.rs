@@ -0,0 +1,365 @@
+//! Lpe module tags paths with their scatter events and routes them to light path expression
+//! buffers
+use std::fmt;
+use std::str::FromStr;
+
+use crate::hitable::*;
+use crate::material::*;
+use crate::ray::*;
+use crate::vec3::*;
+
+/// Longest tag: the camera, 50 bounces, a shadow ray's diffuse event and whatever ends
+/// the path
+const MAX_EVENTS: usize = 53;
+
+/// The events along one path, one letter each:
+///
+/// * `C` the camera, always first
+/// * `D` diffuse reflection off a Lambertian
+/// * `G` glossy reflection off a fuzzy Metal, a rough Conductor or frosted glass
+/// * `S` specular reflection off a smooth Metal or Conductor, or a Dielectric
+/// * `T` transmission through a Dielectric
+/// * `B` escaping to the background
+/// * `L` reaching one of the scene's lights, by hitting it or through a shadow ray
+#[derive(Copy, Clone)]
+pub struct PathTag {
+  events: [u8; MAX_EVENTS],
+  len: usize,
+}
+
+impl Default for PathTag {
+  fn default() -> Self {
+    let mut events = [0; MAX_EVENTS];
+    events[0] = b'C';
+    PathTag {
+      events,
+      len: 1,
+    }
+  }
+}
+
+impl PathTag {
+  pub fn push(&mut self, event: u8) {
+    if self.len < MAX_EVENTS {
+      self.events[self.len] = event;
+      self.len += 1;
+    }
+  }
+
+  /// This tag followed by `events`
+  pub fn extended(&self, events: &[u8]) -> PathTag {
+    let mut tag = *self;
+    for &event in events {
+      tag.push(event);
+    }
+    tag
+  }
+
+  pub fn events(&self) -> &[u8] {
+    &self.events[..self.len]
+  }
+}
+
+impl fmt::Debug for PathTag {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", String::from_utf8_lossy(self.events()))
+  }
+}
+
+/// The event for a ray `r_in` hitting `rec` and scattering into `scattered`
+pub fn scatter_event(r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> u8 {
+  material_event(&rec.material, r_in, rec, scattered)
+}
+
+// The event for `material`, which may be one part of the material hit
+fn material_event(material: &MaterialType, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> u8 {
+  match material {
+    MaterialType::Lambertian(_) => b'D',
+    MaterialType::Metal(m) if m.fuzz > 0.0 => b'G',
+    MaterialType::Metal(_) => b'S',
+    MaterialType::Conductor(c) if c.distribution.is_smooth() => b'S',
+    MaterialType::Conductor(_) => b'G',
+    // Lights don't scatter, so this only names what was hit
+    MaterialType::DiffuseLight(_) => b'L',
+    MaterialType::Principled(p) => {
+      let side_in = r_in.direction.dot(rec.normal) > 0.0;
+      let side_out = scattered.direction.dot(rec.normal) > 0.0;
+      // Which lobe reflected the ray isn't known, so go by what the surface mostly is
+      match p.metallic.scalar(rec.u, rec.v) {
+        _ if side_in == side_out => b'T',
+        metallic if metallic < 0.5 => b'D',
+        _ => b'G',
+      }
+    },
+    MaterialType::Dielectric(_) | MaterialType::RoughDielectric(_) => {
+      // Reflection stays on the side the ray came from
+      let side_in = r_in.direction.dot(rec.normal) > 0.0;
+      let side_out = scattered.direction.dot(rec.normal) > 0.0;
+      match material {
+        _ if side_in == side_out => b'T',
+        MaterialType::RoughDielectric(d) if !d.distribution.is_smooth() => b'G',
+        _ => b'S',
+      }
+    },
+    // Which material scattered the ray isn't known either, so go by the one there's more of
+    MaterialType::Mix(m) if m.weight(rec.u, rec.v) < 0.5 => material_event(&m.a, r_in, rec, scattered),
+    MaterialType::Mix(m) => material_event(&m.b, r_in, rec, scattered),
+    // Or whether the coat reflected it, so go by the base
+    MaterialType::Layered(l) => material_event(&l.base, r_in, rec, scattered),
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Quantifier {
+  One,
+  Optional,
+  ZeroOrMore,
+  OneOrMore,
+}
+
+/// One position in an expression: the events it accepts and how many times
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+  events: Vec<u8>,
+  negated: bool,
+  quantifier: Quantifier,
+}
+
+impl Token {
+  fn accepts(&self, event: u8) -> bool {
+    self.events.contains(&event) != self.negated
+  }
+}
+
+const EVENTS: &[u8] = b"CDGSTBL";
+
+/// A named regular expression over path tags, written like `diffuse=C D .* B`.
+/// Whitespace is ignored. A letter matches that event, `.` any event, `[DG]` any of the
+/// listed events and `[^DG]` any other event; each can be followed by `*`, `+` or `?`.
+/// The whole tag has to match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightPathExpression {
+  pub name: String,
+  pub expression: String,
+  tokens: Vec<Token>,
+}
+
+impl LightPathExpression {
+  pub fn matches(&self, tag: &PathTag) -> bool {
+    match_tokens(&self.tokens, tag.events())
+  }
+
+  /// The default split into the usual lighting components. Every path that reaches the
+  /// background or a light matches exactly one of them.
+  pub fn defaults() -> Vec<LightPathExpression> {
+    [
+      "emission=C [BL]",
+      "direct_diffuse=C D [BL]",
+      "indirect_diffuse=C D .+ [BL]",
+      "specular=C [GS] .* [BL]",
+      "transmission=C T .* [BL]",
+    ].iter().map(|e| e.parse().unwrap()).collect()
+  }
+}
+
+impl FromStr for LightPathExpression {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.splitn(2, '=');
+    let (name, expression) = match (parts.next(), parts.next()) {
+      (Some(name), Some(expression)) if !name.trim().is_empty() => (name.trim(), expression),
+      _ => return Err(format!("light path expression '{}' should look like name=C D .* B", s)),
+    };
+
+    let mut tokens = Vec::new();
+    let mut chars = expression.bytes().filter(|c| !c.is_ascii_whitespace()).peekable();
+    while let Some(c) = chars.next() {
+      let (events, negated) = match c {
+        b'.' => (EVENTS.to_vec(), false),
+        b'[' => {
+          let negated = chars.peek() == Some(&b'^');
+          if negated {
+            chars.next();
+          }
+          let mut events = Vec::new();
+          loop {
+            match chars.next() {
+              Some(b']') => break,
+              Some(e) if EVENTS.contains(&e) => events.push(e),
+              _ => return Err(format!("bad event class in light path expression '{}'", expression)),
+            }
+          }
+          (events, negated)
+        },
+        e if EVENTS.contains(&e) => (vec![e], false),
+        _ => return Err(format!("unexpected '{}' in light path expression '{}'", c as char, expression)),
+      };
+      let quantifier = match chars.peek() {
+        Some(b'*') => Quantifier::ZeroOrMore,
+        Some(b'+') => Quantifier::OneOrMore,
+        Some(b'?') => Quantifier::Optional,
+        _ => Quantifier::One,
+      };
+      if quantifier != Quantifier::One {
+        chars.next();
+      }
+      tokens.push(Token { events, negated, quantifier });
+    }
+
+    Ok(LightPathExpression {
+      name: name.to_string(),
+      expression: expression.trim().to_string(),
+      tokens,
+    })
+  }
+}
+
+// Backtracking match of the whole of `events`
+fn match_tokens(tokens: &[Token], events: &[u8]) -> bool {
+  let token = match tokens.first() {
+    Some(token) => token,
+    None => return events.is_empty(),
+  };
+  let (min, max) = match token.quantifier {
+    Quantifier::One => (1, 1),
+    Quantifier::Optional => (0, 1),
+    Quantifier::ZeroOrMore => (0, usize::MAX),
+    Quantifier::OneOrMore => (1, usize::MAX),
+  };
+  // How many leading events this token could take, then try the longest first
+  let available = events.iter().take(max).take_while(|&&e| token.accepts(e)).count();
+  (min..=available).rev().any(|n| match_tokens(&tokens[1..], &events[n..]))
+}
+
+/// Index of the buffer a path goes to: the first expression it matches, or one past the
+/// end (the `other` buffer) if none do. So the buffers always add up to the beauty image.
+pub fn route(expressions: &[LightPathExpression], tag: &PathTag) -> usize {
+  expressions.iter().position(|e| e.matches(tag)).unwrap_or(expressions.len())
+}
+
+/// One sample's light, sorted into a buffer per expression plus the `other` buffer
+pub struct LpeSplit<'a> {
+  expressions: &'a [LightPathExpression],
+  pub buffers: Vec<Vec3>,
+}
+
+impl<'a> LpeSplit<'a> {
+  pub fn new(expressions: &'a [LightPathExpression]) -> Self {
+    LpeSplit {
+      expressions,
+      buffers: vec![Vec3::new(); expressions.len() + 1],
+    }
+  }
+
+  /// Empty every buffer, ready for the next sample
+  pub fn clear(&mut self) {
+    for buffer in self.buffers.iter_mut() {
+      *buffer = Vec3::new();
+    }
+  }
+
+  /// Light `c` arrived along the path `tag`
+  pub fn add(&mut self, tag: &PathTag, c: Vec3) {
+    self.buffers[route(self.expressions, tag)] += c;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tag(events: &str) -> PathTag {
+    PathTag::default().extended(&events.as_bytes()[1..])
+  }
+
+  fn expression(s: &str) -> LightPathExpression {
+    s.parse().unwrap()
+  }
+
+  // Every tag of the camera, up to `bounces` scatter events, then the background or a light
+  fn every_tag(bounces: usize) -> Vec<String> {
+    let mut middles = vec![String::new()];
+    let mut all = middles.clone();
+    for _ in 0..bounces {
+      middles = middles.iter().flat_map(|m| "DGST".chars().map(move |e| format!("{}{}", m, e))).collect();
+      all.extend(middles.iter().cloned());
+    }
+    all.iter().flat_map(|m| vec![format!("C{}B", m), format!("C{}L", m)]).collect()
+  }
+
+  #[test]
+  fn expressions_parse_into_tokens() {
+    let e = expression(" caustic = C [^D]+ D? .* [BL] ");
+    assert_eq!(e.name, "caustic");
+    assert_eq!(e.expression, "C [^D]+ D? .* [BL]");
+    let quantifiers: Vec<&Quantifier> = e.tokens.iter().map(|t| &t.quantifier).collect();
+    assert_eq!(quantifiers, vec![&Quantifier::One, &Quantifier::OneOrMore, &Quantifier::Optional,
+                                 &Quantifier::ZeroOrMore, &Quantifier::One]);
+    assert!(e.tokens[1].negated && e.tokens[1].events == b"D");
+    assert_eq!(e.tokens[3].events, EVENTS);
+    assert_eq!(e.tokens[4].events, b"BL");
+  }
+
+  #[test]
+  fn bad_expressions_are_rejected() {
+    for bad in ["C D B", "=C D B", "name=C X B", "name=C [DQ] B", "name=C [D"].iter() {
+      assert!(bad.parse::<LightPathExpression>().is_err(), "'{}' was accepted", bad);
+    }
+  }
+
+  #[test]
+  fn tokens_match_the_whole_tag() {
+    let e = |s: &str| expression(&format!("e={}", s)).tokens;
+    assert!(match_tokens(&e("C D B"), b"CDB"));
+    assert!(!match_tokens(&e("C D B"), b"CDDB"));
+    assert!(!match_tokens(&e("C D"), b"CDB"));
+    assert!(match_tokens(&e("C D* B"), b"CB"));
+    assert!(match_tokens(&e("C D* B"), b"CDDDB"));
+    assert!(!match_tokens(&e("C D+ B"), b"CB"));
+    assert!(match_tokens(&e("C S? D B"), b"CSDB"));
+    assert!(match_tokens(&e("C S? D B"), b"CDB"));
+    assert!(!match_tokens(&e("C S? D B"), b"CSSDB"));
+    // Backtracking: .* has to give back the D the next token needs
+    assert!(match_tokens(&e("C .* D [BL]"), b"CSTDL"));
+    assert!(match_tokens(&e("C [^D]+ [BL]"), b"CSGTB"));
+    assert!(!match_tokens(&e("C [^D]+ [BL]"), b"CSDB"));
+    assert!(match_tokens(&[], b""));
+    assert!(!match_tokens(&[], b"C"));
+  }
+
+  #[test]
+  fn default_expressions_split_every_path_exactly_once() {
+    let defaults = LightPathExpression::defaults();
+    for events in every_tag(4) {
+      let matching: Vec<&str> = defaults.iter().filter(|e| e.matches(&tag(&events))).map(|e| e.name.as_str()).collect();
+      assert_eq!(matching.len(), 1, "{} matches {:?}", events, matching);
+    }
+    assert_eq!(defaults[route(&defaults, &tag("CL"))].name, "emission");
+    assert_eq!(defaults[route(&defaults, &tag("CDL"))].name, "direct_diffuse");
+    assert_eq!(defaults[route(&defaults, &tag("CDSB"))].name, "indirect_diffuse");
+    assert_eq!(defaults[route(&defaults, &tag("CGDB"))].name, "specular");
+    assert_eq!(defaults[route(&defaults, &tag("CTTB"))].name, "transmission");
+  }
+
+  #[test]
+  fn split_routes_light_to_the_first_match_or_other() {
+    let expressions = vec![expression("direct=C D L"), expression("any_diffuse=C D .*")];
+    let mut split = LpeSplit::new(&expressions);
+    split.add(&tag("CDL"), Vec3::from([1.0; 3]));
+    split.add(&tag("CDDB"), Vec3::from([2.0; 3]));
+    split.add(&tag("CSB"), Vec3::from([4.0; 3]));
+    assert_eq!(split.buffers, vec![Vec3::from([1.0; 3]), Vec3::from([2.0; 3]), Vec3::from([4.0; 3])]);
+    split.clear();
+    assert!(split.buffers.iter().all(|&b| b == Vec3::new()));
+  }
+
+  #[test]
+  fn tags_stop_growing_at_the_longest_path() {
+    let mut tag = PathTag::default();
+    for _ in 0..2 * MAX_EVENTS {
+      tag.push(b'D');
+    }
+    assert_eq!(tag.events().len(), MAX_EVENTS);
+    assert_eq!(format!("{:?}", PathTag::default().extended(b"DSB")), "CDSB");
+  }
+}
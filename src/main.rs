@@ -1,6 +1,7 @@
-use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, IsTerminal, Write};
 use std::sync::Arc;
-use rand::{thread_rng, Rng};
+use std::time::Instant;
 
 pub mod vec3;
 pub mod ray;
@@ -8,178 +9,346 @@ pub mod sphere;
 pub mod hitable;
 pub mod camera;
 pub mod material;
+pub mod lowdiscrepancy;
+pub mod sampler;
+pub mod filter;
+pub mod film;
+pub mod scene;
+pub mod tile;
+pub mod render;
+pub mod bench;
+pub mod furnace;
+pub mod debug;
+pub mod checkpoint;
+pub mod progress;
+pub mod exr;
+pub mod aov;
+pub mod denoise;
+pub mod lpe;
+pub mod light;
+pub mod sky;
+pub mod distribution;
+pub mod hdr;
+pub mod envmap;
+pub mod manylights;
+pub mod microfacet;
+pub mod texture;
+pub mod normalmap;
+pub mod spectrum;
+pub mod cryptomatte;
+pub mod stats;
+pub mod settings;
 
 use vec3::*;
-use ray::*;
-use sphere::*;
-use hitable::*;
-use camera::*;
-use material::*;
-
-// 2560 x 1440 on 6 cores, in release, is ~14minutes
-//const RES_X: u32 = 2560;
-//const RES_Y: u32 = 1440;
-
-// 1000 x 500 on 6 cores, in release, is ~1min 10s
-const RES_X: u32 = 1000;
-const RES_Y: u32 = 500;
-
-const NUM_SAMPLES: u32 = 10;
-// Original color from book:
-const BG_COLOR: Vec3 = Vec3 {
-  e: [0.5, 0.7, 1.0]
-};
-
-// const BG_COLOR: Vec3 = Vec3 {
-//   e: [0.8, 0.6, 0.7]
-// };
-
-fn color<T: Hitable + Send + Sync>(r: &Ray, world: &Arc<T>, depth: i32) -> Vec3 {
-  // Do ray cast in world, and calculate color at location it hits based on what & where it hits. 
-  if let Some(rec) = world.hit(r, 0.001, std::f32::MAX) {
-    let mut attenuation = Vec3::new();
-    if let Some(scatter) = rec.material.scatter(&r, &rec, &mut attenuation) {
-      if depth < 50 {
-        return attenuation * color(&scatter, world, depth + 1);
-      }
+use film::*;
+use checkpoint::*;
+use progress::*;
+use exr::*;
+use aov::*;
+use denoise::*;
+use cryptomatte::*;
+use stats::*;
+use tile::*;
+use sky::*;
+use envmap::*;
+use manylights::*;
+use spectrum::*;
+use scene::*;
+use render::*;
+use bench::*;
+use furnace::*;
+use debug::*;
+use settings::*;
+
+fn main() {
+  let settings = match RenderSettings::from_env() {
+    Ok(settings) => settings,
+    Err(e) => {
+      eprintln!("error: {}", e);
+      std::process::exit(1);
     }
-    // else
-    Vec3::new()
-  } else {
-    let unit_direction = r.direction.unit_vector();
-    let t = 0.5 * (unit_direction.y() + 1.0);
-    // This is an oddly crafted port of C++ code that returns the same result.
-    (1.0 - t) * Vec3::from([1.0; 3]) + t * Vec3::from(BG_COLOR)
+  };
+
+  if settings.furnace {
+    white_furnace(&settings);
+    return;
   }
-}
 
-fn generate_ppm(world: &Arc<HitableList>, cam: &Camera) {
-  // O(n^2) for looping
-  for j in (0..RES_Y).rev() {
-    for i in 0..RES_X {
-      let mut col = Vec3::from([0.0; 3]);
-      
-      // Anti-aliasing. ns is number of samples to take around each u,v point
-      for _ in 0..NUM_SAMPLES {
-        let u: f32 = ((i as f32) + rand::random::<f32>()) / (RES_X as f32);
-        let v: f32 = ((j as f32) + rand::random::<f32>()) / (RES_Y as f32);
-        let r = cam.get_ray(u, v);
-        
-        // WTF is this?????
-        //let p = r.point_at_parameter(2.0);
-
-        col += color(&r, &world, 0);
+  let start = Instant::now();
+  let mut scene = Scene::random_spheres(settings.seed, (settings.width as f32) / (settings.height as f32),
+                                       &settings.scene_options());
+  scene.light_selector = LightSelector::new(settings.light_sampler, &scene.area_lights);
+  scene.background = settings.background;
+  if settings.spectral {
+    scene.spectra = Some(RgbSpectra::default());
+  }
+  scene.lights = settings.lights.clone();
+  if let Some(sky_settings) = settings.sky {
+    let sky = Sky::from(sky_settings);
+    scene.lights.push(sky.sun());
+    scene.sky = Some(sky);
+  }
+  if let Some(path) = &settings.env_map {
+    match EnvironmentMap::load(path, settings.env_intensity, settings.env_rotation) {
+      Ok(environment) => scene.environment = Some(environment),
+      Err(e) => {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
       }
-      col /= NUM_SAMPLES as f32;
+    }
+  }
+  let mut phases = PhaseTimes { scene_build: start.elapsed(), ..PhaseTimes::default() };
 
-      // halve the color (gamma 2) by sqrt'ing each value
-      col = Vec3::from((col.r().sqrt(), col.g().sqrt(), col.b().sqrt()));
+  if settings.bench {
+    benchmark(&scene, &settings);
+    return;
+  }
+  if let Some((x, y)) = settings.debug_pixel {
+    let stdout = io::stdout();
+    if let Err(e) = debug_pixel(&mut stdout.lock(), &scene, &settings, x, y) {
+      eprintln!("error: {}", e);
+      std::process::exit(1);
+    }
+    return;
+  }
 
-      let ir = (255.99 * col[0]) as i32;
-      let ig = (255.99 * col[1]) as i32;
-      let ib = (255.99 * col[2]) as i32;
-      
-      // TODO: For this to work in parallel, we can't print from inside this loop:
-      println!("{} {} {}", ir, ig, ib);
+  // Ctrl-C stops the render at the next tile, and whatever it got through is still written
+  let cancel = CancelToken::new();
+  if let Err(e) = cancel_on_ctrl_c(&cancel) {
+    eprintln!("warning: {}, so Ctrl-C will stop the render without writing anything", e);
+  }
+  let reporter: Option<Arc<dyn ProgressReporter>> = if settings.show_progress && io::stderr().is_terminal() {
+    Some(Arc::new(TerminalProgress))
+  } else {
+    None
+  };
+  let progress = Progress::new(reporter, cancel);
+  let start = Instant::now();
+
+  // Checkpointed renders run as progressive passes, which take exactly the same samples
+  let film = if settings.progressive || settings.checkpoint.is_some() {
+    match progressive(&scene, &settings, &progress) {
+      Ok(film) => film,
+      Err(e) => {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+      }
     }
+  } else if settings.adaptive {
+    render_adaptive(&scene, &settings, &progress)
+  } else {
+    render_parallel(&scene, &settings, settings.sampler, settings.spp, &progress)
+  };
+  progress.finish();
+  // A render stopped part way only writes the tiles it finished
+  let (film, settings) = if progress.is_cancelled() {
+    match finished_part(film, &settings) {
+      Some(finished) => finished,
+      None => {
+        eprintln!("Cancelled before any tile finished, nothing to write");
+        std::process::exit(1);
+      }
+    }
+  } else {
+    (film, settings)
+  };
+  // The denoiser is guided by the AOVs, so they're rendered whether or not they're written
+  let aovs = if settings.aovs.is_empty() && settings.denoise.is_none() {
+    None
+  } else {
+    Some(render_aovs(&scene, &settings))
+  };
+  let denoised = match (&settings.denoise, &aovs) {
+    (Some(denoise_settings), Some(aovs)) => Some(denoise(&film, aovs, denoise_settings)),
+    _ => None,
+  };
+  let mattes: Vec<Cryptomatte> = match settings.cryptomatte {
+    Some(_) => render_cryptomattes(&scene, &settings),
+    None => Vec::new(),
+  };
+  phases.render = start.elapsed();
+
+  let start = Instant::now();
+  if let Err(e) = write_outputs(&film, denoised, aovs.as_ref(), &mattes, &settings) {
+    eprintln!("error: failed to write image: {}", e);
+    std::process::exit(1);
+  }
+  phases.output = start.elapsed();
+
+  let report = RenderReport {
+    width: settings.width,
+    height: settings.height,
+    spp: film.min_samples(),
+    threads: rayon::current_num_threads(),
+    stats: progress.stats(),
+    phases,
+  };
+  if let Err(e) = write_stats(&report, &settings) {
+    eprintln!("error: failed to write statistics: {}", e);
+    std::process::exit(1);
   }
 }
 
-fn generate_ppm_parallel(world: &Arc<HitableList>, cam: &Camera) {
-  // Use a parallel iterator to pre-calculate each pixel.
-  
-  // Allocate all the pixels on heap w/ Vec
-  let pixel_count = RES_X * RES_Y;
-  let mut pixels = Vec::with_capacity(pixel_count as usize);
-  // Fill it so we can mutate it w/ par_iter
-  for _ in 0..pixel_count {
-    pixels.push((0, 0, 0));
-  }
-
-  // Enumerate the par_iter so we get an index w/ each iteration
-  pixels.par_iter_mut().enumerate().for_each(|(index, pxl)| {
-    let row = RES_Y - (index as u32 / RES_X);
-    let col = index as u32 % RES_X;
-
-    //let mut clr = Vec3::from([0.0; 3]);
-      
-    // Anti-aliasing by generating a bunch of random points and averaging (sum / NUM_SAMPLES)
-    let mut pixel: Vec3 = (0..NUM_SAMPLES).into_par_iter().map(|_| {
-      let u: f32 = ((col as f32) + rand::random::<f32>()) / (RES_X as f32);
-      let v: f32 = ((row as f32) + rand::random::<f32>()) / (RES_Y as f32);
-      let r = cam.get_ray(u, v);
-      color(&r, &world, 0)
-    }).sum();
-    pixel /= NUM_SAMPLES as f32;
-
-    // halve the color (gamma 2) by sqrt'ing each value
-    pixel = Vec3::from((pixel.r().sqrt(), pixel.g().sqrt(), pixel.b().sqrt()));
-
-    // Set the pixel color on the 
-    *pxl = ((255.99 * pixel[0]) as i32,
-          (255.99 * pixel[1]) as i32,
-          (255.99 * pixel[2]) as i32);
+/// The film & settings of a cancelled render cropped to the largest rectangle of tiles it
+/// finished, so no unrendered pixels are written. None if it didn't finish any.
+fn finished_part(film: Film, settings: &RenderSettings) -> Option<(Film, RenderSettings)> {
+  let (region, done, total) = finished_region(&film, settings.tile_size);
+  eprintln!("Cancelled with {} of {} tiles rendered", done, total);
+  let region = region?;
+  if region == film.crop {
+    return Some((film, settings.clone()));
+  }
+  eprintln!("Writing the {}x{} pixels from ({}, {}) they fully cover",
+            region.width(), region.height(), region.x0, region.y0);
+  let settings = RenderSettings {
+    crop: Some(CropWindow::Pixels(region.x0, region.y0, region.x1, region.y1)),
+    crop_full_frame: false,
+    ..settings.clone()
+  };
+  Some((film.with_crop(region), settings))
+}
+
+/// Print the report to stderr and/or write it as JSON, as asked for in settings
+fn write_stats(report: &RenderReport, settings: &RenderSettings) -> io::Result<()> {
+  if settings.stats {
+    report.print(&mut io::stderr().lock())?;
+  }
+  if let Some(path) = &settings.stats_json {
+    fs::write(path, report.to_json())?;
+  }
+  Ok(())
+}
+
+/// Progressive render, optionally resumed from and periodically saved to a checkpoint,
+/// writing the image so far every `progress_interval` seconds. Without a progress reporter
+/// the samples per pixel are printed as often, and once more at the end.
+fn progressive(scene: &Scene, settings: &RenderSettings, progress: &Progress) -> Result<Film, String> {
+  let film = match &settings.checkpoint {
+    Some(path) if settings.resume => {
+      let checkpoint = read_checkpoint(path, settings)
+        .map_err(|e| format!("failed to read checkpoint {}: {}", path, e))?;
+      checkpoint.validate(scene, settings)?;
+      eprintln!("Resuming from {} spp", checkpoint.spp);
+      checkpoint.film
+    },
+    _ => settings.film(),
+  };
+
+  let mut last_image = Instant::now();
+  let mut last_checkpoint = Instant::now();
+  let mut last_spp = Instant::now();
+  let film = render_progressive_from(scene, settings, film, progress, |film, spp| {
+    if !progress.is_reporting() && last_spp.elapsed().as_secs_f64() >= settings.progress_interval {
+      last_spp = Instant::now();
+      eprintln!("{} spp", spp);
+    }
+    if let Some(path) = &settings.progress_image {
+      if last_image.elapsed().as_secs_f64() >= settings.progress_interval {
+        last_image = Instant::now();
+        if let Err(e) = write_ppm_file(path, &film.resolve(), settings) {
+          eprintln!("warning: failed to write {}: {}", path, e);
+        }
+      }
+    }
+    if let Some(path) = &settings.checkpoint {
+      if last_checkpoint.elapsed().as_secs_f64() >= settings.checkpoint_interval {
+        last_checkpoint = Instant::now();
+        if let Err(e) = write_checkpoint(path, scene, settings, spp, film) {
+          eprintln!("warning: failed to write checkpoint {}: {}", path, e);
+        }
+      }
+    }
   });
+  if !progress.is_reporting() {
+    eprintln!("{} spp", film.min_samples());
+  }
 
-  for p in pixels.iter() {
-    println!("{} {} {}", p.0, p.1, p.2);
+  // Always leave a final checkpoint, so a finished or cancelled render can be resumed
+  if let Some(path) = &settings.checkpoint {
+    write_checkpoint(path, scene, settings, film.min_samples(), &film)
+      .map_err(|e| format!("failed to write checkpoint {}: {}", path, e))?;
   }
+  Ok(film)
 }
 
-fn main() {
-  // PPM header
-  println!("P3\n{} {}\n255", RES_X, RES_Y);
-
-  // List of Hitable things
-  let mut list: Vec<Arc<Hitable + Send + Sync>> = Vec::new();
-  // Large sphere everything is sitting on (like EARF)
-  list.push(Arc::new(
-    Sphere::from((Vec3::from((0.0, -1000.0, 0.0)),
-                  1000.0,
-                  MaterialType::Lambertian(Lambertian::from((0.5, 0.5, 0.5)))))
-  ));
-
-  // Throw a ton more spheres in the world randomly
-  for a in -11..11 {
-    for b in -11..11 {
-      let material = rand::random::<MaterialType>();
-      let center = Vec3::from(((a as f32) + 0.9 * rand::random::<f32>(), 0.2, (b as f32) + 0.9 * rand::random::<f32>()));
-      if (center - Vec3::from((4.0, 0.2, 0.0))).length() > 0.9 {
-        list.push(Arc::new(
-          Sphere::from((center,
-                        0.2,
-                        material))
-        ));
+/// The beauty image goes to stdout, anything else to the files named in settings
+fn write_outputs(film: &Film, denoised: Option<Vec<Vec3>>, aovs: Option<&AovImage>,
+                 mattes: &[Cryptomatte], settings: &RenderSettings) -> io::Result<()> {
+  let bounds = settings.output_bounds();
+  let stdout = io::stdout();
+  let mut out = BufWriter::new(stdout.lock());
+  let beauty = crop_image(&denoised.unwrap_or_else(|| film.resolve()), film.width, &bounds);
+  write_ppm(&mut out, &beauty, bounds.width(), bounds.height())?;
+  out.flush()?;
+
+  if let (Some(aovs), Some(prefix)) = (aovs, &settings.aov_prefix) {
+    for &aov in settings.aovs.iter() {
+      let planes: Vec<Vec<f32>> = aovs.planes(aov, &bounds).into_iter().map(|data| match data {
+        ExrData::Float(values) => values,
+        ExrData::Uint(values) => values.into_iter().map(|v| v as f32).collect(),
+      }).collect();
+      let mut file = BufWriter::new(File::create(format!("{}{}.pfm", prefix, aov))?);
+      write_pfm(&mut file, &planes, bounds.width(), bounds.height())?;
+      file.flush()?;
+    }
+  }
+
+  let lpe: Vec<(String, Vec<Vec3>)> = settings.lpe_names().into_iter().enumerate()
+    .take(film.lpe.len())
+    .map(|(buffer, name)| (name, crop_image(&film.resolve_lpe(buffer), film.width, &bounds)))
+    .collect();
+  if let Some(prefix) = &settings.aov_prefix {
+    for (name, pixels) in lpe.iter() {
+      let planes: Vec<Vec<f32>> = (0..3).map(|c| pixels.iter().map(|p| p.e[c]).collect()).collect();
+      let mut file = BufWriter::new(File::create(format!("{}{}.pfm", prefix, name))?);
+      write_pfm(&mut file, &planes, bounds.width(), bounds.height())?;
+      file.flush()?;
+    }
+  }
+
+  if let Some(path) = &settings.exr {
+    let mut channels: Vec<ExrChannel> = ["R", "G", "B"].iter().enumerate()
+      .map(|(c, name)| ExrChannel::from((*name, beauty.iter().map(|p| p.e[c]).collect::<Vec<f32>>())))
+      .collect();
+    if let Some(aovs) = aovs {
+      for &aov in settings.aovs.iter() {
+        for (name, data) in aov.channels().iter().zip(aovs.planes(aov, &bounds)) {
+          channels.push(ExrChannel { name: format!("{}.{}", aov, name), data });
+        }
       }
     }
+    for (name, pixels) in lpe.iter() {
+      for (c, channel) in ["R", "G", "B"].iter().enumerate() {
+        let values: Vec<f32> = pixels.iter().map(|p| p.e[c]).collect();
+        channels.push(ExrChannel::from((format!("{}.{}", name, channel).as_str(), values)));
+      }
+    }
+    let mut metadata = Vec::new();
+    for matte in mattes.iter() {
+      channels.extend(matte.channels(settings.cryptomatte.unwrap_or(0), &bounds));
+      metadata.extend(matte.metadata());
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    write_exr(&mut file, bounds.width(), bounds.height(), channels, &metadata)?;
+    file.flush()?;
   }
 
-  list.push(Arc::new(
-    Sphere::from((Vec3::from((0.0, 1.0, 0.0)),
-                  1.0,
-                  MaterialType::Dielectric(Dielectric::from(1.5))))
-  ));
-  list.push(Arc::new(
-    Sphere::from((Vec3::from((-4.0, 1.0, 0.0)),
-                  1.0,
-                  MaterialType::Lambertian(Lambertian::from((0.4, 0.2, 0.1)))))
-  ));
-  list.push(Arc::new(
-    Sphere::from((Vec3::from((4.0, 1.0, 0.0)),
-                  1.0,
-                  MaterialType::Metal(Metal::from(((0.7, 0.6, 0.5), 0.0)))))
-  ));
-
-  let world = Arc::new(HitableList::new(list));
-  let cam: Camera = Camera::new(
-        Vec3::from((7.0, 1.5, 1.95)),
-        Vec3::from((0.0, 0.0, -1.0)),
-        Vec3::from((0.0, 1.0, 0.0)),
-        50.0,
-        (RES_X as f32) / (RES_Y as f32));
-
-  // TODO: Compare run times of these
-  //generate_ppm(&world, &cam);
-  generate_ppm_parallel(&world, &cam);
+  if let Some(path) = &settings.sample_count_image {
+    let max_spp = film.pixels.iter().map(|p| p.samples).max().unwrap_or(1);
+    write_ppm_file(path, &sample_count_image(film, max_spp), settings)?;
+  }
+  Ok(())
+}
+
+/// Write the output region of a full size image to `path` as a PPM. It's written next to
+/// it first and renamed into place, so anything watching the file never sees half an image.
+fn write_ppm_file(path: &str, pixels: &[Vec3], settings: &RenderSettings) -> io::Result<()> {
+  let bounds = settings.output_bounds();
+  let temp = format!("{}.tmp", path);
+  let mut file = BufWriter::new(File::create(&temp)?);
+  write_ppm(&mut file, &crop_image(pixels, settings.width, &bounds), bounds.width(), bounds.height())?;
+  file.flush()?;
+  drop(file);
+  fs::rename(&temp, path)
 }
                                                                                                                     
\ No newline at end of file
@@ -0,0 +1,417 @@
+//! Manylights module contains the emissive sphere lights and the structures that choose
+//! which one of them a shading point samples
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::film::*;
+use crate::light::*;
+use crate::vec3::*;
+
+/// An emissive sphere in the world, sampled as an area light
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SphereLight {
+  pub center: Vec3,
+  pub radius: f32,
+  pub radiance: Vec3,
+  /// Index of the sphere in the world's HitableList
+  pub object_id: u32,
+}
+
+/// A point on a sphere light, chosen from the cone the sphere fills
+pub struct SphereLightSample {
+  pub wi: Vec3,
+  /// Distance to the chosen point on the sphere
+  pub distance: f32,
+  /// Density over solid angle
+  pub pdf: f32,
+}
+
+impl SphereLight {
+  /// Total power given off, in luminance
+  pub fn power(&self) -> f32 {
+    luminance(self.radiance) * PI * 4.0 * PI * self.radius * self.radius
+  }
+
+  /// One minus the cosine of the half angle the sphere fills seen from `p`, or None if `p`
+  /// is inside it. Written so it stays accurate for small, distant spheres.
+  fn cone(&self, p: Vec3) -> Option<(f32, f32)> {
+    let distance = (self.center - p).length();
+    if distance <= self.radius {
+      return None;
+    }
+    let sin2_max = (self.radius / distance).powi(2);
+    let cos_max = (1.0 - sin2_max).max(0.0).sqrt();
+    Some((distance, sin2_max / (1.0 + cos_max)))
+  }
+
+  /// Choose a direction from `p` towards the sphere, uniformly over the cone it fills
+  pub fn sample(&self, p: Vec3, u: (f32, f32)) -> Option<SphereLightSample> {
+    let (distance, one_minus_cos_max) = self.cone(p)?;
+    let one_minus_cos = u.0 * one_minus_cos_max;
+    let cos_theta = 1.0 - one_minus_cos;
+    let sin2_theta = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0);
+    let phi = 2.0 * PI * u.1;
+    let w = (self.center - p) / distance;
+    let (tangent, bitangent) = basis(w);
+    let wi = cos_theta * w + sin2_theta.sqrt() * (phi.cos() * tangent + phi.sin() * bitangent);
+    // The near intersection along wi
+    let half_chord = (self.radius * self.radius - distance * distance * sin2_theta).max(0.0).sqrt();
+    Some(SphereLightSample {
+      wi,
+      distance: distance * cos_theta - half_chord,
+      pdf: 1.0 / (2.0 * PI * one_minus_cos_max),
+    })
+  }
+
+  /// Density over solid angle of `sample` from `p` choosing any direction that hits the sphere
+  pub fn pdf(&self, p: Vec3) -> f32 {
+    self.cone(p).map_or(0.0, |(_, one_minus_cos_max)| 1.0 / (2.0 * PI * one_minus_cos_max))
+  }
+}
+
+/// How a shading point chooses which sphere light to sample
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightSamplerType {
+  /// Every light equally likely
+  Uniform,
+  /// In proportion to each light's power, from an alias table
+  Power,
+  /// In proportion to each light's estimated contribution at the point, from a light BVH
+  Bvh,
+}
+
+impl FromStr for LightSamplerType {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "uniform" => Ok(LightSamplerType::Uniform),
+      "power" => Ok(LightSamplerType::Power),
+      "bvh" => Ok(LightSamplerType::Bvh),
+      _ => Err(format!("unknown light sampler '{}'", s)),
+    }
+  }
+}
+
+impl fmt::Display for LightSamplerType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      LightSamplerType::Uniform => "uniform",
+      LightSamplerType::Power => "power",
+      LightSamplerType::Bvh => "bvh",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// Walker's alias method: picks from a discrete distribution in constant time
+#[derive(Debug, Clone, PartialEq)]
+pub struct AliasTable {
+  /// Chance of keeping each bin rather than taking its alias
+  probability: Vec<f32>,
+  alias: Vec<usize>,
+  pub pmf: Vec<f32>,
+}
+
+impl AliasTable {
+  /// Weights must be non-negative. If they're all 0 every bin is equally likely.
+  pub fn new(weights: &[f32]) -> Self {
+    let n = weights.len();
+    let total: f32 = weights.iter().sum();
+    let pmf: Vec<f32> = weights.iter()
+      .map(|&w| if total > 0.0 { w / total } else { 1.0 / n as f32 })
+      .collect();
+    let mut probability: Vec<f32> = pmf.iter().map(|&p| p * n as f32).collect();
+    let mut alias: Vec<usize> = (0..n).collect();
+    let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| probability[i] < 1.0);
+    // Top up each under-full bin from an over-full one
+    while let (Some(&s), Some(&l)) = (small.last(), large.last()) {
+      small.pop();
+      alias[s] = l;
+      probability[l] -= 1.0 - probability[s];
+      if probability[l] < 1.0 {
+        large.pop();
+        small.push(l);
+      }
+    }
+    // Whatever's left is full, bar rounding
+    for i in small.into_iter().chain(large) {
+      probability[i] = 1.0;
+    }
+    AliasTable {
+      probability,
+      alias,
+      pmf,
+    }
+  }
+
+  /// Pick a bin from uniform `u`, returning it and its probability
+  pub fn sample(&self, u: f32) -> (usize, f32) {
+    let n = self.probability.len();
+    let x = u * n as f32;
+    let bin = (x as usize).min(n - 1);
+    let chosen = if x - (bin as f32) < self.probability[bin] { bin } else { self.alias[bin] };
+    (chosen, self.pmf[chosen])
+  }
+}
+
+/// A node of the light BVH: the bounds and total power of the lights under it
+#[derive(Debug, Clone, PartialEq)]
+struct LightNode {
+  min: Vec3,
+  max: Vec3,
+  power: f32,
+  /// The light, for a leaf
+  light: Option<usize>,
+  /// Index of the second child, for an interior node. The first child follows the node.
+  second: usize,
+}
+
+impl LightNode {
+  /// Rough estimate of how much light the node sends to a point `p` with normal `n`: its
+  /// power over the squared distance, times a bound on the cosine at the point
+  fn importance(&self, p: Vec3, n: Vec3) -> f32 {
+    let center = 0.5 * (self.min + self.max);
+    let radius = 0.5 * (self.max - self.min).length();
+    let to_center = center - p;
+    let distance2 = to_center.squared_length();
+    let cos_bound = if distance2 <= radius * radius {
+      1.0
+    } else {
+      let distance = distance2.sqrt();
+      let theta_n = (n.dot(to_center) / distance).clamp(-1.0, 1.0).acos();
+      let theta_max = (radius / distance).asin();
+      let theta = (theta_n - theta_max).max(0.0);
+      if theta >= PI / 2.0 { 0.0 } else { theta.cos() }
+    };
+    self.power * cos_bound / distance2.max(radius * radius).max(1e-6)
+  }
+}
+
+/// A binary tree over the lights, split at the median along the widest axis. A light is
+/// chosen by walking down from the root, picking each child in proportion to its
+/// importance at the shading point, so nearby bright lights are found far more often than
+/// distant dim ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightBvh {
+  nodes: Vec<LightNode>,
+  /// Each light's path from the root, one bit per level (set for the second child), and
+  /// its length
+  trails: Vec<(u64, u32)>,
+}
+
+impl LightBvh {
+  pub fn new(lights: &[SphereLight]) -> Self {
+    let mut bvh = LightBvh {
+      nodes: Vec::new(),
+      trails: vec![(0, 0); lights.len()],
+    };
+    let mut indices: Vec<usize> = (0..lights.len()).collect();
+    if !lights.is_empty() {
+      bvh.build(lights, &mut indices, 0, 0);
+    }
+    bvh
+  }
+
+  // Add the node over `indices` and everything under it, returning its index
+  fn build(&mut self, lights: &[SphereLight], indices: &mut [usize], trail: u64, depth: u32) -> usize {
+    let radius = |l: &SphereLight| Vec3::from([l.radius; 3]);
+    let min = indices.iter().map(|&i| lights[i].center - radius(&lights[i])).fold(Vec3::from([f32::MAX; 3]), min3);
+    let max = indices.iter().map(|&i| lights[i].center + radius(&lights[i])).fold(Vec3::from([f32::MIN; 3]), max3);
+    let power = indices.iter().map(|&i| lights[i].power()).sum();
+    let index = self.nodes.len();
+    if indices.len() == 1 {
+      self.nodes.push(LightNode { min, max, power, light: Some(indices[0]), second: 0 });
+      self.trails[indices[0]] = (trail, depth);
+      return index;
+    }
+
+    self.nodes.push(LightNode { min, max, power, light: None, second: 0 });
+    let extent = max - min;
+    let axis = (0..3).fold(0, |best, a| if extent.e[a] > extent.e[best] { a } else { best });
+    indices.sort_by(|&a, &b| lights[a].center.e[axis].partial_cmp(&lights[b].center.e[axis]).unwrap());
+    let (first, second) = indices.split_at_mut(indices.len() / 2);
+    self.build(lights, first, trail, depth + 1);
+    self.nodes[index].second = self.build(lights, second, trail | 1 << depth, depth + 1);
+    index
+  }
+
+  // Chance of taking each child of interior node `index`
+  fn split(&self, index: usize, p: Vec3, n: Vec3) -> Option<f32> {
+    let first = self.nodes[index + 1].importance(p, n);
+    let second = self.nodes[self.nodes[index].second].importance(p, n);
+    if first + second > 0.0 { Some(first / (first + second)) } else { None }
+  }
+
+  /// Pick a light for a point `p` with normal `n` from uniform `u`, returning it and its
+  /// probability. None if no light can reach the point.
+  pub fn sample(&self, p: Vec3, n: Vec3, mut u: f32) -> Option<(usize, f32)> {
+    let mut index = 0;
+    let mut pmf = 1.0;
+    loop {
+      let node = self.nodes.get(index)?;
+      if let Some(light) = node.light {
+        return Some((light, pmf));
+      }
+      let p_first = self.split(index, p, n)?;
+      if u < p_first {
+        u = (u / p_first).min(1.0 - f32::EPSILON);
+        pmf *= p_first;
+        index += 1;
+      } else {
+        u = ((u - p_first) / (1.0 - p_first)).min(1.0 - f32::EPSILON);
+        pmf *= 1.0 - p_first;
+        index = node.second;
+      }
+    }
+  }
+
+  /// Probability of `sample` choosing `light` for a point `p` with normal `n`
+  pub fn pmf(&self, p: Vec3, n: Vec3, light: usize) -> f32 {
+    let (trail, depth) = self.trails[light];
+    let mut index = 0;
+    let mut pmf = 1.0;
+    for level in 0..depth {
+      let p_first = match self.split(index, p, n) {
+        Some(p_first) => p_first,
+        None => return 0.0,
+      };
+      if trail & 1 << level == 0 {
+        pmf *= p_first;
+        index += 1;
+      } else {
+        pmf *= 1.0 - p_first;
+        index = self.nodes[index].second;
+      }
+    }
+    pmf
+  }
+}
+
+fn min3(a: Vec3, b: Vec3) -> Vec3 {
+  Vec3::from((a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z())))
+}
+
+fn max3(a: Vec3, b: Vec3) -> Vec3 {
+  Vec3::from((a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z())))
+}
+
+/// Chooses one sphere light for a shading point to sample, with a known probability
+#[derive(Debug, Clone, PartialEq)]
+pub enum LightSelector {
+  Uniform(usize),
+  Power(AliasTable),
+  Bvh(LightBvh),
+}
+
+impl LightSelector {
+  pub fn new(sampler: LightSamplerType, lights: &[SphereLight]) -> Self {
+    match sampler {
+      LightSamplerType::Uniform => LightSelector::Uniform(lights.len()),
+      LightSamplerType::Power => {
+        let powers: Vec<f32> = lights.iter().map(SphereLight::power).collect();
+        LightSelector::Power(AliasTable::new(&powers))
+      },
+      LightSamplerType::Bvh => LightSelector::Bvh(LightBvh::new(lights)),
+    }
+  }
+
+  /// Pick a light for a point `p` with normal `n` from uniform `u`, returning it and its
+  /// probability
+  pub fn sample(&self, p: Vec3, n: Vec3, u: f32) -> Option<(usize, f32)> {
+    match self {
+      LightSelector::Uniform(0) => None,
+      LightSelector::Uniform(count) => Some((((u * *count as f32) as usize).min(count - 1), 1.0 / *count as f32)),
+      LightSelector::Power(table) if table.pmf.is_empty() => None,
+      LightSelector::Power(table) => Some(table.sample(u)),
+      LightSelector::Bvh(bvh) => bvh.sample(p, n, u),
+    }
+  }
+
+  /// Probability of `sample` choosing `light` for a point `p` with normal `n`
+  pub fn pmf(&self, p: Vec3, n: Vec3, light: usize) -> f32 {
+    match self {
+      LightSelector::Uniform(count) => 1.0 / *count as f32,
+      LightSelector::Power(table) => table.pmf[light],
+      LightSelector::Bvh(bvh) => bvh.pmf(p, n, light),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DRAWS: usize = 200_000;
+
+  // A row of lights along x of growing size and brightness, and a few scattered about
+  fn lights() -> Vec<SphereLight> {
+    let mut lights: Vec<SphereLight> = (0..9).map(|i| SphereLight {
+      center: Vec3::from((2.0 * i as f32 - 8.0, 1.0, 0.0)),
+      radius: 0.1 + 0.05 * i as f32,
+      radiance: Vec3::from((1.0 + i as f32, 2.0, 0.5)),
+      object_id: i,
+    }).collect();
+    for (i, &(x, y, z)) in [(0.5, 4.0, -3.0), (-3.0, 0.3, 5.0), (6.0, 2.0, 6.0)].iter().enumerate() {
+      lights.push(SphereLight {
+        center: Vec3::from((x, y, z)),
+        radius: 0.3,
+        radiance: Vec3::from([4.0; 3]),
+        object_id: 9 + i as u32,
+      });
+    }
+    lights
+  }
+
+  // Shading points facing up, sideways and down, near and far from the lights
+  fn shading_points() -> Vec<(Vec3, Vec3)> {
+    vec![
+      (Vec3::from((0.0, 0.0, 0.0)), Vec3::from((0.0, 1.0, 0.0))),
+      (Vec3::from((-7.0, 0.5, 1.0)), Vec3::from((1.0, 0.0, 0.0))),
+      (Vec3::from((5.0, 3.0, 4.0)), Vec3::from((0.0, -1.0, 0.0))),
+      (Vec3::from((20.0, 0.2, -10.0)), Vec3::from((-0.6, 0.8, 0.0))),
+    ]
+  }
+
+  #[test]
+  fn picks_follow_the_pmf() {
+    let lights = lights();
+    for &sampler in [LightSamplerType::Uniform, LightSamplerType::Power, LightSamplerType::Bvh].iter() {
+      let selector = LightSelector::new(sampler, &lights);
+      for &(p, n) in shading_points().iter() {
+        let pmf: Vec<f32> = (0..lights.len()).map(|light| selector.pmf(p, n, light)).collect();
+        let total: f32 = pmf.iter().sum();
+        assert!((total - 1.0).abs() < 1e-4, "{} pmf at {:?} sums to {}", sampler, p, total);
+
+        let mut picks = vec![0usize; lights.len()];
+        for i in 0..DRAWS {
+          let (light, light_pmf) = selector.sample(p, n, (i as f32 + 0.5) / DRAWS as f32).unwrap();
+          assert!((light_pmf - pmf[light]).abs() <= 1e-5 * pmf[light].max(1.0),
+                  "{} picked {} at {:?} with {} but its pmf is {}", sampler, light, p, light_pmf, pmf[light]);
+          picks[light] += 1;
+        }
+        for (light, &count) in picks.iter().enumerate() {
+          let frequency = count as f32 / DRAWS as f32;
+          assert!((frequency - pmf[light]).abs() < 1e-3,
+                  "{} picks {} at {:?} {} of the time, not {}", sampler, light, p, frequency, pmf[light]);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn alias_table_pmf_matches_its_weights() {
+    let weights = [0.0, 1.0, 5.0, 0.5, 3.5, 0.0, 10.0];
+    let table = AliasTable::new(&weights);
+    assert!((table.pmf.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    let mut picks = vec![0usize; weights.len()];
+    for i in 0..DRAWS {
+      picks[table.sample((i as f32 + 0.5) / DRAWS as f32).0] += 1;
+    }
+    for (i, &count) in picks.iter().enumerate() {
+      assert!((count as f32 / DRAWS as f32 - weights[i] / 20.0).abs() < 1e-3, "bin {} picked {} times", i, count);
+    }
+    // All zero weights fall back to every bin alike
+    assert_eq!(AliasTable::new(&[0.0; 4]).pmf, vec![0.25; 4]);
+  }
+}
@@ -1,21 +1,40 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::film::*;
 use crate::hitable::*;
+use crate::microfacet::*;
 use crate::ray::*;
+use crate::sampler::*;
+use crate::spectrum::*;
 use crate::sphere::*;
+use crate::texture::*;
 use crate::vec3::*;
 use rand::Rng;
 use rand::distributions::{Distribution, Standard};
 
 // One trait to rule them all
 pub trait Material {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3) -> Option<Ray>;
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray>;
 }
 
 // Use an enum as type of material
-#[derive(Debug, Copy, Clone, PartialEq)]
+// Principled is far bigger than the rest, but boxing it would cost an allocation each time a
+// hit record takes its own copy of the material
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MaterialType {
   Dielectric(Dielectric),
   Lambertian(Lambertian),
   Metal(Metal),
+  DiffuseLight(DiffuseLight),
+  Conductor(Conductor),
+  RoughDielectric(RoughDielectric),
+  Principled(Principled),
+  Mix(Mix),
+  Layered(Layered),
 }
 
 // So we can use Material with rand::random::<Material>()
@@ -39,12 +58,105 @@ impl Distribution<MaterialType> for Standard {
 }
 
 impl Material for MaterialType {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3) -> Option<Ray> {
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
     use MaterialType::*;
+    let scattered = match self {
+      Dielectric(d) => d.scatter(r_in, rec, attenuation, ctx),
+      Lambertian(l) => l.scatter(r_in, rec, attenuation, ctx),
+      Metal(m) => m.scatter(r_in, rec, attenuation, ctx),
+      DiffuseLight(l) => l.scatter(r_in, rec, attenuation, ctx),
+      Conductor(c) => c.scatter(r_in, rec, attenuation, ctx),
+      RoughDielectric(d) => d.scatter(r_in, rec, attenuation, ctx),
+      Principled(p) => p.scatter(r_in, rec, attenuation, ctx),
+      Mix(m) => m.scatter(r_in, rec, attenuation, ctx),
+      Layered(l) => l.scatter(r_in, rec, attenuation, ctx),
+    }?;
+    // With a bent shading normal a ray can leave on one side of it but the other side of
+    // the surface itself, reflecting through the surface or refracting back out of it
+    let shading_side = scattered.direction.dot(rec.normal);
+    let geometric_side = scattered.direction.dot(rec.geometric_normal);
+    if shading_side * geometric_side < 0.0 {
+      return None;
+    }
+    Some(scattered)
+  }
+}
+
+impl MaterialType {
+  /// Small integer identifying the kind of material, for the material ID AOV
+  pub fn id(&self) -> u32 {
+    match self {
+      MaterialType::Dielectric(_) => 0,
+      MaterialType::Lambertian(_) => 1,
+      MaterialType::Metal(_) => 2,
+      MaterialType::DiffuseLight(_) => 3,
+      MaterialType::Conductor(_) => 4,
+      MaterialType::RoughDielectric(_) => 5,
+      MaterialType::Principled(_) => 6,
+      MaterialType::Mix(_) => 7,
+      MaterialType::Layered(_) => 8,
+    }
+  }
+
+  /// Base color of the surface at (u, v), ignoring lighting. Glass lets everything
+  /// through, and a conductor's color is what it reflects head on.
+  pub fn albedo(&self, u: f32, v: f32) -> Vec3 {
+    match self {
+      MaterialType::Dielectric(_) | MaterialType::RoughDielectric(_) => Vec3::from([1.0; 3]),
+      MaterialType::Lambertian(l) => l.albedo,
+      MaterialType::Metal(m) => m.albedo,
+      MaterialType::DiffuseLight(_) => Vec3::new(),
+      MaterialType::Conductor(c) => fresnel_conductor(1.0, c.eta, c.k),
+      MaterialType::Principled(p) => p.base_color.value(u, v),
+      MaterialType::Mix(m) => mix(m.a.albedo(u, v), m.b.albedo(u, v), m.weight(u, v)),
+      MaterialType::Layered(l) => l.base.albedo(u, v) * l.color,
+    }
+  }
+
+  /// Radiance the surface gives off by itself at (u, v)
+  pub fn emitted(&self, u: f32, v: f32) -> Vec3 {
     match self {
-      Dielectric(d) => d.scatter(r_in, rec, attenuation),
-      Lambertian(l) => l.scatter(r_in, rec, attenuation),
-      Metal(m) => m.scatter(r_in, rec, attenuation),
+      MaterialType::DiffuseLight(l) => l.emit,
+      MaterialType::Principled(p) => p.emission.value(u, v),
+      MaterialType::Mix(m) => mix(m.a.emitted(u, v), m.b.emitted(u, v), m.weight(u, v)),
+      MaterialType::Layered(l) => l.base.emitted(u, v) * l.color,
+      _ => Vec3::new(),
+    }
+  }
+
+  /// Whether `bsdf` covers everything the surface does at (u, v), so light sampling works
+  /// on it
+  pub fn samples_lights(&self, u: f32, v: f32) -> bool {
+    match self {
+      MaterialType::Lambertian(_) => true,
+      MaterialType::Conductor(c) => !c.distribution.is_smooth(),
+      MaterialType::Principled(p) => p.is_opaque(u, v),
+      MaterialType::Mix(m) => m.a.samples_lights(u, v) && m.b.samples_lights(u, v),
+      MaterialType::Layered(l) => l.base.samples_lights(u, v),
+      _ => false,
+    }
+  }
+
+  /// For surfaces light sampling works on, the fraction of light arriving from `wi` that's
+  /// reflected towards `wo`, times the cosine at `wi`, and the density `scatter` picks `wi`
+  /// with. None for mirrors, glass, and anything else with a lobe too sharp to evaluate.
+  /// Directions needn't be unit vectors.
+  pub fn bsdf(&self, rec: &HitRecord, wo: Vec3, wi: Vec3) -> Option<(Vec3, f32)> {
+    match self {
+      MaterialType::Lambertian(l) => {
+        let cosine = rec.normal.dot(wi.unit_vector()).max(0.0);
+        Some((l.albedo / PI * cosine, Lambertian::pdf(rec.normal, wi)))
+      },
+      MaterialType::Conductor(c) if !c.distribution.is_smooth() => Some(c.bsdf(rec, wo, wi)),
+      MaterialType::Principled(p) if p.is_opaque(rec.u, rec.v) => Some(p.bsdf(rec, wo, wi)),
+      MaterialType::Mix(m) => {
+        let (f_a, pdf_a) = m.a.bsdf(rec, wo, wi)?;
+        let (f_b, pdf_b) = m.b.bsdf(rec, wo, wi)?;
+        let weight = m.weight(rec.u, rec.v);
+        Some((mix(f_a, f_b, weight), pdf_a + weight * (pdf_b - pdf_a)))
+      },
+      MaterialType::Layered(l) => l.bsdf(rec, wo, wi),
+      _ => None,
     }
   }
 }
@@ -54,6 +166,14 @@ impl Material for MaterialType {
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Dielectric {
   pub ref_idx: f32,
+  /// Fraction of light absorbed per unit of distance travelled inside, per channel
+  pub absorption: Vec3,
+  /// An infinitely thin sheet, like a window pane or a bubble, that light goes straight
+  /// through without bending or being absorbed
+  pub thin: bool,
+  /// How the index of refraction varies with wavelength. Spectral renders bend each
+  /// wavelength by its own; RGB ones use `ref_idx`.
+  pub dispersion: Option<Dispersion>,
 }
 
 impl Dielectric {
@@ -62,49 +182,406 @@ impl Dielectric {
     r0 *= r0;
     r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
   }
+
+  /// The absorption that tints white light to `color` after `distance`
+  pub fn absorption_for(color: Vec3, distance: f32) -> Vec3 {
+    Vec3::from((-color.r().ln(), -color.g().ln(), -color.b().ln())) / distance
+  }
+
+  /// Fraction of light left after going `distance` through the inside, by Beer-Lambert
+  pub fn transmittance(&self, distance: f32) -> Vec3 {
+    transmittance(self.absorption, distance)
+  }
+}
+
+/// Light left after `distance` through a medium with `absorption`
+pub fn transmittance(absorption: Vec3, distance: f32) -> Vec3 {
+  Vec3::from(((-absorption.r() * distance).exp(), (-absorption.g() * distance).exp(),
+              (-absorption.b() * distance).exp()))
 }
 
 impl From<f32> for Dielectric {
   fn from(ref_idx: f32) -> Self {
     Self {
-      ref_idx
+      ref_idx,
+      absorption: Vec3::new(),
+      thin: false,
+      dispersion: None,
     }
   }
 }
 
 impl Material for Dielectric {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3) -> Option<Ray> {
-    let mut outward_normal: Vec3;
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    if self.thin {
+      return self.scatter_thin(r_in, rec, attenuation, ctx);
+    }
+    let ref_idx = self.path_ior(ctx);
+    let outward_normal: Vec3;
     let reflected = r_in.direction.reflect(rec.normal);
     let ni_over_nt: f32;
-    let reflect_prob: f32;
     let cosine: f32;
     *attenuation = Vec3::from((1.0, 1.0, 1.0));
     if r_in.direction.dot(rec.normal) > 0.0 {
       outward_normal = -rec.normal;
-      ni_over_nt = self.ref_idx;
-      cosine = self.ref_idx * r_in.direction.dot(rec.normal) / r_in.direction.length();
+      ni_over_nt = ref_idx;
+      cosine = ref_idx * r_in.direction.dot(rec.normal) / r_in.direction.length();
+      // The ray got here through the inside, so absorb along the way
+      *attenuation = self.transmittance(rec.t * r_in.direction.length());
     } else {
       outward_normal = rec.normal;
-      ni_over_nt = 1.0 / self.ref_idx;
+      ni_over_nt = 1.0 / ref_idx;
       cosine = -r_in.direction.dot(rec.normal) / r_in.direction.length();
     }
 
     let refracted = r_in.direction.refract(outward_normal, ni_over_nt);
-    if refracted.is_some() {
-      reflect_prob = Dielectric::schlick(cosine, self.ref_idx);
+    let reflect_prob = if refracted.is_some() {
+      Dielectric::schlick(cosine, ref_idx)
     } else {
-      reflect_prob = 1.0;
-    }
+      1.0
+    };
 
     // Result randomly chosen between reflected and refracted
-    if rand::random::<f32>() < reflect_prob {
+    if ctx.bsdf_1d() < reflect_prob {
       Some(Ray::from((rec.p, reflected)))
     } else {
       Some(Ray::from((rec.p, refracted.unwrap())))
     }
   }
 }
+
+impl Dielectric {
+  // The index of refraction for this path. A spectral path through dispersive glass takes
+  // its hero wavelength's, and leaves the other wavelengths behind, since they'd have been
+  // bent elsewhere.
+  fn path_ior(&self, ctx: &mut SamplerContext) -> f32 {
+    match (self.dispersion, ctx.wavelengths.as_mut()) {
+      (Some(dispersion), Some(wavelengths)) => {
+        wavelengths.terminate_secondary();
+        dispersion.ior(wavelengths.hero())
+      },
+      _ => self.ref_idx,
+    }
+  }
+
+  // A thin sheet bounces light back and forth between its two faces. All of it that gets
+  // out on the far side carries on in a straight line, the rest is reflected.
+  fn scatter_thin(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    *attenuation = Vec3::from((1.0, 1.0, 1.0));
+    let cosine = r_in.direction.dot(rec.normal).abs() / r_in.direction.length();
+    let r = Dielectric::schlick(cosine, self.ref_idx);
+    // R + TRT + TRRRT + ..., with T = 1 - R
+    let reflect_prob = r + (1.0 - r) * (1.0 - r) * r / (1.0 - r * r);
+    if ctx.bsdf_1d() < reflect_prob {
+      Some(Ray::from((rec.p, r_in.direction.reflect(rec.normal))))
+    } else {
+      Some(Ray::from((rec.p, r_in.direction)))
+    }
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// ROUGH DIELECTRIC MATERIAL
+/// Frosted glass made of GGX microfacets, each a smooth boundary that reflects or refracts
+/// by the full Fresnel equations
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RoughDielectric {
+  pub ref_idx: f32,
+  pub distribution: Ggx,
+  /// Fraction of light absorbed per unit of distance travelled inside, per channel
+  pub absorption: Vec3,
+}
+
+impl From<(f32, f32)> for RoughDielectric {
+  fn from(tuple: (f32, f32)) -> Self {
+    Self {
+      ref_idx: tuple.0,
+      distribution: Ggx::from_roughness(tuple.1, 0.0),
+      absorption: Vec3::new(),
+    }
+  }
+}
+
+impl Material for RoughDielectric {
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    // Work on the side the ray comes from, with eta the density of the side it goes into
+    let direction = r_in.direction.unit_vector();
+    let inside = direction.dot(rec.normal) > 0.0;
+    let (normal, eta) = if inside {
+      (-rec.normal, 1.0 / self.ref_idx)
+    } else {
+      (rec.normal, self.ref_idx)
+    };
+    let (tangent, bitangent) = shading_frame(normal);
+    let wo = to_local(-direction, tangent, bitangent, normal);
+    let m = self.distribution.sample_visible(wo, ctx.bsdf_2d());
+    let reflect_prob = fresnel_dielectric(wo.dot(m), eta);
+
+    // Pick a lobe by Fresnel, which cancels it out of the weight. Like Dielectric, radiance
+    // isn't scaled by eta² going through, since it's scaled back coming out.
+    let wi = if ctx.bsdf_1d() < reflect_prob {
+      let wi = 2.0 * wo.dot(m) * m - wo;
+      if wi.z() <= 0.0 {
+        return None;
+      }
+      wi
+    } else {
+      match refract_microfacet(wo, m, eta) {
+        Some(wi) if wi.z() < 0.0 => wi,
+        _ => return None,
+      }
+    };
+    *attenuation = Vec3::from([self.distribution.g(wo, wi) / self.distribution.g1(wo); 3]);
+    if inside {
+      // The ray got here through the inside, so absorb along the way
+      *attenuation *= transmittance(self.absorption, rec.t * r_in.direction.length());
+    }
+    Some(Ray::from((rec.p, from_local(wi, tangent, bitangent, normal))))
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// PRINCIPLED MATERIAL
+/// Burley's Disney principled BSDF: a handful of artist friendly parameters, each a
+/// texture, that blend a diffuse base with sheen, a GGX specular layer, a clearcoat on
+/// top, rough glass and emission. There's no scene importer, so nothing maps glTF's
+/// metallic-roughness materials onto it yet.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Principled {
+  pub base_color: Texture,
+  /// 0 for a dielectric to 1 for a metal colored by `base_color`
+  pub metallic: Texture,
+  pub roughness: Texture,
+  /// Strength of a dielectric's highlight, 0.5 for a reflectance of 4%
+  pub specular: Texture,
+  /// How much a dielectric's highlight takes on the base color
+  pub specular_tint: Texture,
+  /// Soft rim of light at grazing angles, for cloth
+  pub sheen: Texture,
+  pub sheen_tint: Texture,
+  /// Strength of a second, clear specular layer
+  pub clearcoat: Texture,
+  /// 0 for a satin to 1 for a glossy clearcoat
+  pub clearcoat_gloss: Texture,
+  /// 0 for opaque to 1 for glass
+  pub transmission: Texture,
+  pub ior: Texture,
+  pub emission: Texture,
+}
+
+impl Default for Principled {
+  fn default() -> Self {
+    Principled {
+      base_color: Texture::from(0.8),
+      metallic: Texture::from(0.0),
+      roughness: Texture::from(0.5),
+      specular: Texture::from(0.5),
+      specular_tint: Texture::from(0.0),
+      sheen: Texture::from(0.0),
+      sheen_tint: Texture::from(0.5),
+      clearcoat: Texture::from(0.0),
+      clearcoat_gloss: Texture::from(1.0),
+      transmission: Texture::from(0.0),
+      ior: Texture::from(1.5),
+      emission: Texture::from(0.0),
+    }
+  }
+}
+
+/// Comma separated `name=texture` pairs, such as `base_color=0.9/0.1/0.1,metallic=1`, over
+/// the defaults
+impl FromStr for Principled {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut material = Principled::default();
+    for pair in s.split(',') {
+      let (name, value) = pair.split_once('=').ok_or_else(|| format!("'{}' isn't name=value", pair))?;
+      let texture = value.parse()?;
+      match name {
+        "base_color" => material.base_color = texture,
+        "metallic" => material.metallic = texture,
+        "roughness" => material.roughness = texture,
+        "specular" => material.specular = texture,
+        "specular_tint" => material.specular_tint = texture,
+        "sheen" => material.sheen = texture,
+        "sheen_tint" => material.sheen_tint = texture,
+        "clearcoat" => material.clearcoat = texture,
+        "clearcoat_gloss" => material.clearcoat_gloss = texture,
+        "transmission" => material.transmission = texture,
+        "ior" => material.ior = texture,
+        "emission" => material.emission = texture,
+        _ => return Err(format!("unknown principled parameter '{}'", name)),
+      }
+    }
+    Ok(material)
+  }
+}
+
+// Principled's parameters looked up at one point
+struct PrincipledPoint {
+  base_color: Vec3,
+  metallic: f32,
+  roughness: f32,
+  specular: f32,
+  specular_tint: f32,
+  sheen: f32,
+  sheen_tint: f32,
+  clearcoat: f32,
+  clearcoat_gloss: f32,
+  transmission: f32,
+  ior: f32,
+}
+
+impl Principled {
+  fn at(&self, u: f32, v: f32) -> PrincipledPoint {
+    PrincipledPoint {
+      base_color: self.base_color.value(u, v),
+      metallic: self.metallic.scalar(u, v),
+      roughness: self.roughness.scalar(u, v),
+      specular: self.specular.scalar(u, v),
+      specular_tint: self.specular_tint.scalar(u, v),
+      sheen: self.sheen.scalar(u, v),
+      sheen_tint: self.sheen_tint.scalar(u, v),
+      clearcoat: self.clearcoat.scalar(u, v),
+      clearcoat_gloss: self.clearcoat_gloss.scalar(u, v),
+      transmission: self.transmission.scalar(u, v),
+      ior: self.ior.scalar(u, v),
+    }
+  }
+
+  /// Whether no light goes through the surface at (u, v), so `bsdf` covers all of it
+  pub fn is_opaque(&self, u: f32, v: f32) -> bool {
+    let point = self.at(u, v);
+    (1.0 - point.metallic) * point.transmission <= 0.0
+  }
+
+  /// The reflective lobes' BSDF times cosine and pdf, between world directions
+  pub fn bsdf(&self, rec: &HitRecord, wo: Vec3, wi: Vec3) -> (Vec3, f32) {
+    let (tangent, bitangent) = shading_frame(rec.normal);
+    let wo = to_local(wo.unit_vector(), tangent, bitangent, rec.normal);
+    let wi = to_local(wi.unit_vector(), tangent, bitangent, rec.normal);
+    self.at(rec.u, rec.v).reflection(wo, wi)
+  }
+}
+
+impl PrincipledPoint {
+  // Chances of sampling the diffuse, specular and clearcoat lobes
+  fn lobe_probabilities(&self) -> [f32; 3] {
+    let weights = [1.0 - self.metallic, 1.0, 0.25 * self.clearcoat];
+    let total: f32 = weights.iter().sum();
+    [weights[0] / total, weights[1] / total, weights[2] / total]
+  }
+
+  fn specular_distribution(&self) -> Ggx {
+    Ggx::from_roughness(self.roughness, 0.0)
+  }
+
+  fn clearcoat_alpha(&self) -> f32 {
+    0.1 + (0.001 - 0.1) * self.clearcoat_gloss
+  }
+
+  // The base color's hue and saturation at a luminance of 1
+  fn tint(&self) -> Vec3 {
+    let l = luminance(self.base_color);
+    if l > 0.0 { self.base_color / l } else { Vec3::from([1.0; 3]) }
+  }
+
+  // BSDF times cosine and pdf of the reflective lobes, in the local frame
+  fn reflection(&self, wo: Vec3, wi: Vec3) -> (Vec3, f32) {
+    if wo.z() <= 0.0 || wi.z() <= 0.0 {
+      return (Vec3::new(), 0.0);
+    }
+    let white = Vec3::from([1.0; 3]);
+    let tint = self.tint();
+    let h = (wo + wi).unit_vector();
+    let cos_d = wi.dot(h);
+    let (fl, fv, fd) = (schlick_weight(wi.z()), schlick_weight(wo.z()), schlick_weight(cos_d));
+
+    // Diffuse, brighter at grazing angles when rough, plus sheen
+    let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+    let diffuse = self.base_color / PI * ((1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv));
+    let sheen = self.sheen * fd * mix(white, tint, self.sheen_tint);
+    // Specular: a dielectric's highlight is mostly white, a metal's the base color
+    let specular0 = mix(self.specular * 0.08 * mix(white, tint, self.specular_tint), self.base_color, self.metallic);
+    // Only what the highlight doesn't reflect reaches the base beneath it
+    let through = white - (specular0 + fv * (white - specular0));
+    let mut f = (1.0 - self.metallic) * through * (diffuse + sheen);
+    let ggx = self.specular_distribution();
+    f += ggx.d(h) * ggx.g(wo, wi) / (4.0 * wi.z() * wo.z()) * (specular0 + fd * (white - specular0));
+    // Clearcoat, with a fixed index of refraction of 1.5 and masking roughness
+    let d_clearcoat = gtr1(h.z(), self.clearcoat_alpha());
+    if self.clearcoat > 0.0 {
+      let coat = Ggx { alpha_x: 0.25, alpha_y: 0.25 };
+      let clearcoat = 0.25 * self.clearcoat * d_clearcoat * (0.04 + 0.96 * fd) * coat.g1(wo) * coat.g1(wi);
+      f += Vec3::from([clearcoat / (4.0 * wi.z() * wo.z()); 3]);
+    }
+
+    let [p_diffuse, p_specular, p_clearcoat] = self.lobe_probabilities();
+    let pdf = p_diffuse * wi.z() / PI
+      + p_specular * ggx.pdf_visible(wo, h) / (4.0 * wo.dot(h))
+      + p_clearcoat * d_clearcoat * h.z() / (4.0 * wo.dot(h));
+    (f * wi.z(), pdf)
+  }
+
+  // Pick a direction from one of the reflective lobes, chosen by `u_lobe`
+  fn sample_reflection(&self, wo: Vec3, u_lobe: f32, u: (f32, f32)) -> Vec3 {
+    let [p_diffuse, p_specular, _] = self.lobe_probabilities();
+    if u_lobe < p_diffuse {
+      // Cosine weighted
+      let (r, phi) = (u.0.sqrt(), 2.0 * PI * u.1);
+      return Vec3::from((r * phi.cos(), r * phi.sin(), (1.0 - u.0).max(0.0).sqrt()));
+    }
+    let m = if u_lobe < p_diffuse + p_specular {
+      self.specular_distribution().sample_visible(wo, u)
+    } else {
+      sample_gtr1(self.clearcoat_alpha(), u)
+    };
+    2.0 * wo.dot(m) * m - wo
+  }
+}
+
+// Schlick's (1 - cos)^5 Fresnel falloff
+fn schlick_weight(cosine: f32) -> f32 {
+  (1.0 - cosine).clamp(0.0, 1.0).powi(5)
+}
+
+fn mix(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+  a + t * (b - a)
+}
+
+impl Material for Principled {
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    let point = self.at(rec.u, rec.v);
+    // The glass part, chosen in proportion to how much of the surface it is
+    if ctx.bsdf_1d() < (1.0 - point.metallic) * point.transmission {
+      let glass = RoughDielectric {
+        ref_idx: point.ior,
+        distribution: point.specular_distribution(),
+        absorption: Vec3::new(),
+      };
+      let scattered = glass.scatter(r_in, rec, attenuation, ctx)?;
+      if scattered.direction.dot(rec.normal) * r_in.direction.dot(rec.normal) > 0.0 {
+        // Tinted going in and again coming out, so by the square root each time
+        let c = point.base_color;
+        *attenuation *= Vec3::from((c.r().sqrt(), c.g().sqrt(), c.b().sqrt()));
+      }
+      return Some(scattered);
+    }
+
+    let (tangent, bitangent) = shading_frame(rec.normal);
+    let wo = to_local(-r_in.direction.unit_vector(), tangent, bitangent, rec.normal);
+    let u_lobe = ctx.bsdf_1d();
+    let wi = point.sample_reflection(wo, u_lobe, ctx.bsdf_2d());
+    let (f, pdf) = point.reflection(wo, wi);
+    if pdf <= 0.0 {
+      return None;
+    }
+    *attenuation = f / pdf;
+    Some(Ray::from((rec.p, from_local(wi, tangent, bitangent, rec.normal))))
+  }
+}
 // =================================================================================
 
 // =================================================================================
@@ -130,11 +607,22 @@ impl From<Vec3> for Lambertian {
   }
 }
 
+impl Lambertian {
+  /// Density over solid angle of `scatter` choosing `direction`: cosine weighted
+  pub fn pdf(normal: Vec3, direction: Vec3) -> f32 {
+    normal.dot(direction.unit_vector()).max(0.0) / std::f32::consts::PI
+  }
+}
+
 impl Material for Lambertian {
-  fn scatter(&self, _r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3) -> Option<Ray> {
-    let target = rec.p + rec.normal + Sphere::random_in_unit_sphere();
+  fn scatter(&self, _r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    // A point on the unit sphere touching the surface gives cosine weighted directions
+    let mut direction = rec.normal + Sphere::random_unit_vector(ctx);
+    if direction.squared_length() < 1e-8 {
+      direction = rec.normal;
+    }
     *attenuation = self.albedo;
-    Some(Ray::from((rec.p, target - rec.p)))
+    Some(Ray::from((rec.p, direction)))
   }
 }
 // =================================================================================
@@ -160,16 +648,16 @@ impl From<((f32, f32, f32), f32)> for Metal {
 impl From<(Vec3, f32)> for Metal {
   fn from(tuple: (Vec3, f32)) -> Self {
     Self {
-      albedo: Vec3::from(tuple.0),
+      albedo: tuple.0,
       fuzz: tuple.1,
     }
   }
 }
 
 impl Material for Metal {
-  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3) -> Option<Ray> {
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
     let reflected = r_in.direction.unit_vector().reflect(rec.normal);
-    let scattered = Ray::from((rec.p, reflected + self.fuzz * Sphere::random_in_unit_sphere()));
+    let scattered = Ray::from((rec.p, reflected + self.fuzz * Sphere::random_in_unit_sphere(ctx)));
     *attenuation = self.albedo;
     // Result:
     if scattered.direction.dot(rec.normal) > 0.0 {
@@ -179,4 +667,311 @@ impl Material for Metal {
     }
   }
 }
-// =================================================================================
\ No newline at end of file
+// =================================================================================
+
+// =================================================================================
+/// DIFFUSE LIGHT MATERIAL
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DiffuseLight {
+  pub emit: Vec3,
+}
+
+impl From<Vec3> for DiffuseLight {
+  fn from(emit: Vec3) -> Self {
+    Self {
+      emit
+    }
+  }
+}
+
+impl Material for DiffuseLight {
+  // Lights only give off light, they never reflect any
+  fn scatter(&self, _r_in: &Ray, _rec: &HitRecord, _attenuation: &mut Vec3, _ctx: &mut SamplerContext) -> Option<Ray> {
+    None
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// CONDUCTOR MATERIAL
+/// A rough metal made of GGX microfacets, each a perfect mirror reflecting by the Fresnel
+/// equations for its complex index of refraction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Conductor {
+  /// Real part of the index of refraction, per channel
+  pub eta: Vec3,
+  /// Absorption coefficient, the imaginary part
+  pub k: Vec3,
+  pub distribution: Ggx,
+}
+
+/// Metals with measured indices of refraction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConductorPreset {
+  Gold,
+  Silver,
+  Copper,
+  Aluminium,
+}
+
+impl ConductorPreset {
+  /// Index of refraction (eta, k) at red, green & blue wavelengths of 650, 550 & 450nm
+  pub fn ior(&self) -> (Vec3, Vec3) {
+    match self {
+      ConductorPreset::Gold => (Vec3::from((0.143, 0.374, 1.442)), Vec3::from((3.983, 2.385, 1.603))),
+      ConductorPreset::Silver => (Vec3::from((0.155, 0.117, 0.138)), Vec3::from((4.828, 3.122, 2.147))),
+      ConductorPreset::Copper => (Vec3::from((0.200, 0.924, 1.102)), Vec3::from((3.912, 2.452, 2.142))),
+      ConductorPreset::Aluminium => (Vec3::from((1.657, 0.880, 0.521)), Vec3::from((9.224, 6.270, 4.837))),
+    }
+  }
+}
+
+impl FromStr for ConductorPreset {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "gold" => Ok(ConductorPreset::Gold),
+      "silver" => Ok(ConductorPreset::Silver),
+      "copper" => Ok(ConductorPreset::Copper),
+      "aluminium" => Ok(ConductorPreset::Aluminium),
+      _ => Err(format!("unknown conductor '{}'", s)),
+    }
+  }
+}
+
+impl fmt::Display for ConductorPreset {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      ConductorPreset::Gold => "gold",
+      ConductorPreset::Silver => "silver",
+      ConductorPreset::Copper => "copper",
+      ConductorPreset::Aluminium => "aluminium",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// Which metal the big metal sphere is made of, and how rough it is
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ConductorSettings {
+  pub preset: ConductorPreset,
+  /// 0 for a mirror to 1 for very rough
+  pub roughness: f32,
+  /// 0 for round highlights to 1 for highlights stretched along the tangent
+  pub anisotropy: f32,
+}
+
+impl Default for ConductorSettings {
+  fn default() -> Self {
+    ConductorSettings {
+      preset: ConductorPreset::Gold,
+      roughness: 0.2,
+      anisotropy: 0.0,
+    }
+  }
+}
+
+impl From<ConductorSettings> for Conductor {
+  fn from(settings: ConductorSettings) -> Self {
+    let (eta, k) = settings.preset.ior();
+    Self {
+      eta,
+      k,
+      distribution: Ggx::from_roughness(settings.roughness, settings.anisotropy),
+    }
+  }
+}
+
+impl Conductor {
+  /// The rough BSDF times cosine and pdf, between world directions
+  pub fn bsdf(&self, rec: &HitRecord, wo: Vec3, wi: Vec3) -> (Vec3, f32) {
+    let (tangent, bitangent) = shading_frame(rec.normal);
+    let wo = to_local(wo.unit_vector(), tangent, bitangent, rec.normal);
+    let wi = to_local(wi.unit_vector(), tangent, bitangent, rec.normal);
+    if wo.z() <= 0.0 || wi.z() <= 0.0 {
+      return (Vec3::new(), 0.0);
+    }
+    let h = (wo + wi).unit_vector();
+    let fresnel = fresnel_conductor(wo.dot(h), self.eta, self.k);
+    let f = self.distribution.d(h) * self.distribution.g(wo, wi) / (4.0 * wo.z()) * fresnel;
+    // `scatter` picks a visible microfacet and reflects off it
+    (f, self.distribution.pdf_visible(wo, h) / (4.0 * wo.dot(h)))
+  }
+}
+
+impl Material for Conductor {
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    let (tangent, bitangent) = shading_frame(rec.normal);
+    let wo = to_local(-r_in.direction.unit_vector(), tangent, bitangent, rec.normal);
+    if wo.z() <= 0.0 {
+      return None;
+    }
+    // Reflect off a microfacet the ray can see, so no sample is wasted on hidden ones
+    let m = self.distribution.sample_visible(wo, ctx.bsdf_2d());
+    let wi = 2.0 * wo.dot(m) * m - wo;
+    if wi.z() <= 0.0 {
+      return None;
+    }
+    // The BSDF times cosine over the pdf, which leaves just Fresnel and masking
+    *attenuation = self.distribution.g(wo, wi) / self.distribution.g1(wo) * fresnel_conductor(wo.dot(m), self.eta, self.k);
+    Some(Ray::from((rec.p, from_local(wi, tangent, bitangent, rec.normal))))
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// MIX MATERIAL
+/// Two materials blended by a weight texture, each path picking one of them at random.
+/// The two are shared, so copies of the mix taken by hit records don't copy them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mix {
+  pub a: Arc<MaterialType>,
+  pub b: Arc<MaterialType>,
+  /// How much of `b` there is, from 0 for all `a` to 1 for all `b`
+  pub weight: Texture,
+}
+
+impl From<(MaterialType, MaterialType, Texture)> for Mix {
+  fn from(tuple: (MaterialType, MaterialType, Texture)) -> Self {
+    Self {
+      a: Arc::new(tuple.0),
+      b: Arc::new(tuple.1),
+      weight: tuple.2,
+    }
+  }
+}
+
+impl Mix {
+  /// How much of `b` there is at (u, v)
+  pub fn weight(&self, u: f32, v: f32) -> f32 {
+    self.weight.scalar(u, v).clamp(0.0, 1.0)
+  }
+}
+
+impl Material for Mix {
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    // Picked in proportion to how much of each there is, which cancels out of the weight
+    if ctx.bsdf_1d() < self.weight(rec.u, rec.v) {
+      self.b.scatter(r_in, rec, attenuation, ctx)
+    } else {
+      self.a.scatter(r_in, rec, attenuation, ctx)
+    }
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// LAYERED MATERIAL
+/// A clear dielectric coat, like varnish or lacquer, over any base material. The coat
+/// reflects by Fresnel off GGX microfacets. What gets through is tinted by the coat on the
+/// way down and back up, and partly reflected back down again on its way out. Rather than
+/// follow that light between the layers, what the base sends out is scaled up by what it
+/// would add, taking the base's albedo as how much each bounce off it keeps. Directions
+/// aren't bent going through the coat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layered {
+  pub base: Arc<MaterialType>,
+  pub ior: f32,
+  pub distribution: Ggx,
+  /// Color white light comes out after going straight down through the coat and back up
+  pub color: Vec3,
+}
+
+// This from is for a base, index of refraction & roughness
+impl From<(MaterialType, f32, f32)> for Layered {
+  fn from(tuple: (MaterialType, f32, f32)) -> Self {
+    Self {
+      base: Arc::new(tuple.0),
+      ior: tuple.1,
+      distribution: Ggx::from_roughness(tuple.2, 0.0),
+      color: Vec3::from([1.0; 3]),
+    }
+  }
+}
+
+impl Layered {
+  /// The coat and base's BSDF times cosine and pdf, between world directions. Light gets
+  /// into the base by Fresnel at the macro surface, an approximation to going through
+  /// whichever microfacet it meets. None if the base's BSDF can't be evaluated.
+  pub fn bsdf(&self, rec: &HitRecord, wo: Vec3, wi: Vec3) -> Option<(Vec3, f32)> {
+    let (base_f, base_pdf) = self.base.bsdf(rec, wo, wi)?;
+    let (tangent, bitangent) = shading_frame(rec.normal);
+    let wo = to_local(wo.unit_vector(), tangent, bitangent, rec.normal);
+    let wi = to_local(wi.unit_vector(), tangent, bitangent, rec.normal);
+    if wo.z() <= 0.0 {
+      return Some((base_f, base_pdf));
+    }
+    let (coat_f, coat_pdf) = self.coat(wo, wi);
+    let through = 1.0 - fresnel_dielectric(wo.z(), self.ior);
+    let base_f = if wi.z() > 0.0 { self.exit(rec, wo, wi) * through * base_f } else { Vec3::new() };
+    Some((Vec3::from([coat_f; 3]) + base_f, coat_pdf + through * base_pdf))
+  }
+
+  // The coat's reflection times cosine, and the density of `scatter` reflecting off it
+  // towards `wi`, in the local frame
+  fn coat(&self, wo: Vec3, wi: Vec3) -> (f32, f32) {
+    if wo.z() <= 0.0 || wi.z() <= 0.0 {
+      return (0.0, 0.0);
+    }
+    let h = (wo + wi).unit_vector();
+    let fresnel = fresnel_dielectric(wo.dot(h), self.ior);
+    let f = self.distribution.d(h) * self.distribution.g(wo, wi) * fresnel / (4.0 * wo.z());
+    let reflected = fresnel_dielectric(wo.z(), self.ior);
+    let pdf = self.distribution.pdf_visible(wo, h) * reflected / (4.0 * wo.dot(h));
+    (f, pdf)
+  }
+
+  // Light the base sends towards `wi` that makes it out of the coat: what the coat doesn't
+  // reflect back down, tinted along its paths down and up through the coat, plus what
+  // makes it out after bouncing between coat and base
+  fn exit(&self, rec: &HitRecord, wo: Vec3, wi: Vec3) -> Vec3 {
+    // Cosines of the refracted directions inside the coat
+    let inside = |cos: f32| (1.0 - (1.0 - cos * cos) / (self.ior * self.ior)).max(1e-4).sqrt();
+    let length = 0.5 * (1.0 / inside(wo.z()) + 1.0 / inside(wi.z()));
+    let c = self.color;
+    let tint = Vec3::from((c.r().powf(length), c.g().powf(length), c.b().powf(length)));
+    // Each time round, the coat reflects its average back down and the base keeps its albedo
+    let kept = self.average_fresnel() * self.base.albedo(rec.u, rec.v) * c;
+    let bounces = Vec3::from((1.0 / (1.0 - kept.r()), 1.0 / (1.0 - kept.g()), 1.0 / (1.0 - kept.b())));
+    (1.0 - fresnel_dielectric(wi.z(), self.ior)) * tint * bounces
+  }
+
+  // Fresnel averaged over the cosine weighted hemisphere, how much of the light a diffuse
+  // base sends up the coat reflects back down
+  fn average_fresnel(&self) -> f32 {
+    // Midpoint rule over cos², which the cosine weighting is uniform in
+    const STEPS: usize = 32;
+    (0..STEPS).map(|i| fresnel_dielectric(((i as f32 + 0.5) / STEPS as f32).sqrt(), self.ior)).sum::<f32>() / STEPS as f32
+  }
+}
+
+impl Material for Layered {
+  fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, ctx: &mut SamplerContext) -> Option<Ray> {
+    let (tangent, bitangent) = shading_frame(rec.normal);
+    let wo = to_local(-r_in.direction.unit_vector(), tangent, bitangent, rec.normal);
+    if wo.z() <= 0.0 {
+      // From underneath there's no coat in the way
+      return self.base.scatter(r_in, rec, attenuation, ctx);
+    }
+    // Reflect off the coat by Fresnel at the macro surface, as `bsdf` weighs the base, else go
+    // through to the base. The microfacet the ray meets sets the coat's weight.
+    let reflected = fresnel_dielectric(wo.z(), self.ior);
+    if ctx.bsdf_1d() < reflected {
+      let m = self.distribution.sample_visible(wo, ctx.bsdf_2d());
+      let wi = 2.0 * wo.dot(m) * m - wo;
+      if wi.z() <= 0.0 {
+        return None;
+      }
+      let fresnel = fresnel_dielectric(wo.dot(m), self.ior) / reflected;
+      *attenuation = Vec3::from([fresnel * self.distribution.g(wo, wi) / self.distribution.g1(wo); 3]);
+      return Some(Ray::from((rec.p, from_local(wi, tangent, bitangent, rec.normal))));
+    }
+    let scattered = self.base.scatter(r_in, rec, attenuation, ctx)?;
+    let wi = to_local(scattered.direction.unit_vector(), tangent, bitangent, rec.normal);
+    if wi.z() > 0.0 {
+      *attenuation *= self.exit(rec, wo, wi);
+    }
+    Some(scattered)
+  }
+}
+// =================================================================================
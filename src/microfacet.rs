@@ -0,0 +1,184 @@
+//! Microfacet module contains the GGX (Trowbridge-Reitz) distribution of microfacet normals
+//! and the Fresnel terms rough surfaces reflect with
+use std::f32::consts::PI;
+
+use crate::light::*;
+use crate::vec3::*;
+
+/// Smallest alpha used, below which the distribution's numbers break down. Surfaces this
+/// smooth are mirrors in all but name.
+const MIN_ALPHA: f32 = 1e-3;
+
+/// Anisotropic GGX, in a local frame where the macro surface normal is +z and the x axis
+/// is the tangent
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ggx {
+  /// Roughness along the tangent
+  pub alpha_x: f32,
+  /// Roughness along the bitangent
+  pub alpha_y: f32,
+}
+
+impl Ggx {
+  /// From artist friendly roughness in [0, 1], squared to get alpha, and anisotropy in
+  /// [0, 1], which stretches the highlight along the tangent
+  pub fn from_roughness(roughness: f32, anisotropy: f32) -> Self {
+    let alpha = roughness * roughness;
+    let aspect = (1.0 - 0.9 * anisotropy).sqrt();
+    Ggx {
+      alpha_x: (alpha / aspect).max(MIN_ALPHA),
+      alpha_y: (alpha * aspect).max(MIN_ALPHA),
+    }
+  }
+
+  /// Whether the surface is as smooth as the distribution goes
+  pub fn is_smooth(&self) -> bool {
+    self.alpha_x.max(self.alpha_y) <= MIN_ALPHA
+  }
+
+  /// Density of microfacet normal `m`
+  pub fn d(&self, m: Vec3) -> f32 {
+    if m.z() <= 0.0 {
+      return 0.0;
+    }
+    let e = (m.x() / self.alpha_x).powi(2) + (m.y() / self.alpha_y).powi(2) + m.z() * m.z();
+    1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+  }
+
+  /// Smith's Λ: how much of the surface is shadowed seen from `w`
+  pub fn lambda(&self, w: Vec3) -> f32 {
+    let z2 = w.z() * w.z();
+    if z2 == 0.0 {
+      return f32::INFINITY;
+    }
+    let a2 = ((self.alpha_x * w.x()).powi(2) + (self.alpha_y * w.y()).powi(2)) / z2;
+    ((1.0 + a2).sqrt() - 1.0) / 2.0
+  }
+
+  /// Fraction of microfacets facing `w` that `w` sees
+  pub fn g1(&self, w: Vec3) -> f32 {
+    1.0 / (1.0 + self.lambda(w))
+  }
+
+  /// Fraction of microfacets seen from both `wo` and `wi`, height correlated
+  pub fn g(&self, wo: Vec3, wi: Vec3) -> f32 {
+    1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+  }
+
+  /// Pick a microfacet normal as seen from `wo` (on the +z side), from uniform `u`. Heitz
+  /// (2018), "Sampling the GGX Distribution of Visible Normals".
+  pub fn sample_visible(&self, wo: Vec3, u: (f32, f32)) -> Vec3 {
+    // Stretch to the hemisphere configuration, where the distribution is a unit sphere
+    let vh = Vec3::from((self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z())).unit_vector();
+    let length2 = vh.x() * vh.x() + vh.y() * vh.y();
+    let t1 = if length2 > 0.0 {
+      Vec3::from((-vh.y(), vh.x(), 0.0)) / length2.sqrt()
+    } else {
+      Vec3::from((1.0, 0.0, 0.0))
+    };
+    let t2 = vh.cross(t1);
+    // A point on the disk, squashed onto the part of the hemisphere `wo` sees
+    let r = u.0.sqrt();
+    let phi = 2.0 * PI * u.1;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z());
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+    // And back
+    Vec3::from((self.alpha_x * nh.x(), self.alpha_y * nh.y(), nh.z().max(1e-6))).unit_vector()
+  }
+
+  /// Density of `sample_visible` picking `m` as seen from `wo`
+  pub fn pdf_visible(&self, wo: Vec3, m: Vec3) -> f32 {
+    if wo.z() <= 0.0 {
+      return 0.0;
+    }
+    self.g1(wo) * wo.dot(m).max(0.0) * self.d(m) / wo.z()
+  }
+}
+
+/// Burley's GTR1 distribution, with its longer tail than GGX, at a microfacet normal with
+/// cosine `cos_h` to the surface normal. Used for clearcoat.
+pub fn gtr1(cos_h: f32, alpha: f32) -> f32 {
+  let a2 = alpha * alpha;
+  (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_h * cos_h))
+}
+
+/// Pick a microfacet normal from GTR1 by its projected area, from uniform `u`
+pub fn sample_gtr1(alpha: f32, u: (f32, f32)) -> Vec3 {
+  let a2 = alpha * alpha;
+  let cos_h = ((1.0 - a2.powf(1.0 - u.0)) / (1.0 - a2)).max(0.0).sqrt();
+  let sin_h = (1.0 - cos_h * cos_h).max(0.0).sqrt();
+  let phi = 2.0 * PI * u.1;
+  Vec3::from((sin_h * phi.cos(), sin_h * phi.sin(), cos_h))
+}
+
+/// A tangent frame around unit normal `n`: the tangent runs around the y axis, like lines
+/// of latitude on a sphere, so anisotropic highlights look brushed. Falls back to any
+/// perpendicular where `n` is vertical.
+pub fn shading_frame(n: Vec3) -> (Vec3, Vec3) {
+  let around = Vec3::from((0.0, 1.0, 0.0)).cross(n);
+  if around.squared_length() < 1e-6 {
+    return basis(n);
+  }
+  let tangent = around.unit_vector();
+  (tangent, n.cross(tangent))
+}
+
+/// `v` in the local frame of (`tangent`, `bitangent`, `n`)
+pub fn to_local(v: Vec3, tangent: Vec3, bitangent: Vec3, n: Vec3) -> Vec3 {
+  Vec3::from((v.dot(tangent), v.dot(bitangent), v.dot(n)))
+}
+
+/// Local `v` back in the world
+pub fn from_local(v: Vec3, tangent: Vec3, bitangent: Vec3, n: Vec3) -> Vec3 {
+  v.x() * tangent + v.y() * bitangent + v.z() * n
+}
+
+/// Fraction of unpolarized light reflected at a boundary into a medium `eta` times as
+/// dense, at an angle with cosine `cos_i` to the normal. 1 past the critical angle.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let cos_i = cos_i.clamp(0.0, 1.0);
+  let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+  if sin2_t >= 1.0 {
+    return 1.0;
+  }
+  let cos_t = (1.0 - sin2_t).sqrt();
+  let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+  let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+  0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+/// `wo` refracted through a microfacet with normal `m` on its side, into a medium `eta`
+/// times as dense. None past the critical angle.
+pub fn refract_microfacet(wo: Vec3, m: Vec3, eta: f32) -> Option<Vec3> {
+  let cos_i = wo.dot(m);
+  let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+  if sin2_t >= 1.0 {
+    return None;
+  }
+  let cos_t = (1.0 - sin2_t).sqrt();
+  Some(-wo / eta + (cos_i / eta - cos_t) * m)
+}
+
+/// Fraction of light reflected by a conductor with complex index of refraction
+/// `eta` + i`k`, per channel, at an angle with cosine `cos_i` to the normal
+pub fn fresnel_conductor(cos_i: f32, eta: Vec3, k: Vec3) -> Vec3 {
+  let cos2 = cos_i.clamp(0.0, 1.0).powi(2);
+  let sin2 = 1.0 - cos2;
+  let mut reflectance = [0.0; 3];
+  for (c, r) in reflectance.iter_mut().enumerate() {
+    let (eta2, k2) = (eta[c] * eta[c], k[c] * k[c]);
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_i.clamp(0.0, 1.0) * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    *r = 0.5 * (rp + rs);
+  }
+  Vec3::from(reflectance)
+}
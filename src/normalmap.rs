@@ -0,0 +1,200 @@
+//! Normal map module contains the ways a surface's shading normal can be bent away from its
+//! geometric one, to add detail the geometry doesn't have
+use std::str::FromStr;
+
+use crate::texture::*;
+use crate::vec3::*;
+
+/// Smallest cosine the shading normal, and a mirror reflection off it, are kept at to the
+/// geometric normal
+const MIN_COSINE: f32 = 0.01;
+/// Step in u & v a bump map's slope is measured over
+const BUMP_DELTA: f32 = 1e-3;
+
+/// A texture bending the shading normal
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NormalMap {
+  /// Tangent space normals: red, green & blue in [0, 1] are the normal's tangent,
+  /// bitangent & normal parts scaled into [-1, 1], so flat is (0.5, 0.5, 1)
+  Normal(Texture),
+  /// Heights, read from red, raising the surface along its normal by `scale` times them
+  Bump { height: Texture, scale: f32 },
+}
+
+/// Where a surface was hit, as a normal map needs it
+pub struct SurfacePoint {
+  /// Unit geometric normal
+  pub normal: Vec3,
+  /// How the point moves as u & v increase
+  pub tangent: Vec3,
+  pub bitangent: Vec3,
+  pub u: f32,
+  pub v: f32,
+}
+
+impl NormalMap {
+  /// The shading normal at `point`, for a ray going in `direction`. It's kept above the
+  /// surface, and so that the ray reflects off it without going through the surface.
+  pub fn shading_normal(&self, point: &SurfacePoint, direction: Vec3) -> Vec3 {
+    let bent = match self {
+      NormalMap::Normal(texture) => {
+        let (tangent, bitangent) = orthonormal_frame(point);
+        let t = 2.0 * texture.value(point.u, point.v) - Vec3::from([1.0; 3]);
+        t.x() * tangent + t.y() * bitangent + t.z() * point.normal
+      },
+      NormalMap::Bump { height, scale } => {
+        // The surface moved out by the height moves along the normal as well as the tangents
+        let h = height.scalar(point.u, point.v);
+        let dh_du = (height.scalar(point.u + BUMP_DELTA, point.v) - h) / BUMP_DELTA;
+        let dh_dv = (height.scalar(point.u, point.v + BUMP_DELTA) - h) / BUMP_DELTA;
+        let dp_du = point.tangent + scale * dh_du * point.normal;
+        let dp_dv = point.bitangent + scale * dh_dv * point.normal;
+        let n = dp_du.cross(dp_dv);
+        if n.dot(point.normal) < 0.0 { -n } else { n }
+      },
+    };
+    if bent.squared_length() < 1e-12 {
+      return point.normal;
+    }
+    safeguard(bent.unit_vector(), point.normal, direction)
+  }
+}
+
+// A unit tangent & bitangent around the normal, the tangent following u
+fn orthonormal_frame(point: &SurfacePoint) -> (Vec3, Vec3) {
+  let n = point.normal;
+  let along = point.tangent - n.dot(point.tangent) * n;
+  let tangent = if along.squared_length() > 1e-12 {
+    along.unit_vector()
+  } else {
+    // At a pole u goes nowhere, so start from v
+    n.cross(point.bitangent).unit_vector()
+  };
+  let bitangent = n.cross(tangent);
+  // Turned to follow v too, whichever way u & v wind
+  if bitangent.dot(point.bitangent) < 0.0 {
+    (tangent, -bitangent)
+  } else {
+    (tangent, bitangent)
+  }
+}
+
+// Tip `shading` back towards `normal` until it's above the surface, then make sure a mirror
+// reflection off it stays on the ray's side of the surface. Otherwise a surface could be
+// lit or seen from the wrong side, glass would mistake rays going in for rays coming out,
+// and shiny surfaces would go black where every reflection went through them.
+fn safeguard(shading: Vec3, normal: Vec3, direction: Vec3) -> Vec3 {
+  let shading = tip_towards(shading, normal, normal);
+  // Work on the ray's side of the surface
+  let wo = -direction.unit_vector();
+  let flip = wo.dot(normal) < 0.0;
+  let (n, g) = if flip { (-shading, -normal) } else { (shading, normal) };
+  let reflected = 2.0 * wo.dot(n) * n - wo;
+  let height = reflected.dot(g);
+  if height >= MIN_COSINE {
+    return shading;
+  }
+  // Lift the reflection to just above the surface, and take the normal that reflects to it
+  let along = reflected - height * g;
+  let lifted = if along.squared_length() > 1e-12 {
+    (1.0 - MIN_COSINE * MIN_COSINE).sqrt() * along.unit_vector() + MIN_COSINE * g
+  } else {
+    g
+  };
+  let half = (wo + lifted).unit_vector();
+  if flip { -half } else { half }
+}
+
+// Add as little of `up` to unit `n` as keeps its cosine to `w` at least MIN_COSINE
+fn tip_towards(n: Vec3, up: Vec3, w: Vec3) -> Vec3 {
+  let cosine = n.dot(w);
+  let rate = up.dot(w);
+  if cosine >= MIN_COSINE || rate <= 0.0 {
+    return n;
+  }
+  (n + (MIN_COSINE - cosine) / rate * up).unit_vector()
+}
+
+/// `normal:TEXTURE` or `bump:SCALE:TEXTURE`, TEXTURE as any texture is given
+impl FromStr for NormalMap {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some(texture) = s.strip_prefix("normal:") {
+      return Ok(NormalMap::Normal(texture.parse()?));
+    }
+    if let Some(bump) = s.strip_prefix("bump:") {
+      let (scale, height) = bump.split_once(':').ok_or_else(|| format!("'{}' isn't bump:SCALE:TEXTURE", s))?;
+      return Ok(NormalMap::Bump {
+        height: height.parse()?,
+        scale: scale.parse().map_err(|_| format!("bad bump scale '{}'", scale))?,
+      });
+    }
+    Err(format!("'{}' isn't normal:TEXTURE or bump:SCALE:TEXTURE", s))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn point(u: f32, v: f32, tangent: Vec3) -> SurfacePoint {
+    SurfacePoint {
+      normal: Vec3::from((0.0, 0.0, 1.0)),
+      tangent,
+      bitangent: Vec3::from((0.0, 2.0, 0.0)),
+      u,
+      v,
+    }
+  }
+
+  // Directions coming at the surface from above, or below when `inside`, at each of
+  // `angles` to the normal and all round
+  fn directions(inside: bool, angles: &[f32]) -> Vec<Vec3> {
+    let mut directions = Vec::new();
+    for &theta in angles.iter() {
+      for phi in (0..8).map(|i| i as f32 * 45.0) {
+        let (theta, phi) = (theta.to_radians(), phi.to_radians());
+        let z = if inside { theta.cos() } else { -theta.cos() };
+        directions.push(Vec3::from((theta.sin() * phi.cos(), theta.sin() * phi.sin(), z)));
+      }
+    }
+    directions
+  }
+
+  #[test]
+  fn flat_normal_map_keeps_the_geometric_normal() {
+    let map: NormalMap = "normal:0.5/0.5/1".parse().unwrap();
+    // At a pole, too, where u goes nowhere
+    for tangent in [Vec3::from((3.0, 0.0, 0.0)), Vec3::new()].iter() {
+      let p = point(0.3, 0.7, *tangent);
+      for &inside in [false, true].iter() {
+        // Short of where even the geometric normal's reflection is too close to grazing
+        for direction in directions(inside, &[0.0, 30.0, 60.0, 85.0, 89.0]) {
+          let n = map.shading_normal(&p, direction);
+          assert!((n - p.normal).length() < 1e-5, "{:?} for {:?}", n, direction);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn steep_bumps_keep_reflections_above_the_surface() {
+    let map: NormalMap = "bump:20:waves:0:1:8".parse().unwrap();
+    for &inside in [false, true].iter() {
+      for direction in directions(inside, &[0.0, 30.0, 60.0, 85.0, 89.5, 89.99]) {
+        for i in 0..16 {
+          let p = point(i as f32 / 16.0, 0.37 * i as f32, Vec3::from((3.0, 0.0, 0.0)));
+          let n = map.shading_normal(&p, direction);
+          assert!((n.length() - 1.0).abs() < 1e-4);
+          assert!(n.dot(p.normal) > 0.0, "{:?} is below the surface", n);
+          // Reflected back to the side the ray came from
+          let wo = -direction.unit_vector();
+          let side = if inside { -p.normal } else { p.normal };
+          let reflected = 2.0 * wo.dot(n) * n - wo;
+          assert!(reflected.dot(side) >= MIN_COSINE - 1e-4,
+                  "{:?} reflects to {:?} off {:?}, inside {}", direction, reflected, n, inside);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,220 @@
+//! Progress module reports how far a render has got, and lets it be cancelled part way
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::stats::*;
+
+/// Snapshot of a render's progress, handed to a ProgressReporter
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProgressUpdate {
+  /// Pass (or adaptive round) being rendered, from 1
+  pub pass: u32,
+  /// Tiles finished out of the current pass's total
+  pub tiles_done: usize,
+  pub tiles_total: usize,
+  /// Rays traced so far, over every pass
+  pub rays: u64,
+  pub elapsed: Duration,
+  /// Estimate of how much of the whole render is done, 0 to 1
+  pub fraction: f32,
+}
+
+impl ProgressUpdate {
+  pub fn rays_per_second(&self) -> f64 {
+    self.rays as f64 / self.elapsed.as_secs_f64().max(1e-6)
+  }
+
+  /// Time left, assuming the rest of the render goes as fast as what's done so far
+  pub fn eta(&self) -> Option<Duration> {
+    if self.fraction <= 0.0 {
+      return None;
+    }
+    let remaining = f64::from((1.0 - self.fraction).max(0.0) / self.fraction);
+    Some(self.elapsed.mul_f64(remaining))
+  }
+}
+
+/// Implement this to be told how a render is going. Calls come from rayon's worker threads.
+pub trait ProgressReporter: Send + Sync {
+  fn update(&self, progress: &ProgressUpdate);
+
+  /// The render has stopped, either finished or cancelled
+  fn finish(&self, _progress: &ProgressUpdate) {}
+}
+
+/// Shared flag a render checks between tiles. Cloning it gives another handle to the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+  pub fn new() -> Self {
+    CancelToken::default()
+  }
+
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Relaxed)
+  }
+}
+
+/// Minimum time between calls to ProgressReporter::update
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Tracks a whole render (every pass of it) for the render functions, forwarding to a
+/// ProgressReporter and exposing the CancelToken.
+pub struct Progress {
+  start: Instant,
+  reporter: Option<Arc<dyn ProgressReporter>>,
+  cancel: CancelToken,
+  pass: AtomicUsize,
+  tiles_done: AtomicUsize,
+  tiles_total: AtomicUsize,
+  rays: AtomicU64,
+  stats: Mutex<RenderStats>,
+  // The slice of the whole render the current pass covers, as fractions from & to
+  pass_range: Mutex<(f32, f32)>,
+  last_report: Mutex<Instant>,
+}
+
+impl Default for Progress {
+  /// No reporting, and a token nobody else holds
+  fn default() -> Self {
+    Progress::new(None, CancelToken::new())
+  }
+}
+
+impl Progress {
+  pub fn new(reporter: Option<Arc<dyn ProgressReporter>>, cancel: CancelToken) -> Self {
+    let start = Instant::now();
+    Progress {
+      start,
+      reporter,
+      cancel,
+      pass: AtomicUsize::new(0),
+      tiles_done: AtomicUsize::new(0),
+      tiles_total: AtomicUsize::new(0),
+      rays: AtomicU64::new(0),
+      stats: Mutex::new(RenderStats::default()),
+      pass_range: Mutex::new((0.0, 1.0)),
+      last_report: Mutex::new(start),
+    }
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancel.is_cancelled()
+  }
+
+  /// Whether there's a reporter showing the progress
+  pub fn is_reporting(&self) -> bool {
+    self.reporter.is_some()
+  }
+
+  /// A pass over `tiles` tiles is starting, which takes the whole render from `from` to
+  /// `to` of the way done
+  pub fn begin_pass(&self, tiles: usize, from: f32, to: f32) {
+    self.pass.fetch_add(1, Ordering::SeqCst);
+    self.tiles_done.store(0, Ordering::SeqCst);
+    self.tiles_total.store(tiles, Ordering::SeqCst);
+    *self.pass_range.lock().unwrap() = (from, to);
+  }
+
+  /// A tile finished, doing the work counted in `stats`
+  pub fn tile_done(&self, stats: &RenderStats) {
+    self.tiles_done.fetch_add(1, Ordering::SeqCst);
+    self.rays.fetch_add(stats.rays, Ordering::Relaxed);
+    self.stats.lock().unwrap().merge(stats);
+    if let Some(reporter) = &self.reporter {
+      // Only hold the lock to claim the report, not while the reporter draws
+      let due = {
+        let mut last = self.last_report.lock().unwrap();
+        let due = last.elapsed() >= REPORT_INTERVAL;
+        if due {
+          *last = Instant::now();
+        }
+        due
+      };
+      if due {
+        reporter.update(&self.snapshot());
+      }
+    }
+  }
+
+  /// Everything counted over the render so far
+  pub fn stats(&self) -> RenderStats {
+    self.stats.lock().unwrap().clone()
+  }
+
+  /// Tell the reporter the render is over
+  pub fn finish(&self) {
+    if let Some(reporter) = &self.reporter {
+      reporter.finish(&self.snapshot());
+    }
+  }
+
+  pub fn snapshot(&self) -> ProgressUpdate {
+    let tiles_done = self.tiles_done.load(Ordering::SeqCst);
+    let tiles_total = self.tiles_total.load(Ordering::SeqCst);
+    let (from, to) = *self.pass_range.lock().unwrap();
+    let pass_fraction = if tiles_total > 0 { tiles_done as f32 / tiles_total as f32 } else { 0.0 };
+    ProgressUpdate {
+      pass: self.pass.load(Ordering::SeqCst) as u32,
+      tiles_done,
+      tiles_total,
+      rays: self.rays.load(Ordering::Relaxed),
+      elapsed: self.start.elapsed(),
+      fraction: (from + (to - from) * pass_fraction).clamp(0.0, 1.0),
+    }
+  }
+}
+
+/// Progress bar on stderr for the command line
+pub struct TerminalProgress;
+
+impl TerminalProgress {
+  fn draw(&self, p: &ProgressUpdate) {
+    const WIDTH: usize = 30;
+    let filled = (p.fraction * WIDTH as f32) as usize;
+    let eta = p.eta().map_or("--:--:--".to_string(), format_duration);
+    eprint!("\r[{}{}] {:5.1}% pass {} tile {}/{} | {:.2} Mrays/s | ETA {}  ",
+            "#".repeat(filled), ".".repeat(WIDTH - filled.min(WIDTH)), 100.0 * p.fraction,
+            p.pass, p.tiles_done, p.tiles_total, p.rays_per_second() / 1e6, eta);
+    let _ = io::stderr().flush();
+  }
+}
+
+impl ProgressReporter for TerminalProgress {
+  fn update(&self, progress: &ProgressUpdate) {
+    self.draw(progress);
+  }
+
+  fn finish(&self, progress: &ProgressUpdate) {
+    self.draw(progress);
+    eprintln!("\nDone in {}", format_duration(progress.elapsed));
+  }
+}
+
+/// hh:mm:ss
+pub fn format_duration(d: Duration) -> String {
+  let s = d.as_secs();
+  format!("{:02}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
+}
+
+/// Cancel `token` when the user hits Ctrl-C, so the render stops at the next tile and the
+/// image so far still gets written. A second Ctrl-C exits straight away.
+pub fn cancel_on_ctrl_c(token: &CancelToken) -> Result<(), String> {
+  let token = token.clone();
+  ctrlc::set_handler(move || {
+    if token.is_cancelled() {
+      std::process::exit(130);
+    }
+    eprintln!("\nCancelling, writing the image so far...");
+    token.cancel();
+  }).map_err(|e| format!("can't catch Ctrl-C: {}", e))
+}
@@ -19,6 +19,12 @@ impl Ray {
   }
 }
 
+impl Default for Ray {
+  fn default() -> Self {
+    Ray::new()
+  }
+}
+
 // Construct a Ray from 2 Vec3s
 impl From<(Vec3, Vec3)> for Ray {
   fn from(tuple: (Vec3, Vec3)) -> Self {
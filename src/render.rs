@@ -0,0 +1,785 @@
+//! Render module contains the integrator and the loops that fill a Film with samples
+use rayon::prelude::*;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::distribution::*;
+use crate::film::*;
+use crate::hitable::*;
+use crate::lpe::*;
+use crate::material::*;
+use crate::progress::*;
+use crate::ray::*;
+use crate::sampler::*;
+use crate::scene::*;
+use crate::settings::*;
+use crate::spectrum::*;
+use crate::tile::*;
+use crate::vec3::*;
+
+// Original color from book:
+const BG_COLOR: Vec3 = Vec3 {
+  e: [0.5, 0.7, 1.0]
+};
+
+// const BG_COLOR: Vec3 = Vec3 {
+//   e: [0.8, 0.6, 0.7]
+// };
+
+/// Mean luminance below which adaptive sampling stops treating error as relative
+const ADAPTIVE_MIN_MEAN: f32 = 0.05;
+/// Bounces after which a path is cut off
+const MAX_DEPTH: usize = 50;
+
+/// Radiance arriving along `r`. The background's light is found by scattering until the
+/// path escapes, the scene's lights by a shadow ray at every diffuse hit. Emissive spheres
+/// and an environment map are found both ways, weighted by multiple importance sampling.
+/// With a `split`, every bit of light is also added to the buffer of the path it took, and
+/// an `observer` is told about every step the path takes.
+/// When the scene is rendered spectrally the path carries a few wavelengths instead of RGB.
+pub fn color(r: &Ray, scene: &Scene, ctx: &mut SamplerContext, mut split: Option<&mut LpeSplit>,
+             mut observer: Option<&mut dyn FnMut(PathEvent)>) -> Vec3 {
+  // Do ray cast in world, and calculate color at location it hits based on what & where it hits.
+  let mut r = Ray::from((r.origin, r.direction));
+  let mut throughput = match &scene.spectra {
+    Some(spectra) => {
+      ctx.wavelengths = Some(Wavelengths::sample(ctx.wavelength_1d()));
+      Throughput::Spectral(SpectralPath::from(spectra))
+    },
+    None => Throughput::Rgb(Vec3::from([1.0; 3])),
+  };
+  let mut radiance = Vec3::new();
+  let mut tag = PathTag::default();
+  let mut depth = 0;
+  let mut last: Option<LastScatter> = None;
+  loop {
+    ctx.stats.rays += 1;
+    let rec = match scene.world.hit(&r, 0.001, f32::MAX, ctx) {
+      Some(rec) => rec,
+      None => {
+        observe(&mut observer, PathEvent::Escaped { depth });
+        let (light, sky) = escaped(scene, &r, last.as_ref());
+        let light = throughput.weigh(light, ctx);
+        observe_light(&mut observer, LightSource::Light, light);
+        deposit(&mut radiance, &mut split, &tag.extended(b"L"), light);
+        tag.push(b'B');
+        let sky = throughput.weigh(sky, ctx);
+        observe_light(&mut observer, LightSource::Background, sky);
+        deposit(&mut radiance, &mut split, &tag, sky);
+        break;
+      },
+    };
+    observe(&mut observer, PathEvent::Hit { depth, rec: &rec });
+
+    let emitted = throughput.weigh(hit_emission(scene, &rec, last.as_ref()), ctx);
+    observe_light(&mut observer, LightSource::Emission, emitted);
+    deposit(&mut radiance, &mut split, &tag.extended(b"L"), emitted);
+    let direct = throughput.weigh(direct_lighting(scene, &r, &rec, ctx), ctx);
+    observe_light(&mut observer, LightSource::Direct, direct);
+    deposit(&mut radiance, &mut split, &tag.extended(b"DL"), direct);
+    let area = throughput.weigh(area_lighting(scene, &r, &rec, ctx), ctx);
+    observe_light(&mut observer, LightSource::Area, area);
+    deposit(&mut radiance, &mut split, &tag.extended(b"DL"), area);
+    let environment = throughput.weigh(environment_lighting(scene, &r, &rec, ctx), ctx);
+    observe_light(&mut observer, LightSource::Environment, environment);
+    deposit(&mut radiance, &mut split, &tag.extended(b"DB"), environment);
+
+    let mut attenuation = Vec3::new();
+    match rec.material.scatter(&r, &rec, &mut attenuation, ctx) {
+      Some(scattered) if depth < MAX_DEPTH => {
+        ctx.stats.scatter_events += 1;
+        tag.push(scatter_event(&r, &rec, &scattered));
+        throughput.scatter(attenuation, ctx);
+        observe(&mut observer, PathEvent::Scattered {
+          direction: scattered.direction,
+          attenuation,
+          throughput: throughput.weigh(Vec3::from([1.0; 3]), ctx),
+        });
+        last = LastScatter::new(&r, &rec, &scattered);
+        r = scattered;
+        depth += 1;
+      },
+      Some(_) => {
+        observe(&mut observer, PathEvent::TooDeep);
+        break;
+      },
+      None => {
+        observe(&mut observer, PathEvent::Absorbed);
+        break;
+      },
+    }
+  }
+  ctx.stats.record_path(depth);
+  ctx.wavelengths = None;
+  radiance
+}
+
+/// One step of a path traced by `color`
+pub enum PathEvent<'a> {
+  /// The path's `depth`th ray hit something
+  Hit { depth: usize, rec: &'a HitRecord },
+  /// The path's `depth`th ray hit nothing
+  Escaped { depth: usize },
+  /// Light reaching the camera from the latest hit or escape, weighted by the throughput
+  Light { source: LightSource, radiance: Vec3 },
+  /// The path carries on in `direction`. `throughput` is how much white light arriving
+  /// along it would make it to the camera.
+  Scattered { direction: Vec3, attenuation: Vec3, throughput: Vec3 },
+  /// The path was cut off after MAX_DEPTH bounces
+  TooDeep,
+  /// The material didn't scatter the path
+  Absorbed,
+}
+
+/// Where the light in a PathEvent::Light came from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightSource {
+  /// A surface hit glowing by itself
+  Emission,
+  /// The scene's lights, by a shadow ray
+  Direct,
+  /// Emissive spheres, by a shadow ray
+  Area,
+  /// The environment map, by a shadow ray
+  Environment,
+  /// The scene's lights, seen directly by an escaped ray
+  Light,
+  /// The background or sky behind an escaped ray
+  Background,
+}
+
+impl fmt::Display for LightSource {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      LightSource::Emission => "emits",
+      LightSource::Direct => "direct light",
+      LightSource::Area => "emissive sphere light",
+      LightSource::Environment => "environment light",
+      LightSource::Light => "looking straight at a light",
+      LightSource::Background => "background",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+fn observe(observer: &mut Option<&mut dyn FnMut(PathEvent)>, event: PathEvent) {
+  if let Some(observer) = observer.as_mut() {
+    observer(event);
+  }
+}
+
+// Only light that's there is worth telling about
+fn observe_light(observer: &mut Option<&mut dyn FnMut(PathEvent)>, source: LightSource, radiance: Vec3) {
+  if radiance != Vec3::new() {
+    observe(observer, PathEvent::Light { source, radiance });
+  }
+}
+
+// How much of the light reaching a path's latest hit makes it back to the camera
+enum Throughput<'a> {
+  Rgb(Vec3),
+  /// At the wavelengths in the sampler context
+  Spectral(SpectralPath<'a>),
+}
+
+impl Throughput<'_> {
+  // RGB light `c` reaching the latest hit, as RGB at the camera
+  fn weigh(&self, c: Vec3, ctx: &SamplerContext) -> Vec3 {
+    match (self, &ctx.wavelengths) {
+      (Throughput::Spectral(path), Some(wavelengths)) => path.weigh(c, wavelengths),
+      (Throughput::Rgb(throughput), _) => *throughput * c,
+      _ => Vec3::new(),
+    }
+  }
+
+  fn scatter(&mut self, attenuation: Vec3, ctx: &SamplerContext) {
+    match (self, &ctx.wavelengths) {
+      (Throughput::Spectral(path), Some(wavelengths)) => path.scatter(attenuation, wavelengths),
+      (Throughput::Rgb(throughput), _) => *throughput *= attenuation,
+      _ => (),
+    }
+  }
+}
+
+// Add light `c` that arrived along the path `tag` to a sample
+fn deposit(radiance: &mut Vec3, split: &mut Option<&mut LpeSplit>, tag: &PathTag, c: Vec3) {
+  if c == Vec3::new() {
+    return;
+  }
+  if let Some(split) = split.as_mut() {
+    split.add(tag, c);
+  }
+  *radiance += c;
+}
+
+/// A hit that sampled the lights, kept so that if the ray it scattered finds a light too,
+/// that can be weighted against the light having been sampled
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LastScatter {
+  pub p: Vec3,
+  pub normal: Vec3,
+  /// Density over solid angle of the scattered direction
+  pub pdf: f32,
+}
+
+impl LastScatter {
+  /// None if `rec` doesn't sample the lights
+  pub fn new(r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Option<Self> {
+    if !samples_lights(rec) {
+      return None;
+    }
+    let (_, pdf) = rec.material.bsdf(rec, -r_in.direction, scattered.direction)?;
+    Some(LastScatter {
+      p: rec.p,
+      normal: rec.normal,
+      pdf,
+    })
+  }
+}
+
+/// Whether a hit gathers light from the scene's lights with shadow rays. Only diffuse and
+/// opaque principled surfaces do, and mixes and coats of those: a delta light reflected in
+/// a mirror or seen through glass is a single direction the scattered ray never takes, and
+/// the sun is so small that the scattered ray finding it by chance is the better estimate.
+pub fn samples_lights(rec: &HitRecord) -> bool {
+  rec.material.samples_lights(rec.u, rec.v)
+}
+
+/// Light reaching a hit straight from the scene's lights and reflected back along `r`
+pub fn direct_lighting(scene: &Scene, r: &Ray, rec: &HitRecord, ctx: &mut SamplerContext) -> Vec3 {
+  if !samples_lights(rec) {
+    return Vec3::new();
+  }
+  let mut total = Vec3::new();
+  for light in scene.lights.iter() {
+    let sample = match light.sample_li(rec.p, ctx) {
+      Some(sample) => sample,
+      None => continue,
+    };
+    let cosine = rec.normal.dot(sample.wi);
+    if cosine <= 0.0 {
+      continue;
+    }
+    ctx.stats.rays += 1;
+    ctx.stats.shadow_rays += 1;
+    let shadow = Ray::from((rec.p, sample.wi));
+    if scene.world.hit(&shadow, 0.001, sample.distance, ctx).is_none() {
+      if let Some((f, _)) = rec.material.bsdf(rec, -r.direction, sample.wi) {
+        total += f * sample.li;
+      }
+    }
+  }
+  total
+}
+
+/// Light reaching a hit from one emissive sphere, chosen by the scene's light selector and
+/// reflected back along `r`. Weighted against the hit's scattered ray finding the same
+/// light.
+pub fn area_lighting(scene: &Scene, r: &Ray, rec: &HitRecord, ctx: &mut SamplerContext) -> Vec3 {
+  if scene.area_lights.is_empty() || !samples_lights(rec) {
+    return Vec3::new();
+  }
+  let (index, pmf) = match scene.light_selector.sample(rec.p, rec.normal, ctx.light_1d()) {
+    Some(choice) => choice,
+    None => return Vec3::new(),
+  };
+  let light = &scene.area_lights[index];
+  let sample = match light.sample(rec.p, ctx.light_2d()) {
+    Some(sample) => sample,
+    None => return Vec3::new(),
+  };
+  let cosine = rec.normal.dot(sample.wi);
+  if cosine <= 0.0 {
+    return Vec3::new();
+  }
+  ctx.stats.rays += 1;
+  ctx.stats.shadow_rays += 1;
+  let shadow = Ray::from((rec.p, sample.wi));
+  // Stop short of the light's own surface
+  if scene.world.hit(&shadow, 0.001, sample.distance - 0.001, ctx).is_some() {
+    return Vec3::new();
+  }
+  let (f, bsdf_pdf) = match rec.material.bsdf(rec, -r.direction, sample.wi) {
+    Some(bsdf) => bsdf,
+    None => return Vec3::new(),
+  };
+  let pdf = pmf * sample.pdf;
+  (power_heuristic(pdf, bsdf_pdf) / pdf) * f * light.radiance
+}
+
+/// Light given off by the surface a ray hit. If the hit before sampled the lights, it's
+/// weighted against that having found this light.
+pub fn hit_emission(scene: &Scene, rec: &HitRecord, last: Option<&LastScatter>) -> Vec3 {
+  let emitted = rec.material.emitted(rec.u, rec.v);
+  if emitted == Vec3::new() {
+    return emitted;
+  }
+  match (last, scene.area_light(rec.object_id)) {
+    (Some(last), Some(index)) => {
+      let light_pdf = scene.light_selector.pmf(last.p, last.normal, index) * scene.area_lights[index].pdf(last.p);
+      power_heuristic(last.pdf, light_pdf) * emitted
+    },
+    _ => emitted,
+  }
+}
+
+/// Light reaching a hit from the environment map, sampled by its brightness and reflected
+/// back along `r`. Weighted against the hit's scattered ray finding the same light.
+pub fn environment_lighting(scene: &Scene, r: &Ray, rec: &HitRecord, ctx: &mut SamplerContext) -> Vec3 {
+  let environment = match &scene.environment {
+    Some(environment) if samples_lights(rec) => environment,
+    _ => return Vec3::new(),
+  };
+  let sample = match environment.sample(ctx.light_2d()) {
+    Some(sample) => sample,
+    None => return Vec3::new(),
+  };
+  let cosine = rec.normal.dot(sample.wi);
+  if cosine <= 0.0 {
+    return Vec3::new();
+  }
+  ctx.stats.rays += 1;
+  ctx.stats.shadow_rays += 1;
+  let shadow = Ray::from((rec.p, sample.wi));
+  if scene.world.hit(&shadow, 0.001, f32::MAX, ctx).is_some() {
+    return Vec3::new();
+  }
+  let (f, bsdf_pdf) = match rec.material.bsdf(rec, -r.direction, sample.wi) {
+    Some(bsdf) => bsdf,
+    None => return Vec3::new(),
+  };
+  (power_heuristic(sample.pdf, bsdf_pdf) / sample.pdf) * f * sample.radiance
+}
+
+/// Light brought back by a ray that escapes the scene: from lights it looks straight at,
+/// and from the background. If the last hit sampled the lights too, those lights aren't
+/// counted twice, and an environment map is weighted against having been sampled.
+pub fn escaped(scene: &Scene, r: &Ray, last: Option<&LastScatter>) -> (Vec3, Vec3) {
+  let light = if last.is_some() { Vec3::new() } else { light_emitted(scene, r) };
+  let sky = background(scene, r);
+  match (&scene.environment, last) {
+    (Some(environment), Some(last)) => (light, power_heuristic(last.pdf, environment.pdf(r.direction)) * sky),
+    _ => (light, sky),
+  }
+}
+
+/// Color of a ray that escapes the scene: the sky, environment map or background color if
+/// there is one, else the book's white to blue gradient
+pub fn background(scene: &Scene, r: &Ray) -> Vec3 {
+  if let Some(sky) = &scene.sky {
+    return sky.radiance(r.direction);
+  }
+  if let Some(environment) = &scene.environment {
+    return environment.radiance(r.direction);
+  }
+  if let Some(color) = scene.background {
+    return color;
+  }
+  let unit_direction = r.direction.unit_vector();
+  let t = 0.5 * (unit_direction.y() + 1.0);
+  // This is an oddly crafted port of C++ code that returns the same result.
+  (1.0 - t) * Vec3::from([1.0; 3]) + t * BG_COLOR
+}
+
+/// Radiance of the lights a ray that escapes the scene looks straight at
+pub fn light_emitted(scene: &Scene, r: &Ray) -> Vec3 {
+  scene.lights.iter().fold(Vec3::new(), |sum, light| sum + light.emitted(r.direction))
+}
+
+/// Start sample `s` of pixel (x, y) and generate its camera ray. Also returns the
+/// continuous raster position the ray goes through, for the film to filter with.
+pub fn camera_ray(scene: &Scene, width: u32, height: u32, x: u32, y: u32, s: u32,
+                  ctx: &mut SamplerContext) -> (f32, f32, Ray) {
+  ctx.start_pixel_sample(x, y, s);
+  ctx.stats.camera_rays += 1;
+  let (dx, dy) = ctx.pixel_2d();
+  let fx = x as f32 + dx;
+  let fy = y as f32 + dy;
+  // Raster y runs down the image, the camera's v runs up it
+  (fx, fy, scene.camera.get_ray(fx / width as f32, 1.0 - fy / height as f32, ctx))
+}
+
+/// Trace sample `s` of pixel (x, y). Returns the raster position the sample was taken at
+/// and its color.
+pub fn sample_pixel(scene: &Scene, width: u32, height: u32, x: u32, y: u32, s: u32,
+                    ctx: &mut SamplerContext) -> (f32, f32, Vec3) {
+  let (fx, fy, r) = camera_ray(scene, width, height, x, y, s, ctx);
+  (fx, fy, color(&r, scene, ctx, None, None))
+}
+
+/// Add samples to `film` in parallel tiles. `samples(x, y)` is the range of sample indices
+/// to take at pixel (x, y). Every tile splats into its own FilmTile, and those are merged
+/// in tile order so the result doesn't depend on scheduling.
+/// Once `progress` is cancelled the remaining tiles are skipped, so every pixel has either
+/// taken all of this pass's samples or none of them.
+pub fn render_pass<F>(scene: &Scene, settings: &RenderSettings, film: &mut Film,
+                      sampler: SamplerType, spp: u32, samples: F, progress: &Progress)
+  where
+    F: Fn(u32, u32) -> Range<u32> + Sync,
+{
+  let (width, height) = (film.width, film.height);
+  let tiles = tiles(&film.crop, settings.tile_size, settings.tile_order);
+
+  // Each worker gets its own SamplerContext, which is reseeded per pixel sample.
+  let init = || SamplerContext::with_sampler(settings.seed, sampler, spp);
+  let film_ref = &*film;
+  let film_tiles: Vec<Option<FilmTile>> = tiles.par_iter().map_init(init, |ctx, bounds| {
+    if progress.is_cancelled() {
+      return None;
+    }
+    let mut tile = film_ref.tile(bounds);
+    let mut split = LpeSplit::new(&settings.lpe);
+    for y in bounds.y0..bounds.y1 {
+      for x in bounds.x0..bounds.x1 {
+        // Anti-aliasing by generating a bunch of jittered points and filtering them
+        for s in samples(x, y) {
+          let (fx, fy, r) = camera_ray(scene, width, height, x, y, s, ctx);
+          if settings.lpe.is_empty() {
+            tile.add_sample((x, y), fx, fy, color(&r, scene, ctx, None, None));
+            continue;
+          }
+          split.clear();
+          let c = color(&r, scene, ctx, Some(&mut split), None);
+          tile.add_sample((x, y), fx, fy, c);
+          for (buffer, &light) in split.buffers.iter().enumerate() {
+            if light != Vec3::new() {
+              tile.add_lpe_sample(buffer, fx, fy, light);
+            }
+          }
+        }
+      }
+    }
+    progress.tile_done(&std::mem::take(&mut ctx.stats));
+    Some(tile)
+  }).collect();
+
+  for tile in film_tiles.iter().flatten() {
+    film.merge_tile(tile);
+  }
+}
+
+/// Render the whole image with `spp` samples in every pixel
+pub fn render_parallel(scene: &Scene, settings: &RenderSettings, sampler: SamplerType,
+                       spp: u32, progress: &Progress) -> Film {
+  let mut film = settings.film();
+  progress.begin_pass(tile_count(&film, settings), 0.0, 1.0);
+  render_pass(scene, settings, &mut film, sampler, spp, |_, _| 0..spp, progress);
+  film
+}
+
+/// Render `settings.spp` samples everywhere, then keep doubling the sample count of pixels
+/// whose relative error is above `settings.adaptive_threshold`, up to `settings.max_spp`.
+/// A pixel stays active while any pixel in its 3x3 neighbourhood is above the threshold, so
+/// a pixel whose first few samples happened to agree doesn't get left behind.
+pub fn render_adaptive(scene: &Scene, settings: &RenderSettings, progress: &Progress) -> Film {
+  let (width, height) = (settings.width, settings.height);
+  let max_spp = settings.max_spp.max(settings.spp);
+  // For the progress estimate, assume every round runs until max_spp is reached
+  let rounds = 1.0 + (max_spp as f32 / settings.spp as f32).log2().ceil().max(0.0);
+  let mut film = settings.film();
+  progress.begin_pass(tile_count(&film, settings), 0.0, 1.0 / rounds);
+  render_pass(scene, settings, &mut film, settings.sampler, settings.spp, |_, _| 0..settings.spp,
+              progress);
+
+  let mut round = 1.0;
+  while !progress.is_cancelled() {
+    // Pixels outside the crop have no samples, so they'd look infinitely noisy
+    let noisy: Vec<bool> = film.pixels.iter().enumerate()
+      .map(|(index, p)| {
+        let (x, y) = (index as u32 % width, index as u32 / width);
+        film.crop.contains(x, y) && p.relative_error(ADAPTIVE_MIN_MEAN) > settings.adaptive_threshold
+      })
+      .collect();
+    // Samples to add at each pixel this round
+    let extra: Vec<u32> = (0..width * height).map(|index| {
+      let (x, y) = (index % width, index / width);
+      let n = film.pixels[index as usize].samples;
+      let active = film.crop.contains(x, y) && (y.saturating_sub(1)..(y + 2).min(height))
+        .any(|ny| (x.saturating_sub(1)..(x + 2).min(width))
+          .any(|nx| noisy[(ny * width + nx) as usize]));
+      if active { n.min(max_spp.saturating_sub(n)) } else { 0 }
+    }).collect();
+    if extra.iter().all(|&e| e == 0) {
+      break;
+    }
+
+    let taken: Vec<u32> = film.pixels.iter().map(|p| p.samples).collect();
+    progress.begin_pass(tile_count(&film, settings), round / rounds, ((round + 1.0) / rounds).min(1.0));
+    render_pass(scene, settings, &mut film, settings.sampler, settings.spp, |x, y| {
+      let index = (y * width + x) as usize;
+      taken[index]..taken[index] + extra[index]
+    }, progress);
+    round += 1.0;
+  }
+
+  film
+}
+
+/// Render passes of `settings.pass_spp` samples over the whole image, accumulating into
+/// `film`, until `settings.time_budget` seconds have passed, the target spp is reached or
+/// `progress` is cancelled. `on_pass` is called with the film and the samples per pixel so
+/// far after every completed pass.
+/// The film may already have samples in it: each pixel carries on from its own sample
+/// count, so a film from a pass that was cancelled part way through catches up on the tiles
+/// it missed.
+pub fn render_progressive_from<F>(scene: &Scene, settings: &RenderSettings, mut film: Film,
+                                  progress: &Progress, mut on_pass: F) -> Film
+  where
+    F: FnMut(&Film, u32),
+{
+  let start = Instant::now();
+  let budget = settings.time_budget.map(Duration::from_secs_f64);
+  let target = settings.progressive_target_spp();
+  let pass_spp = settings.pass_spp;
+  let sampler_spp = settings.progressive_sampler_spp();
+  let width = film.width;
+  let mut spp = film.min_samples();
+
+  while target.is_none_or(|target| spp < target) && budget.is_none_or(|b| start.elapsed() < b) {
+    // The last pass only takes what's needed to land exactly on the target
+    let pass_end = |taken: u32| target.map_or(taken + pass_spp, |target| (taken + pass_spp).min(target));
+    // Estimate by whichever of the target and time budget is there
+    let (from, to) = match (target, budget) {
+      (Some(target), _) => (spp as f32 / target as f32, pass_end(spp) as f32 / target as f32),
+      (None, Some(b)) => {
+        let t = (start.elapsed().as_secs_f64() / b.as_secs_f64()) as f32;
+        (t, t)
+      },
+      (None, None) => (0.0, 0.0),
+    };
+    progress.begin_pass(tile_count(&film, settings), from, to);
+
+    let taken: Vec<u32> = film.pixels.iter().map(|p| p.samples).collect();
+    render_pass(scene, settings, &mut film, settings.sampler, sampler_spp, |x, y| {
+      let taken = taken[(y * width + x) as usize];
+      taken..pass_end(taken)
+    }, progress);
+    if progress.is_cancelled() {
+      break;
+    }
+    spp = film.min_samples();
+    on_pass(&film, spp);
+  }
+
+  film
+}
+
+fn tile_count(film: &Film, settings: &RenderSettings) -> usize {
+  let size = settings.tile_size;
+  (film.crop.width().div_ceil(size) * film.crop.height().div_ceil(size)) as usize
+}
+
+/// The original scheme: a rayon job per pixel, plus a nested one per sample, box averaged.
+/// Kept as the baseline `--bench` compares tiled rendering against.
+pub fn render_per_pixel(scene: &Scene, settings: &RenderSettings) -> Vec<Vec3> {
+  let (width, height, spp) = (settings.width, settings.height, settings.spp);
+  let init = || SamplerContext::with_sampler(settings.seed, settings.sampler, spp);
+  let mut pixels = vec![Vec3::new(); (width * height) as usize];
+  pixels.par_iter_mut().enumerate().for_each(|(index, pxl)| {
+    let (x, y) = (index as u32 % width, index as u32 / width);
+    let sum: Vec3 = (0..spp).into_par_iter().map_init(init, |ctx, s| {
+      sample_pixel(scene, width, height, x, y, s, ctx).2
+    }).sum();
+    *pxl = sum / spp as f32;
+  });
+  pixels
+}
+
+/// Single threaded version of render_parallel
+pub fn render_serial(scene: &Scene, settings: &RenderSettings) -> Film {
+  let (width, height) = (settings.width, settings.height);
+  let mut film = settings.film();
+  let crop = film.crop;
+  let mut tile = film.tile(&crop);
+  let mut ctx = SamplerContext::with_sampler(settings.seed, settings.sampler, settings.spp);
+  // O(n^2) for looping
+  for y in crop.y0..crop.y1 {
+    for x in crop.x0..crop.x1 {
+      for s in 0..settings.spp {
+        let (fx, fy, c) = sample_pixel(scene, width, height, x, y, s, &mut ctx);
+        tile.add_sample((x, y), fx, fy, c);
+      }
+    }
+  }
+  film.merge_tile(&tile);
+  film
+}
+
+/// Write linear pixels (top row first) as a PPM
+pub fn write_ppm<W: Write>(out: &mut W, pixels: &[Vec3], width: u32, height: u32) -> io::Result<()> {
+  // PPM header
+  writeln!(out, "P3\n{} {}\n255", width, height)?;
+  for pixel in pixels.iter() {
+    writeln!(out, "{}", ppm_pixel(*pixel))?;
+  }
+  Ok(())
+}
+
+/// Write planes of linear values (top row first) as a PFM: grayscale for one plane, RGB
+/// for two or three, with any missing planes 0
+pub fn write_pfm<W: Write>(out: &mut W, planes: &[Vec<f32>], width: u32, height: u32) -> io::Result<()> {
+  let gray = planes.len() == 1;
+  // A negative scale means little endian
+  writeln!(out, "{}\n{} {}\n-1.0", if gray { "Pf" } else { "PF" }, width, height)?;
+  // PFM rows run bottom to top
+  for y in (0..height).rev() {
+    for x in 0..width {
+      let index = (y * width + x) as usize;
+      for c in 0..if gray { 1 } else { 3 } {
+        let v = planes.get(c).map_or(0.0, |plane| plane[index]);
+        out.write_all(&v.to_le_bytes())?;
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Grayscale image of how many samples each pixel took, white being `max_spp`
+pub fn sample_count_image(film: &Film, max_spp: u32) -> Vec<Vec3> {
+  film.pixels.iter().map(|p| {
+    let t = (p.samples as f32 / max_spp as f32).min(1.0);
+    // Pre-squared so the gamma 2 encode in write_ppm leaves the counts linear
+    Vec3::from([t * t; 3])
+  }).collect()
+}
+
+/// One PPM pixel, gamma 2 corrected by sqrt'ing each value
+pub fn ppm_pixel(pixel: Vec3) -> String {
+  // Negative filter lobes can push values outside [0, 1]
+  let c = |v: f32| (255.99 * v.clamp(0.0, 1.0).sqrt()) as i32;
+  format!("{} {} {}", c(pixel.r()), c(pixel.g()), c(pixel.b()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::convert::TryInto;
+
+  // A few pixels of the default scene around the big spheres, resolved
+  fn render_crop(scene: &Scene, settings: &RenderSettings, sampler: SamplerType, spp: u32) -> Vec<Vec3> {
+    let film = render_parallel(scene, settings, sampler, spp, &Progress::default());
+    crop_image(&film.resolve(), settings.width, &settings.crop_bounds())
+  }
+
+  #[test]
+  fn pfm_header_then_rows_bottom_up() {
+    let planes = vec![vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0], vec![0.5; 6], vec![-1.0; 6]];
+    let mut bytes = Vec::new();
+    write_pfm(&mut bytes, &planes, 3, 2).unwrap();
+    let header = b"PF\n3 2\n-1.0\n";
+    assert_eq!(&bytes[..header.len()], header);
+    assert_eq!(bytes.len(), header.len() + 3 * 2 * 3 * 4);
+    let value = |i: usize| f32::from_le_bytes(bytes[header.len() + 4 * i..header.len() + 4 * i + 4].try_into().unwrap());
+    // The bottom row's first pixel comes first
+    assert_eq!((value(0), value(1), value(2)), (3.0, 0.5, -1.0));
+    assert_eq!(value(9), 0.0);
+
+    let mut gray = Vec::new();
+    write_pfm(&mut gray, &planes[..1], 3, 2).unwrap();
+    assert!(gray.starts_with(b"Pf\n3 2\n-1.0\n"));
+    assert_eq!(gray.len(), header.len() + 3 * 2 * 4);
+  }
+
+  #[test]
+  fn observer_sees_all_the_light_a_path_gathers() {
+    let settings = RenderSettings { width: 40, height: 20, ..RenderSettings::default() };
+    let scene = Scene::random_spheres(settings.seed, 2.0, &SceneOptions { emissive: 0.5, ..SceneOptions::default() });
+    let mut ctx = SamplerContext::new(settings.seed);
+    for s in 0..64 {
+      let (_, _, r) = camera_ray(&scene, settings.width, settings.height, 20, 12, s, &mut ctx);
+      let mut observed = Vec3::new();
+      let mut ends = 0;
+      let radiance = color(&r, &scene, &mut ctx, None, Some(&mut |event| match event {
+        PathEvent::Light { radiance, .. } => observed += radiance,
+        PathEvent::Escaped { .. } | PathEvent::TooDeep | PathEvent::Absorbed => ends += 1,
+        _ => (),
+      }));
+      assert!((radiance - observed).length() < 1e-5, "{:?} vs {:?}", radiance, observed);
+      assert_eq!(ends, 1);
+    }
+  }
+
+  #[test]
+  fn every_sampler_converges_on_the_scene() {
+    let settings = RenderSettings {
+      width: 40,
+      height: 20,
+      crop: Some(CropWindow::Pixels(16, 8, 24, 12)),
+      ..RenderSettings::default()
+    };
+    let scene = Scene::random_spheres(settings.seed, 2.0, &settings.scene_options());
+    // Seeded differently so the reference's noise is independent of the samplers under test
+    let reference_settings = RenderSettings { seed: !settings.seed, ..settings.clone() };
+    let reference = render_crop(&scene, &reference_settings, SamplerType::Sobol, 1024);
+    for &sampler in SamplerType::ALL.iter() {
+      let errors: Vec<f64> = [4, 64].iter().map(|&spp| {
+        let image = render_crop(&scene, &settings, sampler, spp);
+        let squared_error: f64 = image.iter().zip(reference.iter())
+          .map(|(a, b)| f64::from((*a - *b).squared_length()))
+          .sum();
+        (squared_error / (3 * image.len()) as f64).sqrt()
+      }).collect();
+      // 16x the samples should at least halve the error
+      assert!(errors[1] < errors[0] / 2.0, "{} errors {:?}", sampler, errors);
+    }
+  }
+
+  #[test]
+  fn thread_count_does_not_change_the_film() {
+    // Small tiles, so many of them overlap through the filter and get merged
+    let settings = RenderSettings { width: 24, height: 12, tile_size: 4, ..RenderSettings::default() };
+    let scene = Scene::random_spheres(settings.seed, 2.0, &settings.scene_options());
+    let render = |threads: usize, sampler: SamplerType| {
+      let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+      pool.install(|| render_parallel(&scene, &settings, sampler, 4, &Progress::default()))
+    };
+    for &sampler in SamplerType::ALL.iter() {
+      let one = render(1, sampler);
+      let four = render(4, sampler);
+      assert!(one.pixels == four.pixels, "{} film depends on the thread count", sampler);
+    }
+  }
+
+  #[test]
+  fn adaptive_sampling_stops_where_the_image_is_flat() {
+    let settings = RenderSettings { width: 40, height: 20, spp: 4, max_spp: 64, ..RenderSettings::default() };
+    let mut scene = Scene::random_spheres(settings.seed, 2.0, &settings.scene_options());
+    // A constant sky has no noise at all, unlike the spheres under it
+    scene.background = Some(Vec3::from((0.5, 0.7, 1.0)));
+    let film = render_adaptive(&scene, &settings, &Progress::default());
+    let samples = |x: u32, y: u32| film.pixels[(y * settings.width + x) as usize].samples;
+    for x in 0..settings.width {
+      assert_eq!(samples(x, 0), settings.spp, "sky pixel {}, 0", x);
+    }
+    let most = (0..settings.width * settings.height)
+      .map(|index| samples(index % settings.width, index / settings.width))
+      .max()
+      .unwrap();
+    assert_eq!(most, settings.max_spp);
+  }
+
+  #[test]
+  fn adaptive_sampling_ignores_pixels_outside_the_crop() {
+    let settings = RenderSettings {
+      width: 40,
+      height: 20,
+      spp: 4,
+      max_spp: 64,
+      adaptive_threshold: 100.0,
+      crop: Some(CropWindow::Pixels(16, 8, 24, 12)),
+      ..RenderSettings::default()
+    };
+    let scene = Scene::random_spheres(settings.seed, 2.0, &settings.scene_options());
+    let film = render_adaptive(&scene, &settings, &Progress::default());
+    // Nothing is that noisy, so not even the crop's border goes past the first round
+    let bounds = settings.crop_bounds();
+    for y in 0..settings.height {
+      for x in 0..settings.width {
+        let expected = if bounds.contains(x, y) { settings.spp } else { 0 };
+        assert_eq!(film.pixels[(y * settings.width + x) as usize].samples, expected, "pixel {}, {}", x, y);
+      }
+    }
+  }
+}
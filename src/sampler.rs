@@ -0,0 +1,557 @@
+//! Sampler module contains the Sampler trait, its implementations, and the SamplerContext
+//! that carries random state through a render
+use crate::lowdiscrepancy::*;
+use crate::spectrum::*;
+use crate::stats::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
+use std::fmt;
+use std::str::FromStr;
+
+/// Hands out the sample values for one camera sample, one dimension at a time.
+/// Implementations only have to be deterministic given (pixel, sample index, dimension).
+pub trait Sampler: Send {
+  /// Begin sample `sample_index` of pixel (x, y) and reset the dimension counter
+  fn start_pixel_sample(&mut self, x: u32, y: u32, sample_index: u32);
+  fn get_1d(&mut self) -> f32;
+  fn get_2d(&mut self) -> (f32, f32);
+}
+
+/// The available Sampler implementations
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SamplerType {
+  Independent,
+  Stratified,
+  Halton,
+  Sobol,
+  BlueNoise,
+}
+
+impl SamplerType {
+  pub const ALL: [SamplerType; 5] = [
+    SamplerType::Independent,
+    SamplerType::Stratified,
+    SamplerType::Halton,
+    SamplerType::Sobol,
+    SamplerType::BlueNoise,
+  ];
+
+  /// Build a sampler of this type. `spp` is only a hint for how many samples each pixel
+  /// will take; samplers keep producing valid values past it.
+  pub fn create(self, seed: u64, spp: u32) -> Box<dyn Sampler> {
+    match self {
+      SamplerType::Independent => Box::new(IndependentSampler::new(seed)),
+      SamplerType::Stratified => Box::new(StratifiedSampler::new(seed, spp)),
+      SamplerType::Halton => Box::new(HaltonSampler::new(seed)),
+      SamplerType::Sobol => Box::new(SobolSampler::new(seed)),
+      SamplerType::BlueNoise => Box::new(BlueNoiseSampler::new(seed)),
+    }
+  }
+}
+
+impl FromStr for SamplerType {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "independent" => Ok(SamplerType::Independent),
+      "stratified" => Ok(SamplerType::Stratified),
+      "halton" => Ok(SamplerType::Halton),
+      "sobol" => Ok(SamplerType::Sobol),
+      "bluenoise" => Ok(SamplerType::BlueNoise),
+      _ => Err(format!("unknown sampler '{}'", s)),
+    }
+  }
+}
+
+impl fmt::Display for SamplerType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      SamplerType::Independent => "independent",
+      SamplerType::Stratified => "stratified",
+      SamplerType::Halton => "halton",
+      SamplerType::Sobol => "sobol",
+      SamplerType::BlueNoise => "bluenoise",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// Random state for a single camera sample. It is threaded through `get_ray`, `hit` and
+/// `scatter` so every random number a path consumes comes from one seeded stream.
+///
+/// Dimensions are consumed in a fixed order: pixel, wavelength when rendering spectrally,
+/// then bsdf & light per bounce. A camera with a lens or shutter would take `lens_2d` and
+/// `time_1d` right after the pixel.
+/// Anything that needs an unbounded amount of randomness uses `next_f32` instead.
+pub struct SamplerContext {
+  pub seed: u64,
+  /// Work done with this context since the counters were last taken
+  pub stats: RenderStats,
+  sampler: Box<dyn Sampler>,
+  rng: Pcg32,
+  /// The current path's wavelengths, when rendering spectrally
+  pub wavelengths: Option<Wavelengths>,
+}
+
+impl SamplerContext {
+  pub fn new(seed: u64) -> Self {
+    Self::with_sampler(seed, SamplerType::Independent, 1)
+  }
+
+  pub fn with_sampler(seed: u64, sampler: SamplerType, spp: u32) -> Self {
+    SamplerContext {
+      seed,
+      stats: RenderStats::default(),
+      sampler: sampler.create(seed, spp),
+      rng: Pcg32::new(mix_seed(seed, 0, 0), 0),
+      wavelengths: None,
+    }
+  }
+
+  /// Reseed the stream for sample `sample_index` of pixel (x, y).
+  /// The stream only depends on the global seed and these indices, never on which thread
+  /// (or in what order) the sample is rendered, so renders are bit-identical run to run.
+  pub fn start_pixel_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+    let pixel = (u64::from(y) << 32) | u64::from(x);
+    self.rng = Pcg32::new(mix_seed(self.seed, pixel, u64::from(sample_index)), pixel);
+    self.sampler.start_pixel_sample(x, y, sample_index);
+  }
+
+  /// Uniform f32 in [0, 1) from the independent stream
+  pub fn next_f32(&mut self) -> f32 {
+    self.rng.gen()
+  }
+
+  /// Access the underlying generator, e.g. for rng.gen::<MaterialType>()
+  pub fn rng(&mut self) -> &mut Pcg32 {
+    &mut self.rng
+  }
+
+  /// Sub-pixel offset of the camera ray
+  pub fn pixel_2d(&mut self) -> (f32, f32) {
+    self.sampler.get_2d()
+  }
+
+  /// Point on the camera lens
+  pub fn lens_2d(&mut self) -> (f32, f32) {
+    self.sampler.get_2d()
+  }
+
+  /// Time within the shutter interval
+  pub fn time_1d(&mut self) -> f32 {
+    self.sampler.get_1d()
+  }
+
+  /// Hero wavelength of a spectral path
+  pub fn wavelength_1d(&mut self) -> f32 {
+    self.sampler.get_1d()
+  }
+
+  /// Lobe selection at a scattering event
+  pub fn bsdf_1d(&mut self) -> f32 {
+    self.sampler.get_1d()
+  }
+
+  /// Direction sample at a scattering event
+  pub fn bsdf_2d(&mut self) -> (f32, f32) {
+    self.sampler.get_2d()
+  }
+
+  /// Which light to sample for direct lighting
+  pub fn light_1d(&mut self) -> f32 {
+    self.sampler.get_1d()
+  }
+
+  /// Point on a light for direct lighting
+  pub fn light_2d(&mut self) -> (f32, f32) {
+    self.sampler.get_2d()
+  }
+}
+
+// SplitMix64 finalizer. Mixes the seed & indices so neighbouring pixels get unrelated streams.
+fn splitmix64(mut z: u64) -> u64 {
+  z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+  z ^ (z >> 31)
+}
+
+/// Combine a global seed with a pixel & sample index into a single well-mixed seed
+pub fn mix_seed(seed: u64, pixel: u64, sample: u64) -> u64 {
+  splitmix64(splitmix64(splitmix64(seed) ^ pixel) ^ sample)
+}
+
+// =================================================================================
+/// INDEPENDENT SAMPLER
+/// Plain uniform random numbers, the baseline every other sampler is compared against
+pub struct IndependentSampler {
+  seed: u64,
+  rng: Pcg32,
+}
+
+impl IndependentSampler {
+  pub fn new(seed: u64) -> Self {
+    IndependentSampler {
+      seed,
+      rng: Pcg32::new(mix_seed(seed, 0, 0), 0),
+    }
+  }
+}
+
+impl Sampler for IndependentSampler {
+  fn start_pixel_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+    let pixel = (u64::from(y) << 32) | u64::from(x);
+    // Different stream to SamplerContext's rng so the two aren't correlated
+    self.rng = Pcg32::new(mix_seed(!self.seed, pixel, u64::from(sample_index)), !pixel);
+  }
+
+  fn get_1d(&mut self) -> f32 {
+    self.rng.gen()
+  }
+
+  fn get_2d(&mut self) -> (f32, f32) {
+    (self.rng.gen(), self.rng.gen())
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// STRATIFIED SAMPLER
+/// Jittered strata: each of the `spp` samples lands in its own stratum, in a per pixel
+/// and per dimension shuffled order so dimensions aren't correlated with each other.
+pub struct StratifiedSampler {
+  seed: u64,
+  spp: u32,
+  /// 2D strata across & down, with exactly spp cells
+  grid: (u32, u32),
+  pixel: (u32, u32),
+  sample_index: u32,
+  dimension: u32,
+  rng: Pcg32,
+}
+
+impl StratifiedSampler {
+  pub fn new(seed: u64, spp: u32) -> Self {
+    let spp = spp.max(1);
+    StratifiedSampler {
+      seed,
+      spp,
+      grid: stratum_grid(spp),
+      pixel: (0, 0),
+      sample_index: 0,
+      dimension: 0,
+      rng: Pcg32::new(mix_seed(seed, 0, 0), 0),
+    }
+  }
+
+  // Which stratum this sample falls in for the current dimension. Samples past spp
+  // start another round of strata with a fresh shuffle.
+  fn stratum(&mut self) -> u32 {
+    let round = self.sample_index / self.spp;
+    let hash = hash_pixel(self.seed, self.pixel.0, self.pixel.1, self.dimension ^ hash_u32(round));
+    self.dimension += 1;
+    permutation_element(self.sample_index % self.spp, self.spp, hash)
+  }
+}
+
+// The squarest grid of exactly `spp` cells, so every cell gets a sample. A grid with spare
+// cells would leave parts of the square unsampled, biasing the estimate.
+fn stratum_grid(spp: u32) -> (u32, u32) {
+  let ny = (1..=(spp as f32).sqrt() as u32).rev().find(|n| spp.is_multiple_of(*n)).unwrap_or(1);
+  (spp / ny, ny)
+}
+
+// A point `jitter` of the way across stratum `stratum` of `n`. Clamped, as the top of the
+// last stratum can round up to 1 in f32.
+fn jittered(stratum: u32, jitter: f32, n: u32) -> f32 {
+  ((stratum as f32 + jitter) / n as f32).min(ONE_MINUS_EPSILON)
+}
+
+impl Sampler for StratifiedSampler {
+  fn start_pixel_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+    self.pixel = (x, y);
+    self.sample_index = sample_index;
+    self.dimension = 0;
+    let pixel = (u64::from(y) << 32) | u64::from(x);
+    self.rng = Pcg32::new(mix_seed(!self.seed, pixel, u64::from(sample_index)), !pixel);
+  }
+
+  fn get_1d(&mut self) -> f32 {
+    let stratum = self.stratum();
+    let jitter: f32 = self.rng.gen();
+    jittered(stratum, jitter, self.spp)
+  }
+
+  fn get_2d(&mut self) -> (f32, f32) {
+    let (nx, ny) = self.grid;
+    let stratum = self.stratum();
+    let (jx, jy): (f32, f32) = (self.rng.gen(), self.rng.gen());
+    (jittered(stratum % nx, jx, nx), jittered(stratum / nx, jy, ny))
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// HALTON SAMPLER
+/// Each dimension is the radical inverse of the sample index in its own prime base, Owen
+/// scrambled with a per pixel seed. Dimensions beyond the prime table fall back to random.
+pub struct HaltonSampler {
+  seed: u64,
+  pixel: (u32, u32),
+  sample_index: u32,
+  dimension: u32,
+  rng: Pcg32,
+}
+
+impl HaltonSampler {
+  pub fn new(seed: u64) -> Self {
+    HaltonSampler {
+      seed,
+      pixel: (0, 0),
+      sample_index: 0,
+      dimension: 0,
+      rng: Pcg32::new(mix_seed(seed, 0, 0), 0),
+    }
+  }
+
+  fn sample_dimension(&mut self) -> f32 {
+    let dimension = self.dimension;
+    self.dimension += 1;
+    if (dimension as usize) < PRIMES.len() {
+      let hash = hash_pixel(self.seed, self.pixel.0, self.pixel.1, dimension);
+      owen_scrambled_radical_inverse(PRIMES[dimension as usize], u64::from(self.sample_index), hash)
+    } else {
+      self.rng.gen()
+    }
+  }
+}
+
+impl Sampler for HaltonSampler {
+  fn start_pixel_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+    self.pixel = (x, y);
+    self.sample_index = sample_index;
+    self.dimension = 0;
+    let pixel = (u64::from(y) << 32) | u64::from(x);
+    self.rng = Pcg32::new(mix_seed(!self.seed, pixel, u64::from(sample_index)), !pixel);
+  }
+
+  fn get_1d(&mut self) -> f32 {
+    self.sample_dimension()
+  }
+
+  fn get_2d(&mut self) -> (f32, f32) {
+    (self.sample_dimension(), self.sample_dimension())
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// SOBOL SAMPLER
+/// Every 2D request is an Owen scrambled (0,2) Sobol sequence, so any power of two prefix
+/// is stratified in every elementary interval. Each request shuffles the sample index with
+/// its own seed (Burley 2020), which decorrelates the dimensions from each other.
+pub struct SobolSampler {
+  seed: u64,
+  pixel: (u32, u32),
+  sample_index: u32,
+  dimension: u32,
+}
+
+impl SobolSampler {
+  pub fn new(seed: u64) -> Self {
+    SobolSampler {
+      seed,
+      pixel: (0, 0),
+      sample_index: 0,
+      dimension: 0,
+    }
+  }
+}
+
+/// One scrambled (0,2) point. `hash` seeds the index shuffle and both scrambles.
+fn shuffled_sobol_02(sample_index: u32, hash: u32) -> (u32, u32) {
+  let index = nested_uniform_scramble(sample_index, hash);
+  (nested_uniform_scramble(sobol_02_x(index), hash_u32(hash ^ 0x5bd1_e995)),
+   nested_uniform_scramble(sobol_02_y(index), hash_u32(hash ^ 0x1b87_3593)))
+}
+
+impl Sampler for SobolSampler {
+  fn start_pixel_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+    self.pixel = (x, y);
+    self.sample_index = sample_index;
+    self.dimension = 0;
+  }
+
+  fn get_1d(&mut self) -> f32 {
+    let hash = hash_pixel(self.seed, self.pixel.0, self.pixel.1, self.dimension);
+    self.dimension += 1;
+    u32_to_unit(shuffled_sobol_02(self.sample_index, hash).0)
+  }
+
+  fn get_2d(&mut self) -> (f32, f32) {
+    let hash = hash_pixel(self.seed, self.pixel.0, self.pixel.1, self.dimension);
+    self.dimension += 1;
+    let (x, y) = shuffled_sobol_02(self.sample_index, hash);
+    (u32_to_unit(x), u32_to_unit(y))
+  }
+}
+// =================================================================================
+
+// =================================================================================
+/// BLUE NOISE SAMPLER
+/// The same scrambled Sobol sequence in every pixel, Cranley-Patterson rotated by a blue
+/// noise mask. Neighbouring pixels get very different offsets, so at low sample counts the
+/// error is pushed to high frequencies where it reads as fine grain rather than blotches.
+pub struct BlueNoiseSampler {
+  seed: u64,
+  pixel: (u32, u32),
+  sample_index: u32,
+  dimension: u32,
+}
+
+impl BlueNoiseSampler {
+  pub fn new(seed: u64) -> Self {
+    // Build the mask up front rather than inside the first pixel
+    blue_noise_mask();
+    BlueNoiseSampler {
+      seed,
+      pixel: (0, 0),
+      sample_index: 0,
+      dimension: 0,
+    }
+  }
+
+  // Mask value for the current pixel, toroidally shifted by a per dimension offset
+  fn dither(&self, hash: u32) -> f32 {
+    let size = BLUE_NOISE_SIZE as u32;
+    let x = (self.pixel.0 + (hash & 0xffff)) % size;
+    let y = (self.pixel.1 + (hash >> 16)) % size;
+    blue_noise_mask()[(y * size + x) as usize]
+  }
+
+  fn rotate(&self, value: u32, hash: u32) -> f32 {
+    let v = u32_to_unit(value) + self.dither(hash);
+    (v - v.floor()).min(ONE_MINUS_EPSILON)
+  }
+}
+
+impl Sampler for BlueNoiseSampler {
+  fn start_pixel_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+    self.pixel = (x, y);
+    self.sample_index = sample_index;
+    self.dimension = 0;
+  }
+
+  fn get_1d(&mut self) -> f32 {
+    // Sequence seed is shared by all pixels, only the dither offset varies per pixel
+    let hash = hash_pixel(self.seed, 0, 0, self.dimension);
+    self.dimension += 1;
+    let (x, _) = shuffled_sobol_02(self.sample_index, hash);
+    self.rotate(x, hash_u32(hash))
+  }
+
+  fn get_2d(&mut self) -> (f32, f32) {
+    let hash = hash_pixel(self.seed, 0, 0, self.dimension);
+    self.dimension += 1;
+    let (x, y) = shuffled_sobol_02(self.sample_index, hash);
+    (self.rotate(x, hash_u32(hash)), self.rotate(y, hash_u32(!hash)))
+  }
+}
+// =================================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Estimate of the integral of `f` over the unit square in each of `pixels` pixels, from
+  // `spp` samples of the first 2D dimension. The pixels fill rows as wide as the blue noise
+  // mask, whose offsets only average out over the whole tile.
+  fn estimates(sampler: SamplerType, spp: u32, pixels: u32, f: impl Fn(f32, f32) -> f32) -> Vec<f64> {
+    let mut sampler = sampler.create(7, spp);
+    (0..pixels).map(|pixel| {
+      let sum: f64 = (0..spp).map(|s| {
+        sampler.start_pixel_sample(pixel % BLUE_NOISE_SIZE as u32, pixel / BLUE_NOISE_SIZE as u32, s);
+        let (x, y) = sampler.get_2d();
+        f64::from(f(x, y))
+      }).sum();
+      sum / f64::from(spp)
+    }).collect()
+  }
+
+  fn rmse(estimates: &[f64], expected: f64) -> f64 {
+    (estimates.iter().map(|e| (e - expected).powi(2)).sum::<f64>() / estimates.len() as f64).sqrt()
+  }
+
+  #[test]
+  fn samples_are_in_the_unit_interval() {
+    for &sampler_type in SamplerType::ALL.iter() {
+      let mut sampler = sampler_type.create(1, 16);
+      for pixel in 0..8 {
+        for s in 0..40 {
+          sampler.start_pixel_sample(pixel, pixel * 3, s);
+          for _ in 0..8 {
+            let v = sampler.get_1d();
+            let (x, y) = sampler.get_2d();
+            for value in [v, x, y].iter() {
+              assert!((0.0..1.0).contains(value), "{} gave {}", sampler_type, value);
+            }
+          }
+        }
+      }
+    }
+    // The largest jitter the RNG gives, in the last stratum, rounds up to 1 unless clamped
+    for n in 1..=64 {
+      let top = jittered(n - 1, ONE_MINUS_EPSILON, n);
+      assert!(top < 1.0, "top of {} strata gave {}", n, top);
+    }
+  }
+
+  #[test]
+  fn stratified_puts_one_sample_in_every_stratum() {
+    for spp in 1..=17 {
+      let (nx, ny) = stratum_grid(spp);
+      assert_eq!(nx * ny, spp);
+      let mut sampler = StratifiedSampler::new(3, spp);
+      let mut cells_2d = vec![0; spp as usize];
+      let mut cells_1d = vec![0; spp as usize];
+      for s in 0..spp {
+        sampler.start_pixel_sample(5, 9, s);
+        let v = sampler.get_1d();
+        let (x, y) = sampler.get_2d();
+        cells_1d[(v * spp as f32) as usize] += 1;
+        cells_2d[(y * ny as f32) as usize * nx as usize + (x * nx as f32) as usize] += 1;
+      }
+      assert!(cells_1d.iter().all(|&c| c == 1), "1D strata at {} spp: {:?}", spp, cells_1d);
+      assert!(cells_2d.iter().all(|&c| c == 1), "2D strata at {} spp: {:?}", spp, cells_2d);
+    }
+  }
+
+  #[test]
+  fn every_sampler_is_unbiased() {
+    // x² + y² averages 2/3 over the square
+    for &sampler in SamplerType::ALL.iter() {
+      for &spp in [1, 3, 4, 10].iter() {
+        let estimates = estimates(sampler, spp, 4096, |x, y| x * x + y * y);
+        let mean = estimates.iter().sum::<f64>() / estimates.len() as f64;
+        assert!((mean - 2.0 / 3.0).abs() < 0.01, "{} at {} spp averages {}", sampler, spp, mean);
+      }
+    }
+  }
+
+  #[test]
+  fn every_sampler_converges() {
+    // A quarter disk, whose edge is what samplers find hard; its area is π/4
+    let disk = |x: f32, y: f32| if x * x + y * y < 1.0 { 1.0 } else { 0.0 };
+    let expected = std::f64::consts::PI / 4.0;
+    for &sampler in SamplerType::ALL.iter() {
+      let errors: Vec<f64> = [4, 16, 64, 256].iter()
+        .map(|&spp| rmse(&estimates(sampler, spp, 256, disk), expected))
+        .collect();
+      for pair in errors.windows(2) {
+        assert!(pair[1] < pair[0], "{} error went from {} up to {}", sampler, pair[0], pair[1]);
+      }
+      // Independent samples should at least halve the error for every 4x the samples
+      assert!(errors[3] < errors[0] / 4.0, "{} errors {:?}", sampler, errors);
+    }
+  }
+}
@@ -0,0 +1,204 @@
+//! Scene module contains the Scene that gets rendered and the functions that build one
+use rand::Rng;
+use std::sync::Arc;
+
+use crate::camera::*;
+use crate::envmap::*;
+use crate::hitable::*;
+use crate::light::*;
+use crate::manylights::*;
+use crate::material::*;
+use crate::normalmap::*;
+use crate::sampler::*;
+use crate::sky::*;
+use crate::spectrum::*;
+use crate::sphere::*;
+use crate::vec3::*;
+
+/// Names of an object in the world and of its material, for ID mattes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+  pub name: String,
+  pub material: String,
+}
+
+impl From<(&str, &str)> for ObjectInfo {
+  fn from(tuple: (&str, &str)) -> Self {
+    ObjectInfo {
+      name: tuple.0.to_string(),
+      material: tuple.1.to_string(),
+    }
+  }
+}
+
+/// What the command line can change about `Scene::random_spheres`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SceneOptions {
+  /// Fraction of the small spheres that are lights instead
+  pub emissive: f32,
+  /// Replaces the big metal sphere's material
+  pub conductor: Option<ConductorSettings>,
+  /// Replaces the big metal sphere's material, when there's no conductor
+  pub principled: Option<Principled>,
+  /// Replaces the big glass sphere's material
+  pub glass: Option<MaterialType>,
+  /// Replaces the big diffuse sphere's material
+  pub diffuse: Option<MaterialType>,
+  /// Goes on all three big spheres
+  pub normal_map: Option<NormalMap>,
+}
+
+/// Everything needed to render an image: what's in the world & where it's seen from
+pub struct Scene {
+  pub world: Arc<HitableList>,
+  pub camera: Camera,
+  /// Names for each entry of `world.list`, in the same order
+  pub objects: Vec<ObjectInfo>,
+  /// Lights found by shadow rays rather than by scattered rays hitting them
+  pub lights: Vec<Light>,
+  /// Daylight to use as the background instead of the gradient
+  pub sky: Option<Sky>,
+  /// Image of the surroundings to use as the background, sampled as a light
+  pub environment: Option<EnvironmentMap>,
+  /// Constant background color, used instead of the gradient
+  pub background: Option<Vec3>,
+  /// The emissive spheres in the world, in object order
+  pub area_lights: Vec<SphereLight>,
+  /// Chooses which of `area_lights` a shading point samples
+  pub light_selector: LightSelector,
+  /// Render spectrally, turning RGB into spectra with these
+  pub spectra: Option<RgbSpectra>,
+}
+
+impl Scene {
+  /// The book's final scene, a field of small random spheres around three big ones, with
+  /// the `options` changes
+  pub fn random_spheres(seed: u64, aspect: f32, options: &SceneOptions) -> Self {
+    let SceneOptions { emissive, conductor, principled, glass, diffuse, normal_map } = options.clone();
+    // The scene layout is drawn from its own stream so the same seed gives the same scene
+    let mut scene_ctx = SamplerContext::new(seed);
+    // And which spheres glow from another, so turning them on doesn't move anything
+    let mut light_ctx = SamplerContext::new(seed);
+    light_ctx.start_pixel_sample(0, 0, 1);
+
+    // List of Hitable things
+    let mut list: Vec<Arc<dyn Hitable + Send + Sync>> = Vec::new();
+    let mut objects = Vec::new();
+    let mut area_lights = Vec::new();
+    // Large sphere everything is sitting on (like EARF)
+    list.push(Arc::new(
+      Sphere::from((Vec3::from((0.0, -1000.0, 0.0)),
+                    1000.0,
+                    MaterialType::Lambertian(Lambertian::from((0.5, 0.5, 0.5)))))
+    ));
+    objects.push(ObjectInfo::from(("ground", "ground_gray")));
+
+    // Throw a ton more spheres in the world randomly
+    for a in -11..11 {
+      for b in -11..11 {
+        let mut material = scene_ctx.rng().gen::<MaterialType>();
+        let center = Vec3::from(((a as f32) + 0.9 * scene_ctx.next_f32(), 0.2, (b as f32) + 0.9 * scene_ctx.next_f32()));
+        if (center - Vec3::from((4.0, 0.2, 0.0))).length() > 0.9 {
+          if emissive > 0.0 && light_ctx.next_f32() < emissive {
+            let color = Vec3::from((light_ctx.next_f32(), light_ctx.next_f32(), light_ctx.next_f32()));
+            let radiance = 4.0 * (Vec3::from([0.2; 3]) + 0.8 * color);
+            material = MaterialType::DiffuseLight(DiffuseLight::from(radiance));
+            area_lights.push(SphereLight { center, radius: 0.2, radiance, object_id: list.len() as u32 });
+          }
+          let kind = match material {
+            MaterialType::Dielectric(_) => "glass",
+            MaterialType::Lambertian(_) => "diffuse",
+            MaterialType::Metal(_) => "metal",
+            MaterialType::DiffuseLight(_) => "emissive",
+            MaterialType::Conductor(_) => "conductor",
+            MaterialType::RoughDielectric(_) => "frosted_glass",
+            MaterialType::Principled(_) => "principled",
+            MaterialType::Mix(_) => "mix",
+            MaterialType::Layered(_) => "layered",
+          };
+          list.push(Arc::new(
+            Sphere::from((center,
+                          0.2,
+                          material))
+          ));
+          // Every small sphere has its own random material
+          let name = format!("small_sphere_{}_{}", a, b);
+          objects.push(ObjectInfo::from((name.as_str(), format!("{}_{}_{}", kind, a, b).as_str())));
+        }
+      }
+    }
+
+    let glass = glass.unwrap_or(MaterialType::Dielectric(Dielectric::from(1.5)));
+    let glass_name = match glass {
+      MaterialType::RoughDielectric(_) => "frosted_glass",
+      MaterialType::Dielectric(d) if d.thin => "thin_glass",
+      MaterialType::Dielectric(d) if d.dispersion.is_some() => "dispersive_glass",
+      MaterialType::Dielectric(d) if d.absorption != Vec3::new() => "tinted_glass",
+      _ => "clear_glass",
+    };
+    list.push(Arc::new(Sphere {
+      normal_map,
+      ..Sphere::from((Vec3::from((0.0, 1.0, 0.0)),
+                      1.0,
+                      glass))
+    }));
+    objects.push(ObjectInfo::from(("glass_sphere", glass_name)));
+    let diffuse = diffuse.unwrap_or(MaterialType::Lambertian(Lambertian::from((0.4, 0.2, 0.1))));
+    let diffuse_name = match &diffuse {
+      MaterialType::Layered(l) if matches!(*l.base, MaterialType::Mix(_)) => "varnished_brown_bronze",
+      MaterialType::Layered(_) => "varnished_brown",
+      MaterialType::Mix(_) => "brown_bronze",
+      _ => "brown_diffuse",
+    };
+    list.push(Arc::new(Sphere {
+      normal_map,
+      ..Sphere::from((Vec3::from((-4.0, 1.0, 0.0)),
+                      1.0,
+                      diffuse))
+    }));
+    objects.push(ObjectInfo::from(("diffuse_sphere", diffuse_name)));
+    let (metal, metal_name) = match (conductor, principled) {
+      (Some(settings), _) => (MaterialType::Conductor(Conductor::from(settings)), format!("rough_{}", settings.preset)),
+      (None, Some(principled)) => (MaterialType::Principled(principled), "principled".to_string()),
+      (None, None) => (MaterialType::Metal(Metal::from(((0.7, 0.6, 0.5), 0.0))), "polished_bronze".to_string()),
+    };
+    list.push(Arc::new(Sphere {
+      normal_map,
+      ..Sphere::from((Vec3::from((4.0, 1.0, 0.0)),
+                      1.0,
+                      metal))
+    }));
+    objects.push(ObjectInfo::from(("metal_sphere", metal_name.as_str())));
+
+    let world = Arc::new(HitableList::new(list));
+    let camera = Camera::new(
+          Vec3::from((7.0, 1.5, 1.95)),
+          Vec3::from((0.0, 0.0, -1.0)),
+          Vec3::from((0.0, 1.0, 0.0)),
+          50.0,
+          aspect);
+
+    Scene {
+      world,
+      camera,
+      objects,
+      lights: Vec::new(),
+      sky: None,
+      environment: None,
+      background: None,
+      light_selector: LightSelector::new(LightSamplerType::Bvh, &area_lights),
+      spectra: None,
+      area_lights,
+    }
+  }
+
+  /// Index in `area_lights` of the object with the given index in the world, if it's one
+  pub fn area_light(&self, object_id: u32) -> Option<usize> {
+    self.area_lights.binary_search_by_key(&object_id, |light| light.object_id).ok()
+  }
+
+  /// Names of the object with the given index in the world
+  pub fn object(&self, object_id: u32) -> &ObjectInfo {
+    &self.objects[object_id as usize]
+  }
+}
@@ -0,0 +1,648 @@
+//! Settings module parses the command line into RenderSettings
+use crate::aov::*;
+use crate::denoise::*;
+use crate::film::*;
+use crate::lpe::*;
+use crate::manylights::*;
+use crate::filter::*;
+use crate::light::*;
+use crate::material::*;
+use crate::normalmap::*;
+use crate::sampler::*;
+use crate::scene::*;
+use crate::sky::*;
+use crate::spectrum::*;
+use crate::texture::*;
+use crate::tile::*;
+use crate::vec3::*;
+
+// 2560 x 1440 on 6 cores, in release, is ~14minutes
+//const RES_X: u32 = 2560;
+//const RES_Y: u32 = 1440;
+
+// 1000 x 500 on 6 cores, in release, is ~1min 10s
+pub const RES_X: u32 = 1000;
+pub const RES_Y: u32 = 500;
+
+pub const NUM_SAMPLES: u32 = 10;
+
+/// Region of the image to render
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CropWindow {
+  /// x0, y0, x1, y1 in pixels, the max edges exclusive
+  Pixels(u32, u32, u32, u32),
+  /// x0, y0, x1, y1 as fractions of the image width & height
+  Normalized(f32, f32, f32, f32),
+}
+
+/// Everything about a render that can be changed from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderSettings {
+  /// Global seed for the scene layout and every sample's random stream
+  pub seed: u64,
+  pub width: u32,
+  pub height: u32,
+  /// Samples per pixel
+  pub spp: u32,
+  pub sampler: SamplerType,
+  /// Pixel reconstruction filter, and its radius in pixels if not the filter's default
+  pub filter: FilterType,
+  pub filter_radius: Option<f32>,
+  /// Only render this part of the image. The output is just that region unless
+  /// `crop_full_frame` is set, in which case it's the full image with the rest left black.
+  pub crop: Option<CropWindow>,
+  pub crop_full_frame: bool,
+  /// Instead of an image, print every bounce of every sample of this pixel
+  pub debug_pixel: Option<(u32, u32)>,
+  /// Tiles are `tile_size` pixels square and scheduled in `tile_order`
+  pub tile_size: u32,
+  pub tile_order: TileOrder,
+  /// Keep sampling pixels whose relative error is above `adaptive_threshold`,
+  /// up to `max_spp` samples
+  pub adaptive: bool,
+  pub adaptive_threshold: f32,
+  pub max_spp: u32,
+  /// Render in passes of `pass_spp` until `time_budget` seconds pass or `target_spp`
+  /// is reached, writing `progress_image` every `progress_interval` seconds
+  pub progressive: bool,
+  pub pass_spp: u32,
+  pub time_budget: Option<f64>,
+  pub target_spp: Option<u32>,
+  pub progress_image: Option<String>,
+  pub progress_interval: f64,
+  /// Periodically save the render to `checkpoint`, and with `resume` continue from it
+  pub checkpoint: Option<String>,
+  pub checkpoint_interval: f64,
+  pub resume: bool,
+  /// Draw a progress bar on stderr, when stderr is a terminal
+  pub show_progress: bool,
+  /// Where to write an image of the number of samples each pixel took
+  pub sample_count_image: Option<String>,
+  /// Extra layers to render, written as `<aov_prefix><name>.pfm` and/or into the `exr`
+  pub aovs: Vec<AovType>,
+  pub aov_prefix: Option<String>,
+  /// Where to write a multi-layer EXR of the beauty image and the AOVs
+  pub exr: Option<String>,
+  /// Light path expression buffers to split the image into. Paths matching none of them go
+  /// to an extra `other` buffer.
+  pub lpe: Vec<LightPathExpression>,
+  /// Write Cryptomatte object & material mattes into the `exr`, with this many ranks
+  pub cryptomatte: Option<u32>,
+  /// Point, spot and distant lights to add to the scene
+  pub lights: Vec<Light>,
+  /// Light the scene with a daylight sky and its sun instead of the gradient
+  pub sky: Option<SkySettings>,
+  /// Fraction of the small spheres that are lights
+  pub emissive: f32,
+  /// How shading points choose which emissive sphere to sample
+  pub light_sampler: LightSamplerType,
+  /// Constant background color instead of the gradient
+  pub background: Option<Vec3>,
+  /// Make the big metal sphere a rough GGX conductor
+  pub conductor: Option<ConductorSettings>,
+  /// Make the big glass sphere frosted glass this rough
+  pub glass_roughness: Option<f32>,
+  /// Tint the big glass sphere to this color after `glass_distance` inside it
+  pub glass_color: Option<Vec3>,
+  pub glass_distance: f32,
+  /// Make the big glass sphere a thin-walled bubble
+  pub thin_glass: bool,
+  /// Make the big glass sphere's index of refraction depend on wavelength
+  pub glass_dispersion: Option<Dispersion>,
+  /// Trace a few wavelengths per path instead of RGB
+  pub spectral: bool,
+  /// Make the big metal sphere this principled material
+  pub principled: Option<Principled>,
+  /// Mix polished bronze into the big diffuse sphere, this much of it
+  pub mix: Option<Texture>,
+  /// Coat the big diffuse sphere with varnish of this index of refraction & roughness
+  pub varnish: Option<(f32, f32)>,
+  /// Tint the varnish to this color, seen straight on
+  pub varnish_color: Option<Vec3>,
+  /// Bend the big spheres' shading normals by this
+  pub normal_map: Option<NormalMap>,
+  /// Light the scene with a latitude-longitude .hdr or .pfm image instead of the gradient
+  pub env_map: Option<String>,
+  /// Multiplier on the environment map's values
+  pub env_intensity: f32,
+  /// Degrees to turn the environment map around the y axis
+  pub env_rotation: f32,
+  /// Denoise the image before writing it, guided by the AOVs
+  pub denoise: Option<DenoiseSettings>,
+  /// Print render statistics to stderr, and/or write them to `stats_json` as JSON
+  pub stats: bool,
+  pub stats_json: Option<String>,
+  /// Instead of an image, print how long each render scheme takes
+  pub bench: bool,
+  /// Instead of an image, print how much light the glass materials keep under uniform light
+  pub furnace: bool,
+}
+
+impl Default for RenderSettings {
+  fn default() -> Self {
+    RenderSettings {
+      seed: 0,
+      width: RES_X,
+      height: RES_Y,
+      spp: NUM_SAMPLES,
+      sampler: SamplerType::Independent,
+      filter: FilterType::Box,
+      filter_radius: None,
+      crop: None,
+      crop_full_frame: false,
+      debug_pixel: None,
+      tile_size: 32,
+      tile_order: TileOrder::Hilbert,
+      adaptive: false,
+      adaptive_threshold: 0.05,
+      max_spp: 256,
+      progressive: false,
+      pass_spp: 1,
+      time_budget: None,
+      target_spp: None,
+      progress_image: None,
+      progress_interval: 10.0,
+      checkpoint: None,
+      checkpoint_interval: 60.0,
+      resume: false,
+      show_progress: true,
+      sample_count_image: None,
+      aovs: Vec::new(),
+      aov_prefix: None,
+      exr: None,
+      lpe: Vec::new(),
+      cryptomatte: None,
+      lights: Vec::new(),
+      sky: None,
+      emissive: 0.0,
+      light_sampler: LightSamplerType::Bvh,
+      conductor: None,
+      glass_roughness: None,
+      glass_color: None,
+      glass_distance: 1.0,
+      thin_glass: false,
+      glass_dispersion: None,
+      spectral: false,
+      principled: None,
+      mix: None,
+      varnish: None,
+      varnish_color: None,
+      normal_map: None,
+      background: None,
+      env_map: None,
+      env_intensity: 1.0,
+      env_rotation: 0.0,
+      denoise: None,
+      stats: false,
+      stats_json: None,
+      bench: false,
+      furnace: false,
+    }
+  }
+}
+
+impl RenderSettings {
+  /// Parse settings from `std::env::args()`, skipping the program name
+  pub fn from_env() -> Result<Self, String> {
+    Self::from_args(std::env::args().skip(1))
+  }
+
+  pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+    let mut settings = RenderSettings::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--seed" => settings.seed = parse_value(&arg, args.next())?,
+        "--size" => {
+          let (width, height) = parse_pair(&arg, args.next(), 'x')?;
+          settings.width = width;
+          settings.height = height;
+        },
+        "--spp" => settings.spp = parse_value(&arg, args.next())?,
+        "--sampler" => settings.sampler = parse_value(&arg, args.next())?,
+        "--filter" => settings.filter = parse_value(&arg, args.next())?,
+        "--filter-radius" => settings.filter_radius = Some(parse_value(&arg, args.next())?),
+        "--crop" => {
+          let v: Vec<u32> = parse_list(&arg, args.next(), 4)?;
+          settings.crop = Some(CropWindow::Pixels(v[0], v[1], v[2], v[3]));
+        },
+        "--crop-normalized" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 4)?;
+          settings.crop = Some(CropWindow::Normalized(v[0], v[1], v[2], v[3]));
+        },
+        "--crop-full-frame" => settings.crop_full_frame = true,
+        "--debug-pixel" => settings.debug_pixel = Some(parse_pair(&arg, args.next(), ',')?),
+        "--tile-size" => settings.tile_size = parse_value(&arg, args.next())?,
+        "--tile-order" => settings.tile_order = parse_value(&arg, args.next())?,
+        "--adaptive" => settings.adaptive = true,
+        "--adaptive-threshold" => settings.adaptive_threshold = parse_value(&arg, args.next())?,
+        "--max-spp" => settings.max_spp = parse_value(&arg, args.next())?,
+        "--progressive" => settings.progressive = true,
+        "--pass-spp" => settings.pass_spp = parse_value(&arg, args.next())?,
+        "--time-budget" => settings.time_budget = Some(parse_value(&arg, args.next())?),
+        "--target-spp" => settings.target_spp = Some(parse_value(&arg, args.next())?),
+        "--progress-image" => settings.progress_image = Some(parse_value(&arg, args.next())?),
+        "--progress-interval" => settings.progress_interval = parse_value(&arg, args.next())?,
+        "--checkpoint" => settings.checkpoint = Some(parse_value(&arg, args.next())?),
+        "--checkpoint-interval" => settings.checkpoint_interval = parse_value(&arg, args.next())?,
+        "--resume" => settings.resume = true,
+        "--no-progress" => settings.show_progress = false,
+        "--sample-count-image" => settings.sample_count_image = Some(parse_value(&arg, args.next())?),
+        "--aov" => {
+          let value: String = parse_value(&arg, args.next())?;
+          settings.aovs = if value == "all" {
+            AovType::ALL.to_vec()
+          } else {
+            value.split(',').map(|name| parse_value(&arg, Some(name.to_string())))
+              .collect::<Result<_, _>>()?
+          };
+        },
+        "--aov-prefix" => settings.aov_prefix = Some(parse_value(&arg, args.next())?),
+        "--exr" => settings.exr = Some(parse_value(&arg, args.next())?),
+        "--lpe" => settings.lpe.extend(LightPathExpression::defaults()),
+        "--lpe-expr" => settings.lpe.push(parse_value(&arg, args.next())?),
+        "--cryptomatte" => { settings.cryptomatte.get_or_insert(6); },
+        "--cryptomatte-depth" => settings.cryptomatte = Some(parse_value(&arg, args.next())?),
+        "--point-light" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 6)?;
+          settings.lights.push(Light::point(Vec3::from((v[0], v[1], v[2])), Vec3::from((v[3], v[4], v[5]))));
+        },
+        "--spot-light" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 11)?;
+          let (position, target) = (Vec3::from((v[0], v[1], v[2])), Vec3::from((v[3], v[4], v[5])));
+          if position == target {
+            return Err("--spot-light needs a target away from its position".to_string());
+          }
+          settings.lights.push(Light::spot(position, target, Vec3::from((v[6], v[7], v[8])), v[9], v[10]));
+        },
+        "--distant-light" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 6)?;
+          let direction = Vec3::from((v[0], v[1], v[2]));
+          if direction == Vec3::new() {
+            return Err("--distant-light needs a non-zero direction".to_string());
+          }
+          settings.lights.push(Light::distant(direction, Vec3::from((v[3], v[4], v[5]))));
+        },
+        "--sky" => { settings.sky.get_or_insert_with(SkySettings::default); },
+        "--sun-elevation" => settings.sky_settings().elevation = parse_value(&arg, args.next())?,
+        "--sun-azimuth" => settings.sky_settings().azimuth = parse_value(&arg, args.next())?,
+        "--turbidity" => settings.sky_settings().turbidity = parse_value(&arg, args.next())?,
+        "--sky-exposure" => settings.sky_settings().exposure = parse_value(&arg, args.next())?,
+        "--emissive" => settings.emissive = parse_value(&arg, args.next())?,
+        "--light-sampler" => settings.light_sampler = parse_value(&arg, args.next())?,
+        "--background" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 3)?;
+          settings.background = Some(Vec3::from((v[0], v[1], v[2])));
+        },
+        "--conductor" => settings.conductor_settings().preset = parse_value(&arg, args.next())?,
+        "--roughness" => settings.conductor_settings().roughness = parse_value(&arg, args.next())?,
+        "--anisotropy" => settings.conductor_settings().anisotropy = parse_value(&arg, args.next())?,
+        "--glass-roughness" => settings.glass_roughness = Some(parse_value(&arg, args.next())?),
+        "--glass-color" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 3)?;
+          settings.glass_color = Some(Vec3::from((v[0], v[1], v[2])));
+        },
+        "--glass-distance" => settings.glass_distance = parse_value(&arg, args.next())?,
+        "--thin-glass" => settings.thin_glass = true,
+        "--glass-dispersion" => settings.glass_dispersion = Some(parse_value(&arg, args.next())?),
+        "--spectral" => settings.spectral = true,
+        "--principled" => settings.principled = Some(parse_value(&arg, args.next())?),
+        "--mix" => settings.mix = Some(parse_value(&arg, args.next())?),
+        "--varnish" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 2)?;
+          settings.varnish = Some((v[0], v[1]));
+        },
+        "--varnish-color" => {
+          let v: Vec<f32> = parse_list(&arg, args.next(), 3)?;
+          settings.varnish_color = Some(Vec3::from((v[0], v[1], v[2])));
+        },
+        "--normal-map" => settings.normal_map = Some(parse_value(&arg, args.next())?),
+        "--env-map" => settings.env_map = Some(parse_value(&arg, args.next())?),
+        "--env-intensity" => settings.env_intensity = parse_value(&arg, args.next())?,
+        "--env-rotation" => settings.env_rotation = parse_value(&arg, args.next())?,
+        "--denoise" => { settings.denoise.get_or_insert_with(DenoiseSettings::default); },
+        "--denoise-iterations" => settings.denoise_settings().iterations = parse_value(&arg, args.next())?,
+        "--denoise-sigma-color" => settings.denoise_settings().sigma_color = parse_value(&arg, args.next())?,
+        "--denoise-sigma-normal" => settings.denoise_settings().sigma_normal = parse_value(&arg, args.next())?,
+        "--denoise-sigma-depth" => settings.denoise_settings().sigma_depth = parse_value(&arg, args.next())?,
+        "--denoise-sigma-albedo" => settings.denoise_settings().sigma_albedo = parse_value(&arg, args.next())?,
+        "--stats" => settings.stats = true,
+        "--stats-json" => settings.stats_json = Some(parse_value(&arg, args.next())?),
+        "--bench" => settings.bench = true,
+        "--furnace" => settings.furnace = true,
+        _ => return Err(format!("unknown argument '{}'", arg)),
+      }
+    }
+
+    if settings.width == 0 || settings.height == 0 || settings.spp == 0 || settings.tile_size == 0 {
+      return Err("--size, --spp and --tile-size must be non-zero".to_string());
+    }
+    let crop = settings.crop_bounds();
+    if crop.x0 >= crop.x1 || crop.y0 >= crop.y1 || crop.x1 > settings.width || crop.y1 > settings.height {
+      return Err(format!("crop window {:?} is empty or outside the {}x{} image",
+                         settings.crop.unwrap(), settings.width, settings.height));
+    }
+    if settings.debug_pixel.is_some_and(|(x, y)| x >= settings.width || y >= settings.height) {
+      return Err("--debug-pixel is outside the image".to_string());
+    }
+    if settings.pass_spp == 0 || settings.target_spp == Some(0) {
+      return Err("--pass-spp and --target-spp must be non-zero".to_string());
+    }
+    if settings.time_budget.is_some_and(|t| t <= 0.0) || settings.progress_interval <= 0.0 {
+      return Err("--time-budget and --progress-interval must be positive".to_string());
+    }
+    if settings.progressive && settings.adaptive {
+      return Err("--progressive and --adaptive can't be combined".to_string());
+    }
+    if settings.resume && settings.checkpoint.is_none() {
+      return Err("--resume needs a --checkpoint file to resume from".to_string());
+    }
+    if settings.checkpoint.is_some() && settings.adaptive {
+      return Err("--checkpoint can't be used with --adaptive".to_string());
+    }
+    if !settings.aovs.is_empty() && settings.aov_prefix.is_none() && settings.exr.is_none() {
+      return Err("--aov needs an --aov-prefix or --exr to write the layers to".to_string());
+    }
+    if !settings.lpe.is_empty() && settings.aov_prefix.is_none() && settings.exr.is_none() {
+      return Err("--lpe needs an --aov-prefix or --exr to write the buffers to".to_string());
+    }
+    if !settings.lpe.is_empty() && settings.checkpoint.is_some() {
+      return Err("--lpe can't be used with --checkpoint, which doesn't save the buffers".to_string());
+    }
+    if settings.cryptomatte.is_some() && settings.exr.is_none() {
+      return Err("--cryptomatte needs an --exr to write the mattes to".to_string());
+    }
+    if settings.cryptomatte == Some(0) {
+      return Err("--cryptomatte-depth must be non-zero".to_string());
+    }
+    if settings.sky.is_some_and(|sky| !(0.0..=90.0).contains(&sky.elevation)) {
+      return Err("--sun-elevation must be between 0 and 90 degrees".to_string());
+    }
+    if settings.sky.is_some_and(|sky| !(1.7..=10.0).contains(&sky.turbidity)) {
+      return Err("--turbidity must be between 1.7 and 10".to_string());
+    }
+    if settings.sky.is_some() && settings.env_map.is_some() {
+      return Err("--sky and --env-map can't be used together".to_string());
+    }
+    if settings.background.is_some() && (settings.sky.is_some() || settings.env_map.is_some()) {
+      return Err("--background can't be used with --sky or --env-map".to_string());
+    }
+    if !(0.0..=1.0).contains(&settings.emissive) {
+      return Err("--emissive must be between 0 and 1".to_string());
+    }
+    if settings.conductor.is_some_and(|c| !(0.0..=1.0).contains(&c.roughness) || !(0.0..=1.0).contains(&c.anisotropy)) {
+      return Err("--roughness and --anisotropy must be between 0 and 1".to_string());
+    }
+    if settings.conductor.is_some() && settings.principled.is_some() {
+      return Err("--conductor and --principled can't be used together".to_string());
+    }
+    if settings.glass_roughness.is_some_and(|r| !(0.0..=1.0).contains(&r)) {
+      return Err("--glass-roughness must be between 0 and 1".to_string());
+    }
+    if settings.glass_color.is_some_and(|c| [c.r(), c.g(), c.b()].iter().any(|v| *v <= 0.0 || *v > 1.0)) {
+      return Err("--glass-color values must be above 0 and at most 1".to_string());
+    }
+    if settings.glass_distance <= 0.0 {
+      return Err("--glass-distance must be positive".to_string());
+    }
+    if settings.thin_glass && settings.glass_color.is_some() {
+      // A thin wall has no inside for the light to be absorbed through
+      return Err("--thin-glass can't be used with --glass-color".to_string());
+    }
+    if (settings.thin_glass || settings.glass_dispersion.is_some()) && settings.glass_roughness.is_some() {
+      return Err("--thin-glass and --glass-dispersion can't be used with --glass-roughness".to_string());
+    }
+    if settings.glass_dispersion.is_some_and(|d| d.ior_d().is_nan() || d.ior_d() <= 1.0) {
+      return Err("--glass-dispersion must give an index of refraction above 1".to_string());
+    }
+    if settings.varnish.is_some_and(|(ior, roughness)| ior <= 1.0 || !(0.0..=1.0).contains(&roughness)) {
+      return Err("--varnish needs an index of refraction above 1 and a roughness between 0 and 1".to_string());
+    }
+    if settings.varnish_color.is_some_and(|c| [c.r(), c.g(), c.b()].iter().any(|v| *v <= 0.0 || *v > 1.0)) {
+      return Err("--varnish-color values must be above 0 and at most 1".to_string());
+    }
+    if settings.varnish_color.is_some() && settings.varnish.is_none() {
+      return Err("--varnish-color needs --varnish".to_string());
+    }
+    if settings.env_intensity < 0.0 {
+      return Err("--env-intensity can't be negative".to_string());
+    }
+    if settings.denoise.is_some_and(|d| d.sigma_color <= 0.0 || d.sigma_normal <= 0.0 || d.sigma_depth <= 0.0
+                                         || d.sigma_albedo <= 0.0) {
+      return Err("--denoise-sigma-color, -normal, -depth and -albedo must be positive".to_string());
+    }
+    if settings.filter_radius.is_some_and(|r| r <= 0.0) {
+      return Err("--filter-radius must be positive".to_string());
+    }
+    Ok(settings)
+  }
+
+  /// Samples per pixel a progressive render stops at. With only a time budget there's no
+  /// target, otherwise it's `--target-spp`, falling back to `--spp`.
+  pub fn progressive_target_spp(&self) -> Option<u32> {
+    match (self.target_spp, self.time_budget) {
+      (Some(target), _) => Some(target),
+      (None, Some(_)) => None,
+      (None, None) => Some(self.spp),
+    }
+  }
+
+  /// The spp a progressive render tells its sampler to expect
+  pub fn progressive_sampler_spp(&self) -> u32 {
+    self.progressive_target_spp().unwrap_or(self.pass_spp)
+  }
+
+  /// Sky settings, turning the sky on with defaults if it wasn't already
+  fn sky_settings(&mut self) -> &mut SkySettings {
+    self.sky.get_or_insert_with(SkySettings::default)
+  }
+
+  /// The changes the command line makes to the scene
+  pub fn scene_options(&self) -> SceneOptions {
+    SceneOptions {
+      emissive: self.emissive,
+      conductor: self.conductor,
+      principled: self.principled,
+      glass: self.glass_material(),
+      diffuse: self.diffuse_material(),
+      normal_map: self.normal_map,
+    }
+  }
+
+  /// The big glass sphere's material, if any of the glass options change it
+  pub fn glass_material(&self) -> Option<MaterialType> {
+    let absorption = self.glass_color.map_or(Vec3::new(), |c| Dielectric::absorption_for(c, self.glass_distance));
+    if let Some(roughness) = self.glass_roughness {
+      return Some(MaterialType::RoughDielectric(RoughDielectric { absorption, ..RoughDielectric::from((1.5, roughness)) }));
+    }
+    if self.glass_color.is_none() && !self.thin_glass && self.glass_dispersion.is_none() {
+      return None;
+    }
+    let ref_idx = self.glass_dispersion.map_or(1.5, |d| d.ior_d());
+    Some(MaterialType::Dielectric(Dielectric {
+      absorption,
+      thin: self.thin_glass,
+      dispersion: self.glass_dispersion,
+      ..Dielectric::from(ref_idx)
+    }))
+  }
+
+  /// The big diffuse sphere's material, if mixing or varnishing change it
+  pub fn diffuse_material(&self) -> Option<MaterialType> {
+    let brown = MaterialType::Lambertian(Lambertian::from((0.4, 0.2, 0.1)));
+    let bronze = MaterialType::Metal(Metal::from(((0.7, 0.6, 0.5), 0.0)));
+    let base = match self.mix {
+      Some(weight) => MaterialType::Mix(Mix::from((brown, bronze, weight))),
+      None => brown,
+    };
+    match self.varnish {
+      Some((ior, roughness)) => Some(MaterialType::Layered(Layered {
+        color: self.varnish_color.unwrap_or(Vec3::from([1.0; 3])),
+        ..Layered::from((base, ior, roughness))
+      })),
+      None if self.mix.is_some() => Some(base),
+      None => None,
+    }
+  }
+
+  /// Conductor settings, turning the conductor on with defaults if it wasn't already
+  fn conductor_settings(&mut self) -> &mut ConductorSettings {
+    self.conductor.get_or_insert_with(ConductorSettings::default)
+  }
+
+  /// Denoiser settings, turning the denoiser on with defaults if it wasn't already
+  fn denoise_settings(&mut self) -> &mut DenoiseSettings {
+    self.denoise.get_or_insert_with(DenoiseSettings::default)
+  }
+
+  /// The pixels to render
+  pub fn crop_bounds(&self) -> Bounds {
+    let (w, h) = (self.width as f32, self.height as f32);
+    match self.crop {
+      None => Bounds::new(0, 0, self.width, self.height),
+      Some(CropWindow::Pixels(x0, y0, x1, y1)) => Bounds::new(x0, y0, x1, y1),
+      // Round outwards so every pixel the window touches is rendered
+      Some(CropWindow::Normalized(x0, y0, x1, y1)) => Bounds::new(
+        (x0 * w).floor().max(0.0) as u32, (y0 * h).floor().max(0.0) as u32,
+        (x1 * w).ceil().max(0.0) as u32, (y1 * h).ceil().max(0.0) as u32),
+    }
+  }
+
+  /// The part of the image that's written out
+  pub fn output_bounds(&self) -> Bounds {
+    if self.crop_full_frame {
+      Bounds::new(0, 0, self.width, self.height)
+    } else {
+      self.crop_bounds()
+    }
+  }
+
+  /// An empty film for these settings
+  pub fn film(&self) -> Film {
+    let film = Film::new(self.width, self.height, self.filter()).with_crop(self.crop_bounds());
+    if self.lpe.is_empty() { film } else { film.with_lpe(self.lpe.len() + 1) }
+  }
+
+  /// Names of the light path expression buffers, `other` last
+  pub fn lpe_names(&self) -> Vec<String> {
+    self.lpe.iter().map(|e| e.name.clone()).chain(Some("other".to_string())).collect()
+  }
+
+  pub fn filter(&self) -> Filter {
+    match self.filter_radius {
+      Some(radius) => Filter::from((self.filter, radius)),
+      None => Filter::from(self.filter),
+    }
+  }
+}
+
+// Parse the value following a flag, e.g. the `42` in `--seed 42`
+fn parse_value<T>(flag: &str, value: Option<String>) -> Result<T, String>
+  where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+  let value = value.ok_or_else(|| format!("{} expects a value", flag))?;
+  value.parse().map_err(|e| format!("invalid value '{}' for {}: {}", value, flag, e))
+}
+
+// Parse `count` comma separated values, like `10,20,30,40`
+fn parse_list<T>(flag: &str, value: Option<String>, count: usize) -> Result<Vec<T>, String>
+  where
+    T: std::str::FromStr,
+{
+  let value = value.ok_or_else(|| format!("{} expects a value", flag))?;
+  let list: Result<Vec<T>, _> = value.split(',').map(str::parse).collect();
+  match list {
+    Ok(list) if list.len() == count => Ok(list),
+    _ => Err(format!("invalid value '{}' for {}, expected {} comma separated numbers", value, flag, count)),
+  }
+}
+
+// Parse a value like `1000x500` or `10,20` following a flag
+fn parse_pair<T: std::str::FromStr>(flag: &str, value: Option<String>, separator: char) -> Result<(T, T), String> {
+  let value = value.ok_or_else(|| format!("{} expects a value", flag))?;
+  let mut parts = value.splitn(2, separator);
+  match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+    (Some(Ok(a)), Some(Ok(b))) => Ok((a, b)),
+    _ => Err(format!("invalid value '{}' for {}, expected a{}b", value, flag, separator)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &str) -> Result<RenderSettings, String> {
+    RenderSettings::from_args(args.split_whitespace().map(str::to_string))
+  }
+
+  // Parsing `args` fails with an error mentioning `flag`
+  fn rejects(args: &str, flag: &str) {
+    match parse(args) {
+      Ok(_) => panic!("'{}' was accepted", args),
+      Err(e) => assert!(e.contains(flag), "'{}' failed with '{}', which doesn't mention {}", args, e, flag),
+    }
+  }
+
+  #[test]
+  fn defaults_and_good_arguments_are_accepted() {
+    assert!(parse("").is_ok());
+    let settings = parse("--size 64x32 --spp 8 --sampler sobol --crop 4,4,60,30 --denoise").unwrap();
+    assert_eq!((settings.width, settings.height, settings.spp), (64, 32, 8));
+    assert_eq!(settings.sampler, SamplerType::Sobol);
+    assert_eq!(settings.crop_bounds(), Bounds::new(4, 4, 60, 30));
+  }
+
+  #[test]
+  fn bad_values_are_rejected() {
+    rejects("--spp 0", "must be non-zero");
+    rejects("--spp many", "invalid value 'many' for --spp");
+    rejects("--size 64x32 --crop 10,10,80,20", "crop window");
+    rejects("--size 64x32 --debug-pixel 64,0", "--debug-pixel is outside");
+    rejects("--emissive 1.5", "--emissive must be");
+    rejects("--filter-radius 0", "--filter-radius must be");
+    rejects("--glass-color 0.5,0,0.5", "--glass-color values");
+    rejects("--frobnicate", "unknown argument '--frobnicate'");
+  }
+
+  #[test]
+  fn every_denoise_sigma_must_be_positive() {
+    for flag in ["--denoise-sigma-color", "--denoise-sigma-normal", "--denoise-sigma-depth",
+                 "--denoise-sigma-albedo"].iter() {
+      assert!(parse(&format!("{} 2", flag)).is_ok(), "{} 2", flag);
+      rejects(&format!("{} 0", flag), &flag["--denoise-sigma-".len()..]);
+      rejects(&format!("{} -1", flag), &flag["--denoise-sigma-".len()..]);
+    }
+  }
+
+  #[test]
+  fn clashing_options_are_rejected() {
+    rejects("--progressive --adaptive", "can't be combined");
+    rejects("--resume", "needs a --checkpoint");
+    rejects("--sky --env-map sky.hdr", "can't be used together");
+    rejects("--aov normal", "needs an --aov-prefix");
+    rejects("--varnish-color 0.5,0.5,0.5", "needs --varnish");
+    rejects("--thin-glass --glass-color 0.5,0.5,0.5", "--thin-glass can't be used with --glass-color");
+  }
+}
@@ -0,0 +1,176 @@
+//! Sky module contains the Preetham analytic daylight model and its matching sun
+use std::f32::consts::PI;
+
+use crate::light::*;
+use crate::vec3::*;
+
+/// Angular radius of the sun's disk as seen from the earth, in radians
+const SUN_ANGULAR_RADIUS: f32 = 0.004_654;
+/// Luminance of the sun above the atmosphere, in kcd/m², the sky model's unit
+const SUN_LUMINANCE: f32 = 2.0e6;
+
+/// Where the sun is and how hazy the air is
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SkySettings {
+  /// Degrees above the horizon, 0 to 90
+  pub elevation: f32,
+  /// Degrees around the y axis, from +x towards +z
+  pub azimuth: f32,
+  /// Amount of haze, from 2 for a very clear day to 10 for a hazy one
+  pub turbidity: f32,
+  /// Scale from the model's kcd/m² to the renderer's radiance
+  pub exposure: f32,
+}
+
+impl Default for SkySettings {
+  fn default() -> Self {
+    SkySettings {
+      elevation: 35.0,
+      azimuth: 60.0,
+      turbidity: 3.0,
+      exposure: 0.025,
+    }
+  }
+}
+
+/// The Preetham, Shirley & Smits (1999) clear sky. Sky radiance comes from fits of the
+/// Perez distribution to the zenith's luminance & chromaticity; the sun is the
+/// extraterrestrial sun dimmed by Rayleigh & aerosol scattering along its path.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sky {
+  pub settings: SkySettings,
+  /// Unit vector towards the sun
+  pub sun_direction: Vec3,
+  /// Luminance Y and chromaticity x, y at the zenith
+  zenith: [f32; 3],
+  /// Perez A to E for Y, x & y
+  perez: [[f32; 5]; 3],
+}
+
+impl From<SkySettings> for Sky {
+  fn from(settings: SkySettings) -> Self {
+    let t = settings.turbidity;
+    let elevation = settings.elevation.to_radians();
+    let azimuth = settings.azimuth.to_radians();
+    let sun_direction = Vec3::from((elevation.cos() * azimuth.cos(), elevation.sin(),
+                                    elevation.cos() * azimuth.sin()));
+    let theta_s = PI / 2.0 - elevation;
+
+    let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+    let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+    let chromaticity = |m: [[f32; 4]; 3]| {
+      let thetas = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+      let ts = [t * t, t, 1.0];
+      (0..3).map(|i| ts[i] * (0..4).map(|j| m[i][j] * thetas[j]).sum::<f32>()).sum::<f32>()
+    };
+    let zenith_x = chromaticity([[0.00166, -0.00375, 0.00209, 0.0],
+                                 [-0.02903, 0.06377, -0.03202, 0.00394],
+                                 [0.11693, -0.21196, 0.06052, 0.25886]]);
+    let zenith_y_chroma = chromaticity([[0.00275, -0.00610, 0.00317, 0.0],
+                                        [-0.04214, 0.08970, -0.04153, 0.00516],
+                                        [0.15346, -0.26756, 0.06670, 0.26688]]);
+
+    Sky {
+      settings,
+      sun_direction,
+      zenith: [zenith_y, zenith_x, zenith_y_chroma],
+      perez: [
+        [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+        [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+        [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+      ],
+    }
+  }
+}
+
+impl Sky {
+  /// Radiance of the sky in `direction`, not counting the sun's disk. Below the horizon
+  /// the sky carries on as it is at the horizon.
+  pub fn radiance(&self, direction: Vec3) -> Vec3 {
+    let direction = direction.unit_vector();
+    let cos_theta = direction.y().max(0.01);
+    let gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+    let theta_s = PI / 2.0 - self.settings.elevation.to_radians();
+    let mut yxy = [0.0; 3];
+    for (i, v) in yxy.iter_mut().enumerate() {
+      *v = self.zenith[i] * perez(&self.perez[i], cos_theta, gamma) / perez(&self.perez[i], 1.0, theta_s);
+    }
+    let [luminance, x, y] = yxy;
+    let xyz = Vec3::from((x / y * luminance, luminance, (1.0 - x - y) / y * luminance));
+    self.settings.exposure * xyz_to_rgb(xyz)
+  }
+
+  /// The sun's disk as a light, colored by the air it shines through
+  pub fn sun(&self) -> Light {
+    let theta_s = PI / 2.0 - self.settings.elevation.to_radians();
+    // Kasten's relative air mass, which stays finite at the horizon
+    let air_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * self.settings.turbidity - 0.04586;
+    // Representative wavelengths of R, G & B, in micrometers
+    let mut transmittance = [0.0; 3];
+    for (tr, lambda) in transmittance.iter_mut().zip([0.65f32, 0.57, 0.475].iter()) {
+      let rayleigh = 0.008735 * lambda.powf(-4.08);
+      let aerosol = beta * lambda.powf(-1.3);
+      *tr = (-air_mass * (rayleigh + aerosol)).exp();
+    }
+    let radiance = self.settings.exposure * SUN_LUMINANCE * Vec3::from(transmittance);
+    Light::sun(self.sun_direction, radiance, SUN_ANGULAR_RADIUS)
+  }
+}
+
+// The Perez sky distribution
+fn perez(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+  (1.0 + c[0] * (c[1] / cos_theta).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+}
+
+// CIE XYZ to linear sRGB, with negative results clamped
+fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+  let (x, y, z) = (xyz.x(), xyz.y(), xyz.z());
+  Vec3::from(((3.2406 * x - 1.5372 * y - 0.4986 * z).max(0.0),
+              (-0.9689 * x + 1.8758 * y + 0.0415 * z).max(0.0),
+              (0.0557 * x - 0.2040 * y + 1.0570 * z).max(0.0)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::film::*;
+
+  #[test]
+  fn sky_is_finite_and_never_negative() {
+    for &elevation in [0.0f32, 5.0, 35.0, 60.0, 90.0].iter() {
+      for &turbidity in [2.0f32, 3.0, 6.0, 10.0].iter() {
+        let sky = Sky::from(SkySettings { elevation, turbidity, ..SkySettings::default() });
+        // Directions all over the sphere, from straight down to straight up
+        for i in 0..=36 {
+          let theta = PI * i as f32 / 36.0;
+          for j in 0..72 {
+            let phi = 2.0 * PI * j as f32 / 72.0;
+            let direction = Vec3::from((theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()));
+            let radiance = sky.radiance(direction);
+            for &c in [radiance.r(), radiance.g(), radiance.b()].iter() {
+              assert!(c.is_finite() && c >= 0.0, "{} looking at {:?} with the sun at {}° & turbidity {}",
+                      c, direction, elevation, turbidity);
+            }
+          }
+        }
+        // Straight at the sun, too
+        assert!(sky.radiance(sky.sun_direction).r().is_finite());
+        match sky.sun() {
+          Light::Sun { radiance, .. } => {
+            assert!([radiance.r(), radiance.g(), radiance.b()].iter().all(|c| c.is_finite() && *c > 0.0));
+          },
+          light => panic!("the sun is a {:?}", light),
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn sky_is_brightest_around_the_sun() {
+    let sky = Sky::from(SkySettings::default());
+    let near = sky.radiance(sky.sun_direction + Vec3::from((0.0, 0.05, 0.0)));
+    let opposite = sky.radiance(Vec3::from((-sky.sun_direction.x(), sky.sun_direction.y(), -sky.sun_direction.z())));
+    assert!(luminance(near) > luminance(opposite), "{:?} near the sun, {:?} away from it", near, opposite);
+  }
+}
@@ -0,0 +1,349 @@
+//! Spectrum module contains what spectral rendering needs: sampled wavelengths, the CIE
+//! color matching functions, turning RGB into spectra and back, and dispersive indices of
+//! refraction
+use std::str::FromStr;
+
+use crate::vec3::*;
+
+/// Wavelengths carried by each path
+pub const WAVELENGTHS: usize = 4;
+/// The range wavelengths are sampled from, in nm, which the color matching functions cover
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+/// A path's wavelengths, in nm, and the density each was picked with. The first is the
+/// hero wavelength, which decides directions where the wavelengths would part ways.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Wavelengths {
+  pub lambda: [f32; WAVELENGTHS],
+  pub pdf: [f32; WAVELENGTHS],
+}
+
+impl Wavelengths {
+  /// A hero wavelength from uniform `u` and the rest evenly spaced after it, wrapping around
+  /// the range
+  pub fn sample(u: f32) -> Self {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let mut lambda = [0.0; WAVELENGTHS];
+    for (i, l) in lambda.iter_mut().enumerate() {
+      let offset = (u + i as f32 / WAVELENGTHS as f32).fract();
+      *l = LAMBDA_MIN + offset * range;
+    }
+    Wavelengths {
+      lambda,
+      pdf: [1.0 / range; WAVELENGTHS],
+    }
+  }
+
+  pub fn hero(&self) -> f32 {
+    self.lambda[0]
+  }
+
+  /// Drop all but the hero wavelength, for when the others would have gone another way.
+  /// The hero then stands in for all of them.
+  pub fn terminate_secondary(&mut self) {
+    if self.pdf[1] == 0.0 {
+      return;
+    }
+    for pdf in self.pdf.iter_mut().skip(1) {
+      *pdf = 0.0;
+    }
+    self.pdf[0] /= WAVELENGTHS as f32;
+  }
+}
+
+// One piece of Wyman, Sloan & Shirley's (2013) fit: a Gaussian with a different width either
+// side of its peak
+fn lobe(lambda: f32, mu: f32, sigma_below: f32, sigma_above: f32) -> f32 {
+  let t = (lambda - mu) / if lambda < mu { sigma_below } else { sigma_above };
+  (-0.5 * t * t).exp()
+}
+
+/// The CIE 1931 2° color matching functions x̄, ȳ & z̄ at `lambda` nm, from Wyman, Sloan &
+/// Shirley's multi-lobe fit
+pub fn cie_xyz(lambda: f32) -> Vec3 {
+  let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+    - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+  let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+  let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+  Vec3::from((x, y, z))
+}
+
+/// CIE XYZ to linear sRGB. Unlike the sky's, negative results are kept, so averages of
+/// samples come out right.
+pub fn xyz_to_linear_srgb(xyz: Vec3) -> Vec3 {
+  let (x, y, z) = (xyz.x(), xyz.y(), xyz.z());
+  Vec3::from((3.2406 * x - 1.5372 * y - 0.4986 * z,
+              -0.9689 * x + 1.8758 * y + 0.0415 * z,
+              0.0557 * x - 0.2040 * y + 1.0570 * z))
+}
+
+/// Peaks & widths of the smooth bumps, reddish, greenish and bluish, that RGB values are
+/// turned into spectra with
+const BUMPS: [(f32, f32); 3] = [(610.0, 45.0), (540.0, 40.0), (455.0, 35.0)];
+
+/// Turns RGB into smooth spectra and spectral samples back into RGB. Each RGB channel
+/// becomes a mix of three bumps, solved for so that converting a spectrum back gives exactly
+/// the RGB it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RgbSpectra {
+  /// Bump weights for each of R, G & B: `weights[k][c]` is bump k's weight for channel c
+  weights: [[f32; 3]; 3],
+  /// Integral of ȳ over the wavelength range, so a spectrum of 1 has a luminance of 1
+  y_integral: f32,
+}
+
+impl Default for RgbSpectra {
+  fn default() -> Self {
+    // Integrate each bump against the color matching functions in 1nm steps
+    let mut bump_xyz = [Vec3::new(); 3];
+    let mut y_integral = 0.0;
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+      let cmf = cie_xyz(lambda);
+      y_integral += cmf.y();
+      for (k, xyz) in bump_xyz.iter_mut().enumerate() {
+        *xyz += bump(k, lambda) * cmf;
+      }
+      lambda += 1.0;
+    }
+    // Column k is the RGB bump k turns into
+    let mut projection = [[0.0; 3]; 3];
+    for (k, xyz) in bump_xyz.iter().enumerate() {
+      let rgb = xyz_to_linear_srgb(*xyz / y_integral);
+      for c in 0..3 {
+        projection[c][k] = rgb[c];
+      }
+    }
+    RgbSpectra {
+      weights: invert(projection),
+      y_integral,
+    }
+  }
+}
+
+impl RgbSpectra {
+  /// Value at `lambda` nm of the spectrum standing in for `rgb`
+  pub fn eval(&self, rgb: Vec3, lambda: f32) -> f32 {
+    (0..3).map(|k| {
+      let weight = self.weights[k][0] * rgb.r() + self.weights[k][1] * rgb.g() + self.weights[k][2] * rgb.b();
+      weight * bump(k, lambda)
+    }).sum()
+  }
+
+  /// `rgb`'s spectrum at each of the wavelengths
+  pub fn sample(&self, rgb: Vec3, wavelengths: &Wavelengths) -> [f32; WAVELENGTHS] {
+    let mut values = [0.0; WAVELENGTHS];
+    for (v, &lambda) in values.iter_mut().zip(wavelengths.lambda.iter()) {
+      *v = self.eval(rgb, lambda);
+    }
+    values
+  }
+
+  /// Reflectance at each of the wavelengths of a surface that turns white light into `rgb`.
+  /// This is `rgb`'s spectrum over white's, so grays are flat and bounce after bounce off
+  /// them stays gray. Saturated colors dip a little below 0 in places, which is cut off.
+  pub fn sample_reflectance(&self, rgb: Vec3, wavelengths: &Wavelengths) -> [f32; WAVELENGTHS] {
+    let white = Vec3::from([1.0; 3]);
+    let mut values = [0.0; WAVELENGTHS];
+    for (v, &lambda) in values.iter_mut().zip(wavelengths.lambda.iter()) {
+      let w = self.eval(white, lambda);
+      *v = if w > 0.0 { (self.eval(rgb, lambda) / w).max(0.0) } else { 0.0 };
+    }
+    values
+  }
+
+  /// Estimate of the RGB of a spectrum from its values at the wavelengths
+  pub fn to_rgb(&self, values: &[f32; WAVELENGTHS], wavelengths: &Wavelengths) -> Vec3 {
+    let mut xyz = Vec3::new();
+    for ((v, &pdf), &lambda) in values.iter().zip(wavelengths.pdf.iter()).zip(wavelengths.lambda.iter()) {
+      if pdf > 0.0 {
+        xyz += v / pdf * cie_xyz(lambda);
+      }
+    }
+    xyz_to_linear_srgb(xyz / (WAVELENGTHS as f32 * self.y_integral))
+  }
+}
+
+fn bump(k: usize, lambda: f32) -> f32 {
+  let (mu, sigma) = BUMPS[k];
+  lobe(lambda, mu, sigma, sigma)
+}
+
+// Inverse of a 3x3 matrix by its adjugate
+fn invert(m: [[f32; 3]; 3]) -> [[f32; 3]; 3] {
+  let cofactor = |r: usize, c: usize| {
+    let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+    let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+    m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+  };
+  let determinant: f32 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+  let mut inverse = [[0.0; 3]; 3];
+  for (r, row) in inverse.iter_mut().enumerate() {
+    for (c, v) in row.iter_mut().enumerate() {
+      *v = cofactor(c, r) / determinant;
+    }
+  }
+  inverse
+}
+
+/// How a glass's index of refraction varies with wavelength
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dispersion {
+  /// n = A + B / λ², with λ in µm
+  Cauchy { a: f32, b: f32 },
+  /// n² = 1 + Σ Bᵢλ² / (λ² - Cᵢ), with λ in µm
+  Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+  /// Index of refraction at `lambda` nm
+  pub fn ior(&self, lambda: f32) -> f32 {
+    let l2 = (lambda / 1000.0).powi(2);
+    match self {
+      Dispersion::Cauchy { a, b } => a + b / l2,
+      Dispersion::Sellmeier { b, c } => {
+        (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).sqrt()
+      },
+    }
+  }
+
+  /// Index of refraction at the helium d line, 587.6nm, the one glasses are quoted by
+  pub fn ior_d(&self) -> f32 {
+    self.ior(587.6)
+  }
+}
+
+/// `bk7` or `diamond`, or `cauchy:A:B` with B in µm², or `sellmeier:B1:B2:B3:C1:C2:C3` with
+/// the Cs in µm²
+impl FromStr for Dispersion {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "bk7" => return Ok(Dispersion::Sellmeier {
+        b: [1.039_612, 0.231_792_3, 1.010_469],
+        c: [0.006_000_7, 0.020_017_9, 103.560_6],
+      }),
+      "diamond" => return Ok(Dispersion::Sellmeier {
+        b: [0.3306, 4.3356, 0.0],
+        c: [0.030_625, 0.011_236, 0.0],
+      }),
+      _ => (),
+    }
+    let mut parts = s.split(':');
+    let kind = parts.next().unwrap_or_default();
+    let values = parts.map(|v| v.parse::<f32>()).collect::<Result<Vec<f32>, _>>()
+      .map_err(|_| format!("bad coefficients in '{}'", s))?;
+    match (kind, values.as_slice()) {
+      ("cauchy", [a, b]) => Ok(Dispersion::Cauchy { a: *a, b: *b }),
+      ("sellmeier", [b1, b2, b3, c1, c2, c3]) => Ok(Dispersion::Sellmeier { b: [*b1, *b2, *b3], c: [*c1, *c2, *c3] }),
+      _ => Err(format!("'{}' isn't bk7, diamond, cauchy:A:B or sellmeier:B1:B2:B3:C1:C2:C3", s)),
+    }
+  }
+}
+
+/// The throughput of a spectral path at each of its wavelengths
+pub struct SpectralPath<'a> {
+  spectra: &'a RgbSpectra,
+  throughput: [f32; WAVELENGTHS],
+}
+
+impl<'a> From<&'a RgbSpectra> for SpectralPath<'a> {
+  fn from(spectra: &'a RgbSpectra) -> Self {
+    SpectralPath {
+      spectra,
+      throughput: [1.0; WAVELENGTHS],
+    }
+  }
+}
+
+impl SpectralPath<'_> {
+  /// RGB light `c` reaching the path's latest hit, as RGB arriving at the camera
+  pub fn weigh(&self, c: Vec3, wavelengths: &Wavelengths) -> Vec3 {
+    let mut values = self.spectra.sample(c, wavelengths);
+    for (v, t) in values.iter_mut().zip(self.throughput.iter()) {
+      *v *= t;
+    }
+    self.spectra.to_rgb(&values, wavelengths)
+  }
+
+  /// Take a bounce's RGB attenuation into the throughput
+  pub fn scatter(&mut self, attenuation: Vec3, wavelengths: &Wavelengths) {
+    let reflectance = self.spectra.sample_reflectance(attenuation, wavelengths);
+    for (t, a) in self.throughput.iter_mut().zip(reflectance.iter()) {
+      *t *= a;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const COLORS: [(f32, f32, f32); 5] = [(1.0, 1.0, 1.0), (0.5, 0.5, 0.5), (0.8, 0.3, 0.1), (0.1, 0.9, 0.2), (0.05, 0.1, 0.7)];
+
+  // `to_rgb` of `rgb`'s spectrum averaged over hero wavelengths spread evenly through the
+  // range, with the secondary wavelengths dropped if `terminate`
+  fn round_trip(spectra: &RgbSpectra, rgb: Vec3, terminate: bool) -> Vec3 {
+    let steps = 4000;
+    let mut sum = Vec3::new();
+    for i in 0..steps {
+      let mut wavelengths = Wavelengths::sample((i as f32 + 0.5) / steps as f32);
+      if terminate {
+        wavelengths.terminate_secondary();
+      }
+      sum += spectra.to_rgb(&spectra.sample(rgb, &wavelengths), &wavelengths);
+    }
+    sum / steps as f32
+  }
+
+  #[test]
+  fn rgb_comes_back_from_its_spectrum() {
+    let spectra = RgbSpectra::default();
+    for &color in COLORS.iter() {
+      let rgb = Vec3::from(color);
+      let back = round_trip(&spectra, rgb, false);
+      assert!((back - rgb).length() < 2e-3, "{:?} came back as {:?}", rgb, back);
+    }
+  }
+
+  #[test]
+  fn dropping_secondary_wavelengths_keeps_the_estimate() {
+    let spectra = RgbSpectra::default();
+    for &color in COLORS.iter() {
+      let rgb = Vec3::from(color);
+      let back = round_trip(&spectra, rgb, true);
+      assert!((back - rgb).length() < 2e-3, "{:?} came back as {:?} from the hero alone", rgb, back);
+    }
+    // Dropping them twice doesn't count the hero twice
+    let mut wavelengths = Wavelengths::sample(0.3);
+    wavelengths.terminate_secondary();
+    let once = wavelengths;
+    wavelengths.terminate_secondary();
+    assert_eq!(wavelengths, once);
+  }
+
+  #[test]
+  fn gray_reflectance_is_flat() {
+    let spectra = RgbSpectra::default();
+    for &gray in [0.0f32, 0.18, 0.5, 1.0].iter() {
+      for i in 0..47 {
+        let wavelengths = Wavelengths::sample(i as f32 / 47.0);
+        for &r in spectra.sample_reflectance(Vec3::from([gray; 3]), &wavelengths).iter() {
+          assert!((r - gray).abs() < 1e-4, "gray {} reflects {} at {:?}", gray, r, wavelengths.lambda);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn bk7_is_quoted_at_the_d_line() {
+    let bk7: Dispersion = "bk7".parse().unwrap();
+    assert!((bk7.ior_d() - 1.5168).abs() < 1e-3, "{}", bk7.ior_d());
+    // Blue bends more than red
+    assert!(bk7.ior(450.0) > bk7.ior(650.0));
+    let cauchy: Dispersion = "cauchy:1.5:0.01".parse().unwrap();
+    assert!((cauchy.ior(500.0) - 1.54).abs() < 1e-5);
+    assert!("cauchy:1.5".parse::<Dispersion>().is_err());
+  }
+}
@@ -1,25 +1,41 @@
+use std::f32::consts::PI;
+
 use crate::hitable::*;
 use crate::material::*;
+use crate::normalmap::*;
 use crate::ray::*;
+use crate::sampler::*;
 use crate::vec3::*;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Sphere {
   pub center: Vec3,
   pub radius: f32,
   pub material: MaterialType,
+  /// Bends the shading normal, for detail the sphere doesn't have
+  pub normal_map: Option<NormalMap>,
 }
 
 impl Sphere {
   /// Utility function to get a random point in a sphere w/ radius of 1.0
-  pub fn random_in_unit_sphere() -> Vec3 {
-    let mut p: Vec3;
-    loop {
-      p = 2.0 * Vec3::from((rand::random::<f32>(), rand::random::<f32>(), rand::random::<f32>())) - Vec3::from([1.0; 3]);
-      if p.squared_length() < 1.0 {
-        return p;
-      }
-    }
+  pub fn random_in_unit_sphere(ctx: &mut SamplerContext) -> Vec3 {
+    // A uniform direction scaled by a cube root radius has the same distribution as
+    // rejection sampling a cube, but always uses the same three sampler dimensions.
+    let (u, v) = ctx.bsdf_2d();
+    let r = ctx.bsdf_1d().cbrt();
+    let z = 1.0 - 2.0 * u;
+    let r_xy = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * v;
+    r * Vec3::from((r_xy * phi.cos(), r_xy * phi.sin(), z))
+  }
+
+  /// A uniform direction, a point on the unit sphere
+  pub fn random_unit_vector(ctx: &mut SamplerContext) -> Vec3 {
+    let (u, v) = ctx.bsdf_2d();
+    let z = 1.0 - 2.0 * u;
+    let r_xy = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * v;
+    Vec3::from((r_xy * phi.cos(), r_xy * phi.sin(), z))
   }
 }
 
@@ -29,45 +45,62 @@ impl From<(Vec3, f32, MaterialType)> for Sphere {
       center: tuple.0,
       radius: tuple.1,
       material: tuple.2,
+      normal_map: None,
     }
   }
 }
 
 impl Hitable for Sphere {
-  fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-      let mut record = None;
-      let oc = r.origin - self.center;
-      let a = r.direction.dot(r.direction);
-      let b = oc.dot(r.direction);
-      let c = oc.dot(oc) - self.radius * self.radius;
-      let discriminant = b * b - a * c;
-      if discriminant > 0.0 {
-        let mut temp = (-b - discriminant.sqrt()) / a;
-        if temp < t_max && temp > t_min {
-          let p = r.point_at_parameter(temp);
-          record = Some(
-            HitRecord {
-              t: temp,
-              p,
-              normal: (p - self.center) / self.radius,
-              material: self.material,
-            });
-        } else {
-          temp = (-b + discriminant.sqrt()) / a;
-          if temp < t_max && temp > t_min {
-            let p = r.point_at_parameter(temp);
-            record = Some(
-              HitRecord {
-                t: temp,
-                p,
-                normal: (p - self.center) / self.radius,
-                material: self.material,
-              });
-          }
-        }
+  fn hit_distance(&self, r: &Ray, t_min: f32, t_max: f32, ctx: &mut SamplerContext) -> Option<f32> {
+    ctx.stats.primitive_tests += 1;
+    let oc = r.origin - self.center;
+    let a = r.direction.dot(r.direction);
+    let b = oc.dot(r.direction);
+    let c = oc.dot(oc) - self.radius * self.radius;
+    let discriminant = b * b - a * c;
+    if discriminant > 0.0 {
+      let mut temp = (-b - discriminant.sqrt()) / a;
+      if temp < t_max && temp > t_min {
+        return Some(temp);
       }
+      temp = (-b + discriminant.sqrt()) / a;
+      if temp < t_max && temp > t_min {
+        return Some(temp);
+      }
+    }
+    None
+  }
 
-      // If something was hit, record will not be None
-      record
+  fn record(&self, r: &Ray, t: f32) -> HitRecord {
+    let p = r.point_at_parameter(t);
+    let normal = (p - self.center) / self.radius;
+    // Longitude & latitude, u starting from -x and v from the bottom pole
+    let phi = (-normal.z()).atan2(normal.x()) + PI;
+    let theta = (-normal.y()).clamp(-1.0, 1.0).acos();
+    // Going round, and up from pole to pole. Up is undefined at the poles themselves, so
+    // it's taken from just beside them.
+    let tangent = 2.0 * PI * self.radius * Vec3::from((normal.z(), 0.0, -normal.x()));
+    let sin_theta = theta.sin().max(1e-4);
+    let bitangent = PI * self.radius * Vec3::from((-normal.x() * normal.y() / sin_theta, sin_theta,
+                                                   -normal.y() * normal.z() / sin_theta));
+    let point = SurfacePoint {
+      normal,
+      tangent,
+      bitangent,
+      u: phi / (2.0 * PI),
+      v: theta / PI,
+    };
+    HitRecord {
+      t,
+      p,
+      normal: self.normal_map.map_or(normal, |map| map.shading_normal(&point, r.direction)),
+      geometric_normal: normal,
+      tangent,
+      bitangent,
+      material: self.material.clone(),
+      u: point.u,
+      v: point.v,
+      object_id: 0,
+    }
   }
-}
\ No newline at end of file
+}
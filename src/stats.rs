@@ -0,0 +1,160 @@
+//! Stats module counts the work a render does and times its phases
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Counters for one render. Every worker's SamplerContext carries its own copy, which is
+/// bumped without any synchronisation and merged into the render's total once per tile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderStats {
+  /// Rays generated by the camera
+  pub camera_rays: u64,
+  /// Every ray cast into the scene, camera & shadow rays included
+  pub rays: u64,
+  /// Rays cast towards a light to see if anything is in the way
+  pub shadow_rays: u64,
+  /// Ray-primitive intersection tests. The world is a flat list, so this is every ray
+  /// times every object. There's no BVH, so no node visits or build time to count either.
+  pub primitive_tests: u64,
+  /// Rays that hit something and scattered off it
+  pub scatter_events: u64,
+  /// `path_lengths[n]` is how many paths ended after n bounces
+  pub path_lengths: Vec<u64>,
+}
+
+impl RenderStats {
+  /// A path ended after `bounces` bounces
+  pub fn record_path(&mut self, bounces: usize) {
+    if self.path_lengths.len() <= bounces {
+      self.path_lengths.resize(bounces + 1, 0);
+    }
+    self.path_lengths[bounces] += 1;
+  }
+
+  /// Add another set of counters into this one
+  pub fn merge(&mut self, other: &RenderStats) {
+    self.camera_rays += other.camera_rays;
+    self.rays += other.rays;
+    self.shadow_rays += other.shadow_rays;
+    self.primitive_tests += other.primitive_tests;
+    self.scatter_events += other.scatter_events;
+    for (bounces, &count) in other.path_lengths.iter().enumerate() {
+      if count > 0 {
+        if self.path_lengths.len() <= bounces {
+          self.path_lengths.resize(bounces + 1, 0);
+        }
+        self.path_lengths[bounces] += count;
+      }
+    }
+  }
+
+  pub fn mean_path_length(&self) -> f64 {
+    let paths: u64 = self.path_lengths.iter().sum();
+    let bounces: u64 = self.path_lengths.iter().enumerate().map(|(n, &c)| n as u64 * c).sum();
+    if paths > 0 { bounces as f64 / paths as f64 } else { 0.0 }
+  }
+}
+
+/// Wall clock time of each phase of a run
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PhaseTimes {
+  pub scene_build: Duration,
+  pub render: Duration,
+  pub output: Duration,
+}
+
+impl PhaseTimes {
+  pub fn total(&self) -> Duration {
+    self.scene_build + self.render + self.output
+  }
+}
+
+/// Everything `--stats` and `--stats-json` report about a run
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderReport {
+  pub width: u32,
+  pub height: u32,
+  pub spp: u32,
+  pub threads: usize,
+  pub stats: RenderStats,
+  pub phases: PhaseTimes,
+}
+
+impl RenderReport {
+  /// Human readable report
+  pub fn print<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    let s = &self.stats;
+    let render = self.phases.render.as_secs_f64().max(1e-9);
+    writeln!(out, "Render statistics, {}x{} @ {} spp on {} threads", self.width, self.height,
+             self.spp, self.threads)?;
+    writeln!(out, "  {:<20} {:>14}", "camera rays", s.camera_rays)?;
+    writeln!(out, "  {:<20} {:>14}  ({:.2} Mrays/s)", "total rays", s.rays, s.rays as f64 / render / 1e6)?;
+    writeln!(out, "  {:<20} {:>14}", "shadow rays", s.shadow_rays)?;
+    writeln!(out, "  {:<20} {:>14}", "primitive tests", s.primitive_tests)?;
+    writeln!(out, "  {:<20} {:>14}  (no BVH, the world is a flat list)", "bvh node visits", "n/a")?;
+    writeln!(out, "  {:<20} {:>14}", "scatter events", s.scatter_events)?;
+    writeln!(out, "  path lengths (mean {:.2} bounces)", s.mean_path_length())?;
+    let paths: u64 = s.path_lengths.iter().sum::<u64>().max(1);
+    for (bounces, &count) in s.path_lengths.iter().enumerate().filter(|(_, &c)| c > 0) {
+      writeln!(out, "    {:>3} {:>14}  {:5.1}%", bounces, count, 100.0 * count as f64 / paths as f64)?;
+    }
+    writeln!(out, "  {:<20} {:>10.3} s", "scene build", self.phases.scene_build.as_secs_f64())?;
+    writeln!(out, "  {:<20} {:>12}", "bvh build", "n/a")?;
+    writeln!(out, "  {:<20} {:>10.3} s", "render", self.phases.render.as_secs_f64())?;
+    writeln!(out, "  {:<20} {:>10.3} s", "output", self.phases.output.as_secs_f64())?;
+    writeln!(out, "  {:<20} {:>10.3} s", "total", self.phases.total().as_secs_f64())
+  }
+
+  /// The report as a JSON object, for CI to track
+  pub fn to_json(&self) -> String {
+    let s = &self.stats;
+    let lengths: Vec<String> = s.path_lengths.iter().map(u64::to_string).collect();
+    format!(concat!("{{\n",
+                    "  \"width\": {},\n  \"height\": {},\n  \"spp\": {},\n  \"threads\": {},\n",
+                    "  \"camera_rays\": {},\n  \"rays\": {},\n  \"shadow_rays\": {},\n  \"primitive_tests\": {},\n",
+                    "  \"bvh_node_visits\": null,\n  \"scatter_events\": {},\n  \"path_lengths\": [{}],\n",
+                    "  \"seconds\": {{\"scene_build\": {:.6}, \"bvh_build\": null, \"render\": {:.6}, ",
+                    "\"output\": {:.6}, \"total\": {:.6}}}\n",
+                    "}}\n"),
+            self.width, self.height, self.spp, self.threads,
+            s.camera_rays, s.rays, s.shadow_rays, s.primitive_tests, s.scatter_events, lengths.join(", "),
+            self.phases.scene_build.as_secs_f64(), self.phases.render.as_secs_f64(),
+            self.phases.output.as_secs_f64(), self.phases.total().as_secs_f64())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn merged_counters_add_up() {
+    let mut a = RenderStats { camera_rays: 2, rays: 5, primitive_tests: 40, ..RenderStats::default() };
+    a.record_path(0);
+    a.record_path(2);
+    let mut b = RenderStats { rays: 3, shadow_rays: 1, scatter_events: 4, ..RenderStats::default() };
+    b.record_path(5);
+    a.merge(&b);
+    assert_eq!((a.camera_rays, a.rays, a.shadow_rays, a.primitive_tests, a.scatter_events), (2, 8, 1, 40, 4));
+    assert_eq!(a.path_lengths, vec![1, 0, 1, 0, 0, 1]);
+    assert!((a.mean_path_length() - 7.0 / 3.0).abs() < 1e-12);
+  }
+
+  #[test]
+  fn json_report_has_every_field() {
+    let report = RenderReport {
+      width: 4,
+      height: 2,
+      spp: 8,
+      threads: 1,
+      stats: RenderStats { path_lengths: vec![3, 1], ..RenderStats::default() },
+      phases: PhaseTimes::default(),
+    };
+    let json = report.to_json();
+    for key in ["width", "spp", "camera_rays", "primitive_tests", "bvh_node_visits", "path_lengths",
+                "scene_build", "bvh_build", "render", "output", "total"].iter() {
+      assert!(json.contains(&format!("\"{}\":", key)), "no {} in {}", key, json);
+    }
+    assert!(json.contains("\"path_lengths\": [3, 1]"));
+    assert_eq!(json.matches('{').count(), json.matches('}').count());
+  }
+}
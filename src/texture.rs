@@ -0,0 +1,89 @@
+//! Texture module contains the procedural textures material parameters can vary over a
+//! surface with
+use std::str::FromStr;
+
+use crate::vec3::*;
+
+/// A value looked up by a hit's surface coordinates. Scalar parameters use the red channel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Texture {
+  /// The same everywhere
+  Constant(Vec3),
+  /// Squares alternating between `even` and `odd`, `scale` of them across each of u and v
+  Checker { even: Vec3, odd: Vec3, scale: f32 },
+  /// Smooth bumps rising from `low` to `high`, `scale` of them across each of u and v
+  Waves { low: Vec3, high: Vec3, scale: f32 },
+}
+
+impl Texture {
+  pub fn value(&self, u: f32, v: f32) -> Vec3 {
+    match *self {
+      Texture::Constant(value) => value,
+      Texture::Checker { even, odd, scale } => {
+        let parity = (u * scale).floor() as i64 + (v * scale).floor() as i64;
+        if parity.rem_euclid(2) == 0 { even } else { odd }
+      },
+      Texture::Waves { low, high, scale } => {
+        let tau = 2.0 * std::f32::consts::PI * scale;
+        let t = 0.5 + 0.5 * (tau * u).sin() * (tau * v).sin();
+        low + t * (high - low)
+      },
+    }
+  }
+
+  pub fn scalar(&self, u: f32, v: f32) -> f32 {
+    self.value(u, v).r()
+  }
+}
+
+impl From<f32> for Texture {
+  fn from(value: f32) -> Self {
+    Texture::Constant(Vec3::from([value; 3]))
+  }
+}
+
+impl From<Vec3> for Texture {
+  fn from(value: Vec3) -> Self {
+    Texture::Constant(value)
+  }
+}
+
+/// A single value `X`, a color `R/G/B`, or `checker:A:B:SCALE` or `waves:A:B:SCALE` with
+/// each of A & B one of those
+impl FromStr for Texture {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some(checker) = s.strip_prefix("checker:") {
+      return match checker.split(':').collect::<Vec<&str>>().as_slice() {
+        [even, odd, scale] => Ok(Texture::Checker {
+          even: parse_color(even)?,
+          odd: parse_color(odd)?,
+          scale: scale.parse().map_err(|_| format!("bad checker scale '{}'", scale))?,
+        }),
+        _ => Err(format!("'{}' isn't checker:A:B:SCALE", s)),
+      };
+    }
+    if let Some(waves) = s.strip_prefix("waves:") {
+      return match waves.split(':').collect::<Vec<&str>>().as_slice() {
+        [low, high, scale] => Ok(Texture::Waves {
+          low: parse_color(low)?,
+          high: parse_color(high)?,
+          scale: scale.parse().map_err(|_| format!("bad waves scale '{}'", scale))?,
+        }),
+        _ => Err(format!("'{}' isn't waves:A:B:SCALE", s)),
+      };
+    }
+    Ok(Texture::Constant(parse_color(s)?))
+  }
+}
+
+// `X` as a gray, or `R/G/B`
+fn parse_color(s: &str) -> Result<Vec3, String> {
+  let values = s.split('/').map(|v| v.parse::<f32>()).collect::<Result<Vec<f32>, _>>()
+    .map_err(|_| format!("bad texture value '{}'", s))?;
+  match values.as_slice() {
+    [x] => Ok(Vec3::from([*x; 3])),
+    [r, g, b] => Ok(Vec3::from((*r, *g, *b))),
+    _ => Err(format!("bad texture value '{}'", s)),
+  }
+}
@@ -0,0 +1,186 @@
+//! Tile module splits an image into tiles and decides the order they're rendered in
+use std::fmt;
+use std::str::FromStr;
+
+use crate::film::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TileOrder {
+  /// Left to right, top to bottom
+  Scanline,
+  /// Along a Hilbert curve, so consecutive tiles are always neighbours
+  Hilbert,
+  /// Outwards from the center of the image, which is usually what's looked at first
+  Spiral,
+}
+
+impl FromStr for TileOrder {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "scanline" => Ok(TileOrder::Scanline),
+      "hilbert" => Ok(TileOrder::Hilbert),
+      "spiral" => Ok(TileOrder::Spiral),
+      _ => Err(format!("unknown tile order '{}'", s)),
+    }
+  }
+}
+
+impl fmt::Display for TileOrder {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      TileOrder::Scanline => "scanline",
+      TileOrder::Hilbert => "hilbert",
+      TileOrder::Spiral => "spiral",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// Split `bounds` into tiles of at most `size` x `size` pixels, in the given order.
+/// rayon hands contiguous runs of this list to each worker, so an order where neighbouring
+/// entries are neighbouring tiles keeps each worker in one part of the scene.
+pub fn tiles(bounds: &Bounds, size: u32, order: TileOrder) -> Vec<Bounds> {
+  let nx = bounds.width().div_ceil(size);
+  let ny = bounds.height().div_ceil(size);
+  let tile = |(tx, ty): (u32, u32)| {
+    let x0 = bounds.x0 + tx * size;
+    let y0 = bounds.y0 + ty * size;
+    Bounds::new(x0, y0, (x0 + size).min(bounds.x1), (y0 + size).min(bounds.y1))
+  };
+
+  let mut coords: Vec<(u32, u32)> = (0..ny).flat_map(|ty| (0..nx).map(move |tx| (tx, ty))).collect();
+  match order {
+    TileOrder::Scanline => {},
+    TileOrder::Hilbert => {
+      let n = nx.max(ny).next_power_of_two();
+      coords.sort_by_key(|&(tx, ty)| hilbert_index(n, tx, ty));
+    },
+    TileOrder::Spiral => {
+      // Ring by ring out from the center tile, each ring in angle order
+      let (cx, cy) = ((nx as f32 - 1.0) / 2.0, (ny as f32 - 1.0) / 2.0);
+      let key = |&(tx, ty): &(u32, u32)| {
+        let (dx, dy) = (tx as f32 - cx, ty as f32 - cy);
+        (dx.abs().max(dy.abs()), dy.atan2(dx))
+      };
+      coords.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+    },
+  }
+  coords.into_iter().map(tile).collect()
+}
+
+/// Distance of (x, y) along the Hilbert curve filling an n x n grid, n a power of two
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u32 {
+  let mut d = 0;
+  let mut s = n / 2;
+  while s > 0 {
+    let rx = u32::from(x & s > 0);
+    let ry = u32::from(y & s > 0);
+    d += s * s * ((3 * rx) ^ ry);
+    // Rotate the quadrant so the curve inside it lines up
+    if ry == 0 {
+      if rx == 1 {
+        x = s - 1 - (x & (s - 1));
+        y = s - 1 - (y & (s - 1));
+      }
+      std::mem::swap(&mut x, &mut y);
+    }
+    s /= 2;
+  }
+  d
+}
+
+/// The largest rectangle of `size` tiles (laid out as `tiles` lays them out over the film's
+/// crop) whose pixels have all been sampled, with how many tiles are finished out of how
+/// many there are. Used when a render is stopped part way, so what's written has no
+/// unrendered holes in it. None if no tile finished.
+pub fn finished_region(film: &Film, size: u32) -> (Option<Bounds>, usize, usize) {
+  let crop = film.crop;
+  let tiles = tiles(&crop, size, TileOrder::Scanline);
+  let nx = crop.width().div_ceil(size) as usize;
+  let finished: Vec<bool> = tiles.iter().map(|t| {
+    (t.y0..t.y1).all(|y| (t.x0..t.x1).all(|x| film.pixels[(y * film.width + x) as usize].samples > 0))
+  }).collect();
+  let done = finished.iter().filter(|&&f| f).count();
+
+  // Largest rectangle under a histogram of finished tiles ending in each row
+  let mut heights = vec![0; nx];
+  let mut best: Option<(usize, usize, usize, usize)> = None;
+  for (ty, row) in finished.chunks(nx).enumerate() {
+    for (height, &f) in heights.iter_mut().zip(row.iter()) {
+      *height = if f { *height + 1 } else { 0 };
+    }
+    for x0 in 0..nx {
+      let mut h = usize::MAX;
+      for (x1, &height) in heights.iter().enumerate().skip(x0) {
+        h = h.min(height);
+        if h == 0 {
+          break;
+        }
+        let area = |(x0, x1, h): (usize, usize, usize)| (x1 - x0 + 1) * h;
+        if best.is_none_or(|(bx0, bx1, bh, _)| area((x0, x1, h)) > area((bx0, bx1, bh))) {
+          best = Some((x0, x1, h, ty));
+        }
+      }
+    }
+  }
+  let region = best.map(|(x0, x1, h, ty)| {
+    let first = tiles[(ty + 1 - h) * nx + x0];
+    let last = tiles[ty * nx + x1];
+    Bounds::new(first.x0, first.y0, last.x1, last.y1)
+  });
+  (region, done, tiles.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filter::*;
+
+  // A 40x30 film cropped to (5, 5)-(37, 29), which is 4x3 tiles of 8 pixels
+  fn film_with_tiles(done: &[(u32, u32)]) -> Film {
+    let mut film = Film::new(40, 30, Filter::default()).with_crop(Bounds::new(5, 5, 37, 29));
+    for &(tx, ty) in done.iter() {
+      for y in 5 + ty * 8..5 + (ty + 1) * 8 {
+        for x in 5 + tx * 8..5 + (tx + 1) * 8 {
+          film.pixels[(y * 40 + x) as usize].samples = 1;
+        }
+      }
+    }
+    film
+  }
+
+  #[test]
+  fn tiles_cover_the_bounds_once_in_every_order() {
+    let bounds = Bounds::new(3, 2, 50, 41);
+    for &order in [TileOrder::Scanline, TileOrder::Hilbert, TileOrder::Spiral].iter() {
+      let mut covered = vec![0; 50 * 41];
+      for tile in tiles(&bounds, 16, order) {
+        for y in tile.y0..tile.y1 {
+          for x in tile.x0..tile.x1 {
+            covered[(y * 50 + x) as usize] += 1;
+          }
+        }
+      }
+      for y in 0..41 {
+        for x in 0..50 {
+          assert_eq!(covered[(y * 50 + x) as usize], u32::from(bounds.contains(x, y)), "{} at ({}, {})", order, x, y);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn finished_region_is_the_largest_rectangle_of_finished_tiles() {
+    let all: Vec<(u32, u32)> = (0..3).flat_map(|ty| (0..4).map(move |tx| (tx, ty))).collect();
+    assert_eq!(finished_region(&film_with_tiles(&all), 8), (Some(Bounds::new(5, 5, 37, 29)), 12, 12));
+    assert_eq!(finished_region(&film_with_tiles(&[]), 8), (None, 0, 12));
+    // An L of finished tiles: the 2x2 block beats the 3 tile column and the 4 tile row's 4
+    let done = [(0, 0), (1, 0), (2, 0), (3, 0), (0, 1), (1, 1), (0, 2), (1, 2)];
+    assert_eq!(finished_region(&film_with_tiles(&done), 8), (Some(Bounds::new(5, 5, 21, 29)), 8, 12));
+    // Part of a tile doesn't count
+    let mut film = film_with_tiles(&[(2, 1)]);
+    film.pixels[(5 * 40 + 5) as usize].samples = 1;
+    assert_eq!(finished_region(&film, 8), (Some(Bounds::new(21, 13, 29, 21)), 1, 12));
+  }
+}
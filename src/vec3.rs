@@ -1,3 +1,4 @@
+use std::fmt;
 use std::ops;
 use std::iter::Sum;
 
@@ -38,7 +39,7 @@ impl Vec3 {
 
   pub fn dot(&self, other: Vec3) -> f32 {
     // Algebraic definition of dot product of two vectors a = [a1, a2, a3] and b = [b1, b2, b3] is (a1b1 + a2b2+ a3b3)
-    (self.e[0] * other.e[0] + self.e[1] * other.e[1] + self.e[2] * other.e[2])
+    self.e[0] * other.e[0] + self.e[1] * other.e[1] + self.e[2] * other.e[2]
   }
 
   pub fn cross(&self, rhs: Vec3) -> Vec3 {
@@ -86,6 +87,22 @@ impl Vec3 {
   }
 }
 
+impl Default for Vec3 {
+  fn default() -> Self {
+    Vec3::new()
+  }
+}
+
+// (x, y, z), for printing
+impl fmt::Display for Vec3 {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match f.precision() {
+      Some(p) => write!(f, "({:.*}, {:.*}, {:.*})", p, self[0], p, self[1], p, self[2]),
+      None => write!(f, "({}, {}, {})", self[0], self[1], self[2]),
+    }
+  }
+}
+
 // from a tuple of f32s
 impl From<(f32, f32, f32)> for Vec3 {
   fn from(tuple: (f32, f32, f32)) -> Self {